@@ -1,31 +1,96 @@
 use anyhow::{bail, Result};
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use metrics_exporter_prometheus::PrometheusBuilder;
 use std::num::NonZero;
 use std::path::PathBuf;
-use std::sync::{atomic::AtomicU32, Arc};
 use std::thread;
 use std::time;
 use tokio::runtime;
 use tokio_util::sync::CancellationToken;
 
-use constellation::consumer::consume;
-use constellation::server::serve;
+use constellation::consumer::{
+    catch_up, consume, diff_source_counts, rebuild, replay_dead_letters, start_from,
+    ArchiveRetention, DeadLetters, EventArchive, Jetstream, Pipeline, ReconnectPolicy, Replay,
+    ReplaySpeed, StartFrom, WatchdogPolicy,
+};
+use constellation::policy::{reload_on_sighup, Policy, SharedPolicy};
+use constellation::server::{serve, Admin};
+use constellation::storage::{
+    FilteredReader, LinkReader, LinkStorage, MemStorage, StorageStats, SwitchableReader,
+};
 #[cfg(feature = "rocks")]
-use constellation::storage::RocksStorage;
-use constellation::storage::{LinkReader, LinkStorage, MemStorage, StorageStats};
+use constellation::{consumer::claim_stream, storage::RocksStorage};
 
 const MONITOR_INTERVAL: time::Duration = time::Duration::from_secs(15);
 
 /// Aggregate links in the at-mosphere
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
     #[arg(short, long)]
     /// Jetstream server to connect to (exclusive with --fixture). Provide either a wss:// URL, or a shorhand value:
     /// 'us-east-1', 'us-east-2', 'us-west-1', or 'us-west-2'
     #[arg(short, long)]
     jetstream: String,
+    /// Longest wait between jetstream reconnect attempts (they back off exponentially up to this)
+    #[arg(long, default_value_t = 60)]
+    reconnect_max_delay_seconds: u64,
+    /// Only reset the reconnect backoff once a connection has stayed up this long
+    #[arg(long, default_value_t = 30)]
+    reconnect_reset_seconds: u64,
+    /// Give up (and shut down) after this many failed reconnects in a row
+    #[arg(long, default_value_t = 10, conflicts_with = "reconnect_forever")]
+    reconnect_max_retries: u32,
+    /// Never give up reconnecting to jetstream
+    #[arg(long)]
+    reconnect_forever: bool,
+    /// Allow starting with a different --jetstream than the stored cursor came from
+    #[arg(long)]
+    switch_streams: bool,
+    /// With --switch-streams: rewind the stored cursor by this many seconds to cover any gap
+    /// between the two instances (events in the overlap get counted twice unless
+    /// --idempotent-window-seconds is on, the default)
+    #[arg(long, default_value_t = 10)]
+    switch_streams_rewind_seconds: u64,
+    /// Consume jetstream from here instead of the stored cursor: a cursor (unix microseconds),
+    /// how long ago (like '2h' or '30m'), or an RFC 3339 timestamp
+    ///
+    /// Starting behind the stored cursor re-counts the links in between, unless
+    /// --idempotent-window-seconds is on (the default).
+    #[arg(long, conflicts_with = "fixture")]
+    start_from: Option<StartFrom>,
+    /// Reconnect if jetstream sends no events for this many seconds
+    #[arg(long, default_value_t = 30)]
+    watchdog_stall_seconds: u64,
+    /// Reconnect if the jetstream cursor is this far behind and still falling back (0 to disable)
+    #[arg(long, default_value_t = 300)]
+    watchdog_max_lag_seconds: u64,
+    /// Threads decompressing and parsing jetstream messages
+    #[arg(long, default_value_t = 2)]
+    decode_workers: usize,
+    /// Threads extracting links from events, ahead of the (single) storage writer
+    #[arg(long, default_value_t = 2)]
+    extract_workers: usize,
+    /// Exclusions (accounts, collections, targets) to leave out of the index and responses
+    ///
+    /// One rule per line: 'did <did>', 'collection <nsid>', or 'target <uri>' (a trailing '*'
+    /// matches a prefix). Reloaded on SIGHUP; links from newly excluded accounts get purged.
+    #[arg(long)]
+    policy: Option<PathBuf>,
+    /// Enable /admin endpoints (like POST /admin/reload-policy), with this bearer token
+    #[arg(long)]
+    admin_token: Option<String>,
+    /// List links from accounts that self-label '!no-unauthenticated' (hidden by default, like
+    /// deactivated accounts)
+    #[arg(long)]
+    show_private_accounts: bool,
+    /// Most events to store in one write when the writer falls behind
+    #[arg(long, default_value_t = 512)]
+    max_write_batch: usize,
+    /// Check for already-stored records for this many seconds of events past the stored cursor
+    /// (and after any rewind), so overlapping events aren't counted twice. 0 to disable.
+    #[arg(long, default_value_t = 60)]
+    idempotent_window_seconds: u64,
     // TODO: make this part of rocks' own sub-config?
     /// Where to store data on disk, for backends that use disk storage
     #[arg(short, long)]
@@ -34,6 +99,10 @@ struct Args {
     #[arg(short, long)]
     #[clap(value_enum, default_value_t = StorageBackend::Memory)]
     backend: StorageBackend,
+    /// Collect deleted links out of a target's rocksdb linker chunks once they're at least this
+    /// share of what the chunks hold (above 1 never does). Cursors from before still line up
+    #[arg(long, default_value_t = 0.2)]
+    tombstone_ratio: f64,
     /// Initiate a database backup into this dir, if supported by the storage
     #[arg(long)]
     backup: Option<PathBuf>,
@@ -44,8 +113,72 @@ struct Args {
     #[arg(long)]
     max_old_backups: Option<usize>,
     /// Saved jsonl from jetstream to use instead of a live subscription
+    ///
+    /// Either a single file or a directory of (optionally zstd) jsonl segments, like --archive
+    /// produces.
     #[arg(short, long)]
     fixture: Option<PathBuf>,
+    /// With --fixture: skip events before this cursor
+    #[arg(long)]
+    replay_from: Option<u64>,
+    /// With --fixture: stop at events after this cursor
+    #[arg(long)]
+    replay_until: Option<u64>,
+    /// With --fixture: 'max', 'realtime', or a multiple of real-time like '10x'
+    #[arg(long, default_value = "max")]
+    replay_speed: ReplaySpeed,
+    /// Append events that fail to be written to storage to this jsonl file
+    #[arg(long)]
+    dead_letters: Option<PathBuf>,
+    /// Append valid events that had nothing to index to this jsonl file (this can be a lot!)
+    #[arg(long)]
+    save_rejected: Option<PathBuf>,
+    /// Save every received jetstream event into zstd-compressed jsonl segments in this dir
+    #[arg(long)]
+    archive: Option<PathBuf>,
+    /// Start a new archive segment after this many minutes
+    #[arg(long, default_value_t = 60)]
+    archive_rotate_minutes: u64,
+    /// Remove the oldest archive segments when the archive grows past this many MB
+    #[arg(long)]
+    archive_max_size_mb: Option<u64>,
+    /// Remove archive segments with events older than this many hours
+    #[arg(long)]
+    archive_max_age_hours: Option<u64>,
+    /// Re-index into a fresh rocks dir from --reindex-from, then switch over to serving from it
+    ///
+    /// The current data keeps serving (and consuming) until the new index has caught up with
+    /// jetstream.
+    #[arg(long, requires = "reindex_from")]
+    reindex_into: Option<PathBuf>,
+    /// Archive dir (see --archive) to rebuild the index from, with --reindex-into
+    #[arg(long, requires = "reindex_into")]
+    reindex_from: Option<PathBuf>,
+    /// Consider the new index caught up when its cursor is this many seconds from now
+    #[arg(long, default_value_t = 5)]
+    reindex_caught_up_seconds: u64,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Work with the dead-letter log of events that failed to be written
+    Dlq {
+        #[command(subcommand)]
+        command: DlqCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DlqCommand {
+    /// Retry dead-lettered events against the storage (the server must not be running)
+    ///
+    /// Events that still fail are kept in the log. Ones that --policy excludes are dropped.
+    Replay {
+        /// The dead-letter jsonl file to replay
+        file: PathBuf,
+    },
 }
 
 #[derive(Debug, Clone, ValueEnum)]
@@ -68,25 +201,130 @@ fn jetstream_url(provided: &str) -> String {
 fn main() -> Result<()> {
     let args = Args::parse();
 
+    if let Some(Command::Dlq {
+        command: DlqCommand::Replay { file },
+    }) = args.command
+    {
+        return match args.backend {
+            StorageBackend::Memory => replay(MemStorage::new(), file, args.policy),
+            #[cfg(feature = "rocks")]
+            StorageBackend::Rocks => {
+                let storage_dir = args.data.clone().unwrap_or("rocks.test".into());
+                replay(RocksStorage::new(storage_dir)?, file, args.policy)
+            }
+        };
+    }
+
     println!("starting with storage backend: {:?}...", args.backend);
 
-    let fixture = args.fixture;
-    if let Some(ref p) = fixture {
-        println!("using fixture at {p:?}...");
-    }
+    let fixture = args.fixture.map(|source| {
+        println!("using fixture at {source:?}...");
+        Replay {
+            from: args.replay_from,
+            until: args.replay_until,
+            speed: args.replay_speed,
+            ..Replay::new(source)
+        }
+    });
 
-    let stream = jetstream_url(&args.jetstream);
-    println!("using jetstream server {stream:?}...",);
+    let jetstream = Jetstream {
+        reconnect: ReconnectPolicy {
+            max_delay: time::Duration::from_secs(args.reconnect_max_delay_seconds),
+            reset_after: time::Duration::from_secs(args.reconnect_reset_seconds),
+            max_retries: (!args.reconnect_forever).then_some(args.reconnect_max_retries),
+            ..Default::default()
+        },
+        watchdog: WatchdogPolicy {
+            stall_after: time::Duration::from_secs(args.watchdog_stall_seconds),
+            max_lag: (args.watchdog_max_lag_seconds > 0)
+                .then(|| time::Duration::from_secs(args.watchdog_max_lag_seconds)),
+            ..Default::default()
+        },
+        decoders: args.decode_workers,
+        ..Jetstream::new(jetstream_url(&args.jetstream))
+    };
+    let pipeline = Pipeline {
+        workers: args.extract_workers,
+        max_batch: args.max_write_batch,
+        idempotent_window: (args.idempotent_window_seconds > 0)
+            .then(|| time::Duration::from_secs(args.idempotent_window_seconds)),
+        policy: args
+            .policy
+            .clone()
+            .map(SharedPolicy::load)
+            .transpose()?
+            .unwrap_or_default(),
+        ..Default::default()
+    };
+    let admin = args.admin_token.clone().map(|token| Admin {
+        token,
+        policy: pipeline.policy.clone(),
+    });
+    println!("using jetstream server {:?}...", jetstream.url);
+
+    // opened again for the new store's consumer after a reindex switch
+    let sinks = || -> Result<Sinks> {
+        let dead_letters =
+            DeadLetters::open(args.dead_letters.as_deref(), args.save_rejected.as_deref())?;
+        let archive = args
+            .archive
+            .as_ref()
+            .map(|dir| {
+                println!("archiving events to {dir:?}...");
+                EventArchive::new(
+                    dir,
+                    time::Duration::from_secs(args.archive_rotate_minutes * 60),
+                    ArchiveRetention {
+                        max_bytes: args.archive_max_size_mb.map(|mb| mb * 1024 * 1024),
+                        max_age: args
+                            .archive_max_age_hours
+                            .map(|h| time::Duration::from_secs(h * 60 * 60)),
+                    },
+                )
+            })
+            .transpose()?;
+        Ok((dead_letters, archive))
+    };
+
+    if args.reindex_into.is_some() && fixture.is_some() {
+        bail!("--reindex-into needs a live jetstream to catch up from, it can't be used with --fixture");
+    }
 
     let stay_alive = CancellationToken::new();
 
     match args.backend {
-        StorageBackend::Memory => run(MemStorage::new(), fixture, None, stream, stay_alive),
+        StorageBackend::Memory => {
+            if args.reindex_into.is_some() {
+                bail!("--reindex-into is only supported by the rocks backend");
+            }
+            let mut mem = MemStorage::new();
+            mem.set_show_private(args.show_private_accounts);
+            if let Some(start) = args.start_from {
+                let idempotent = pipeline.idempotent_window.is_some();
+                start_from(&mut mem, &jetstream.url, start, idempotent)?;
+            }
+            run(
+                mem, fixture, None, jetstream, pipeline, sinks, None, admin, stay_alive,
+            )
+        }
         #[cfg(feature = "rocks")]
         StorageBackend::Rocks => {
             let storage_dir = args.data.clone().unwrap_or("rocks.test".into());
             println!("starting rocksdb...");
             let mut rocks = RocksStorage::new(storage_dir)?;
+            rocks.set_show_private(args.show_private_accounts);
+            rocks.set_tombstone_ratio(args.tombstone_ratio);
+            if fixture.is_none() {
+                let rewind = args
+                    .switch_streams
+                    .then(|| time::Duration::from_secs(args.switch_streams_rewind_seconds));
+                let idempotent = pipeline.idempotent_window.is_some();
+                claim_stream(&mut rocks, &jetstream.url, rewind, idempotent)?;
+            }
+            if let Some(start) = args.start_from {
+                let idempotent = pipeline.idempotent_window.is_some();
+                start_from(&mut rocks, &jetstream.url, start, idempotent)?;
+            }
             if let Some(backup_dir) = args.backup {
                 let auto_backup = match (args.backup_interval, args.max_old_backups) {
                     (Some(interval_hrs), copies) => Some((interval_hrs, copies)),
@@ -95,19 +333,72 @@ fn main() -> Result<()> {
                 };
                 rocks.start_backup(backup_dir, auto_backup, stay_alive.clone())?;
             }
+            let reindex = match (args.reindex_into, args.reindex_from) {
+                (Some(into), Some(from)) => {
+                    if args.backup_interval.is_some() {
+                        bail!("--reindex-into can't be combined with --backup-interval: the backups would keep following the old dir");
+                    }
+                    println!("opening fresh rocksdb at {into:?} to reindex into...");
+                    let mut fresh = RocksStorage::new(&into)?;
+                    fresh.set_show_private(args.show_private_accounts);
+                    claim_stream(&mut fresh, &jetstream.url, None, true)?;
+                    Some(Reindex {
+                        into: fresh,
+                        dir: into,
+                        archive: Replay::new(from),
+                        caught_up: time::Duration::from_secs(args.reindex_caught_up_seconds),
+                    })
+                }
+                _ => None,
+            };
             println!("rocks ready.");
-            run(rocks, fixture, args.data, stream, stay_alive)
+            run(
+                rocks,
+                fixture,
+                args.data.clone(),
+                jetstream,
+                pipeline,
+                sinks,
+                reindex,
+                admin,
+                stay_alive,
+            )
         }
     }
 }
 
-fn run(
-    mut storage: impl LinkStorage,
-    fixture: Option<PathBuf>,
+type Sinks = (DeadLetters, Option<EventArchive>);
+
+/// A fresh store to rebuild from an archive and switch over to
+struct Reindex<S: LinkStorage> {
+    into: S,
+    dir: PathBuf,
+    archive: Replay,
+    caught_up: time::Duration,
+}
+
+#[allow(clippy::too_many_arguments)] // it's all the wiring
+fn run<S: LinkStorage>(
+    mut storage: S,
+    fixture: Option<Replay>,
     data_dir: Option<PathBuf>,
-    stream: String,
+    mut jetstream: Jetstream,
+    pipeline: Pipeline,
+    sinks: impl Fn() -> Result<Sinks> + Sync,
+    reindex: Option<Reindex<S>>,
+    admin: Option<Admin>,
     stay_alive: CancellationToken,
 ) -> Result<()> {
+    let (dead_letters, archive) = sinks()?;
+
+    // shared by the server and the jetstream client
+    let rt = runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .max_blocking_threads(2)
+        .enable_all()
+        .build()?;
+    jetstream.runtime = Some(rt.handle().clone());
+
     ctrlc::set_handler({
         let mut desperation: u8 = 0;
         let stay_alive = stay_alive.clone();
@@ -121,39 +412,133 @@ fn run(
         }
     })?;
 
-    let qsize = Arc::new(AtomicU32::new(0));
-
     thread::scope(|s| {
-        let readable = storage.to_readable();
+        let readable = SwitchableReader::new(storage.to_readable());
+        let stream_status = jetstream.status.clone();
+
+        // a reindex stops this consumer (without stopping everything else) when it takes over
+        let consumer_alive = stay_alive.child_token();
+        let (stopped, consumer_stopped) = flume::bounded::<()>(1);
 
         s.spawn({
-            let qsize = qsize.clone();
+            let pipeline = pipeline.clone();
+            let jetstream = jetstream.clone();
             let stay_alive = stay_alive.clone();
-            let staying_alive = stay_alive.clone();
+            let staying_alive = consumer_alive.clone();
             move || {
-                if let Err(e) = consume(storage, qsize, fixture, stream, staying_alive) {
+                if let Err(e) = consume(
+                    &mut storage,
+                    pipeline,
+                    fixture,
+                    jetstream,
+                    dead_letters,
+                    archive,
+                    staying_alive.clone(),
+                ) {
                     eprintln!("jetstream finished with error: {e}");
                 }
+                drop(storage);
+                if staying_alive.is_cancelled() && !stay_alive.is_cancelled() {
+                    println!("consumer stopped for the reindex switch-over");
+                    stopped.send(()).unwrap();
+                    return;
+                }
                 stay_alive.drop_guard();
             }
         });
 
-        s.spawn({
+        if let Some(Reindex {
+            into: mut fresh,
+            dir,
+            archive,
+            caught_up,
+        }) = reindex
+        {
             let readable = readable.clone();
+            let pipeline = pipeline.clone();
+            let sinks = &sinks;
+            let stay_alive = stay_alive.clone();
+            // the catching-up consumer gets its own status: the server shows the serving one
+            let catching_up = Jetstream {
+                status: Default::default(),
+                ..jetstream.clone()
+            };
+            s.spawn(move || {
+                let switched = (|| -> Result<()> {
+                    rebuild(&mut fresh, pipeline.clone(), archive, catching_up.clone(), caught_up, &stay_alive)?;
+
+                    println!("reindex: caught up. comparing link counts per source (this walks both indexes)...");
+                    let diff = diff_source_counts(&readable, &fresh.to_readable())?;
+                    if diff.is_empty() {
+                        println!("reindex: link counts match for every source.");
+                    }
+                    for d in diff {
+                        println!(
+                            "reindex: {} {}: {} -> {} ({:+})",
+                            d.collection,
+                            d.path,
+                            d.old,
+                            d.new,
+                            d.new as i64 - d.old as i64
+                        );
+                    }
+
+                    // the comparison can take a while
+                    catch_up(&mut fresh, pipeline.clone(), catching_up, caught_up, &stay_alive)?;
+                    consumer_alive.cancel();
+                    if consumer_stopped.recv().is_err() {
+                        bail!("reindex: the consumer exited instead of handing over");
+                    }
+                    readable.switch(fresh.to_readable());
+                    println!("reindex: now serving from {dir:?}. restart with --data {dir:?} to keep using it.");
+                    Ok(())
+                })();
+                if let Err(e) = switched {
+                    eprintln!("reindex failed, still serving from the old data: {e:?}");
+                    return;
+                }
+
+                let (dead_letters, archive) = sinks().unwrap_or_else(|e| {
+                    eprintln!("reindex: failed to reopen dead-letter/archive sinks, continuing without them: {e:?}");
+                    Default::default()
+                });
+                if let Err(e) = consume(
+                    &mut fresh,
+                    pipeline,
+                    None,
+                    jetstream,
+                    dead_letters,
+                    archive,
+                    stay_alive.clone(),
+                ) {
+                    eprintln!("jetstream finished with error: {e}");
+                }
+                stay_alive.drop_guard();
+            });
+        }
+
+        s.spawn({
+            let readable = FilteredReader::new(readable.clone(), pipeline.policy.clone());
+            let policy = pipeline.policy.clone();
             let stay_alive = stay_alive.clone();
             let staying_alive = stay_alive.clone();
-            || {
-                runtime::Builder::new_multi_thread()
-                    .worker_threads(1)
-                    .max_blocking_threads(2)
-                    .enable_all()
-                    .build()
-                    .expect("axum startup")
-                    .block_on(async {
-                        install_metrics_server()?;
-                        serve(readable, "0.0.0.0:6789", staying_alive).await
-                    })
-                    .unwrap();
+            let rt = &rt;
+            move || {
+                rt.block_on(async {
+                    install_metrics_server()?;
+                    if policy.reloadable() {
+                        tokio::spawn(reload_on_sighup(policy, staying_alive.clone()));
+                    }
+                    serve(
+                        readable,
+                        stream_status,
+                        admin,
+                        "0.0.0.0:6789",
+                        staying_alive,
+                    )
+                    .await
+                })
+                .unwrap();
                 stay_alive.drop_guard();
             }
         });
@@ -218,6 +603,17 @@ fn run(
     Ok(())
 }
 
+fn replay(mut storage: impl LinkStorage, file: PathBuf, policy: Option<PathBuf>) -> Result<()> {
+    let policy = policy.map(Policy::load).transpose()?.unwrap_or_default();
+    println!("replaying dead letters from {file:?}...");
+    let report = replay_dead_letters(&mut storage, &file, &policy)?;
+    println!(
+        "dlq replay finished: {} replayed, {} excluded by the policy, {} skipped and {} still failing (both kept in {file:?})",
+        report.replayed, report.excluded, report.skipped, report.still_failing
+    );
+    Ok(())
+}
+
 fn install_metrics_server() -> Result<()> {
     println!("installing metrics server...");
     let host = [0, 0, 0, 0];
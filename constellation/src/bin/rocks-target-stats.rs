@@ -4,7 +4,8 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 
 use constellation::storage::rocks_store::{
-    Collection, DidId, RKey, RPath, Target, TargetKey, TargetLinkers, _bincode_opts,
+    _bincode_opts, Collection, DidId, LinkerChunkKey, LinkerCounts, RKey, RPath, SourceId, Target,
+    TargetId, TargetKey, TargetLinkers, LINKERS_PER_CHUNK,
 };
 use constellation::storage::RocksStorage;
 use constellation::Did;
@@ -40,9 +41,9 @@ const BUCKETS: Buckets = Buckets([
 
 // b1, b2, b3, b4, b5, b6, b7, b8, b9, b10, b12, b16, b32, b64, b128, b256, b512, b1024, b4096, b16384, b65535, b262144, bmax
 
-static DID_IDS_CF: &str = "did_ids";
 static TARGET_IDS_CF: &str = "target_ids";
 static TARGET_LINKERS_CF: &str = "target_links";
+static TARGET_LINK_COUNTS_CF: &str = "target_link_counts";
 
 const REPORT_INTERVAL: usize = 50_000;
 
@@ -53,9 +54,10 @@ struct ErrStats {
     failed_to_get_sample: usize,
     failed_to_read_target_id: usize,
     failed_to_deserialize_target_key: usize,
+    failed_to_resolve_source: usize,
     failed_to_parse_target_as_link: usize,
-    failed_to_get_links: usize,
-    failed_to_deserialize_linkers: usize,
+    failed_to_get_counts: usize,
+    failed_to_deserialize_counts: usize,
 }
 
 fn thousands(n: usize) -> String {
@@ -87,11 +89,12 @@ fn main() {
     let RocksStorage { ref db, .. } = rocks;
 
     let mut stats = Stats::new();
+    let mut sources: HashMap<SourceId, (Collection, RPath)> = HashMap::new();
     let mut err_stats: ErrStats = Default::default();
 
-    let did_ids_cf = db.cf_handle(DID_IDS_CF).unwrap();
     let target_id_cf = db.cf_handle(TARGET_IDS_CF).unwrap();
     let target_links_cf = db.cf_handle(TARGET_LINKERS_CF).unwrap();
+    let target_link_counts_cf = db.cf_handle(TARGET_LINK_COUNTS_CF).unwrap();
 
     let t0 = time::Instant::now();
     let mut t_prev = t0;
@@ -121,12 +124,22 @@ fn main() {
             continue;
         };
 
-        let Ok(TargetKey(Target(target), collection, rpath)) =
-            _bincode_opts().deserialize(&target_key)
+        let Ok(TargetKey(Target(target), source_id)) = _bincode_opts().deserialize(&target_key)
         else {
             err_stats.failed_to_deserialize_target_key += 1;
             continue;
         };
+        let (collection, rpath) = match sources.get(&source_id) {
+            Some(source) => source.clone(),
+            None => {
+                let Ok(Some(source)) = rocks.get_source(&source_id) else {
+                    err_stats.failed_to_resolve_source += 1;
+                    continue;
+                };
+                sources.insert(source_id, source.clone());
+                source
+            }
+        };
 
         let source = {
             let Some(parsed) = parse_any_link(&target) else {
@@ -136,15 +149,15 @@ fn main() {
             SourceLink(collection, rpath, parsed.name().into())
         };
 
-        let Ok(Some(links_raw)) = db.get_cf(&target_links_cf, &target_id) else {
-            err_stats.failed_to_get_links += 1;
+        let Ok(Some(counts_raw)) = db.get_cf(&target_link_counts_cf, &target_id) else {
+            err_stats.failed_to_get_counts += 1;
             continue;
         };
-        let Ok(linkers) = _bincode_opts().deserialize::<TargetLinkers>(&links_raw) else {
-            err_stats.failed_to_deserialize_linkers += 1;
+        let Ok(counts) = _bincode_opts().deserialize::<LinkerCounts>(&counts_raw) else {
+            err_stats.failed_to_deserialize_counts += 1;
             continue;
         };
-        let (n, _) = linkers.count();
+        let n = counts.alive();
 
         if n == 0 {
             continue;
@@ -161,14 +174,25 @@ fn main() {
         stats
             .entry(source)
             .or_insert_with(|| {
-                let (DidId(did_id), RKey(k)) = &linkers.0[(n - 1) as usize];
-                if let Ok(Some(did_bytes)) = db.get_cf(&did_ids_cf, did_id.to_be_bytes()) {
-                    if let Ok(Did(did)) = _bincode_opts().deserialize(&did_bytes) {
-                        return (did, k.clone(), Default::default());
-                    }
-                }
-                err_stats.failed_to_get_sample += 1;
-                ("".into(), "".into(), Default::default())
+                // sample the newest linker that's still around, from the last chunk
+                let sample = (|| {
+                    let target_id: TargetId = _bincode_opts().deserialize(&target_id).ok()?;
+                    let last_chunk = (counts.total as u64 - 1) / LINKERS_PER_CHUNK;
+                    let key = _bincode_opts()
+                        .serialize(&LinkerChunkKey(target_id, last_chunk))
+                        .ok()?;
+                    let chunk_raw = db.get_cf(&target_links_cf, key).ok()??;
+                    let TargetLinkers(linkers) = TargetLinkers::decode(&chunk_raw).ok()?;
+                    let (DidId(did_id), RKey(k)) =
+                        linkers.into_iter().rev().find(|(d, _)| d.0 != 0)?;
+                    let Did(did) = rocks.get_did(&DidId(did_id)).ok()??;
+                    Some((did, k))
+                })();
+                let Some((did, k)) = sample else {
+                    err_stats.failed_to_get_sample += 1;
+                    return ("".into(), "".into(), Default::default());
+                };
+                (did, k, Default::default())
             })
             .2
              .0[bucket] += 1;
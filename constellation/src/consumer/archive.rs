@@ -0,0 +1,399 @@
+use anyhow::{bail, Result};
+use metrics::{counter, describe_counter, Unit};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tinyjson::JsonValue;
+
+const SEGMENT_EXT: &str = ".jsonl.zst";
+const PARTIAL_EXT: &str = ".jsonl.zst.partial";
+const ZSTD_LEVEL: i32 = 3;
+
+/// How long to keep finished segments around
+///
+/// Either limit (or both) can be set. Segments are removed oldest-first until the archive fits.
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveRetention {
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+/// A finished archive segment on disk
+///
+/// Segment file names carry the (inclusive) range of jetstream cursors they contain:
+/// `<first>-<last>.jsonl.zst`, zero-padded so they sort in cursor order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub first: u64,
+    pub last: u64,
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+struct OpenSegment {
+    first: u64,
+    last: u64,
+    opened: Instant,
+    path: PathBuf,
+    encoder: zstd::stream::write::Encoder<'static, BufWriter<File>>,
+}
+
+/// Tee for raw jetstream events into time-rotated, zstd-compressed jsonl segments
+pub struct EventArchive {
+    dir: PathBuf,
+    rotate_every: Duration,
+    retention: ArchiveRetention,
+    current: Option<OpenSegment>,
+    latest: Option<u64>,
+    pruned: Instant,
+}
+
+impl EventArchive {
+    pub fn new(
+        dir: impl AsRef<Path>,
+        rotate_every: Duration,
+        retention: ArchiveRetention,
+    ) -> Result<Self> {
+        describe_counter!(
+            "archive_events",
+            Unit::Count,
+            "events written to the event archive"
+        );
+        describe_counter!(
+            "archive_bytes",
+            Unit::Bytes,
+            "uncompressed event bytes written to the event archive"
+        );
+        describe_counter!(
+            "archive_events_skipped",
+            Unit::Count,
+            "already-archived events seen again (eg. after a reconnect) and not re-written"
+        );
+        describe_counter!(
+            "archive_segments_finished",
+            Unit::Count,
+            "archive segments rotated out and finalized"
+        );
+        describe_counter!(
+            "archive_segments_pruned",
+            Unit::Count,
+            "archive segments removed by retention"
+        );
+        describe_counter!(
+            "archive_write_fail",
+            Unit::Count,
+            "failures to write to the event archive"
+        );
+
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        recover_partials(&dir)?;
+        let latest = list_segments(&dir)?.last().map(|s| s.last);
+        let archive = Self {
+            dir,
+            rotate_every,
+            retention,
+            current: None,
+            latest,
+            pruned: Instant::now(),
+        };
+        archive.prune()?;
+        Ok(archive)
+    }
+
+    /// append one raw json event (without trailing newline) with its cursor
+    ///
+    /// events at or before the latest archived cursor are skipped, so the overlap from a jetstream
+    /// reconnect doesn't get archived twice.
+    pub fn write(&mut self, raw: &str, cursor: u64) {
+        if self.latest.map(|latest| cursor <= latest).unwrap_or(false) {
+            counter!("archive_events_skipped").increment(1);
+            return;
+        }
+        if let Err(e) = self.try_write(raw, cursor) {
+            counter!("archive_write_fail").increment(1);
+            eprintln!("archive: failed to write event at {cursor}: {e:?}");
+        }
+    }
+
+    /// rotate and apply retention if they're due, for when no events are arriving to do it
+    ///
+    /// an open segment is finished once it's older than the rotation interval, and an idle archive
+    /// with nothing open still gets pruned every interval.
+    pub fn tick(&mut self) {
+        let due = match self.current {
+            Some(ref c) => c.opened.elapsed() >= self.rotate_every,
+            None => self.pruned.elapsed() >= self.rotate_every,
+        };
+        if !due {
+            return;
+        }
+        if let Err(e) = self.rotate() {
+            counter!("archive_write_fail").increment(1);
+            eprintln!("archive: failed to rotate an idle segment: {e:?}");
+        }
+    }
+
+    fn try_write(&mut self, raw: &str, cursor: u64) -> Result<()> {
+        if self
+            .current
+            .as_ref()
+            .map(|c| c.opened.elapsed() >= self.rotate_every)
+            .unwrap_or(false)
+        {
+            self.rotate()?;
+        }
+        let segment = match self.current {
+            Some(ref mut s) => s,
+            None => {
+                let path = self.dir.join(format!("{cursor:016}{PARTIAL_EXT}"));
+                let file = BufWriter::new(File::create_new(&path)?);
+                self.current.insert(OpenSegment {
+                    first: cursor,
+                    last: cursor,
+                    opened: Instant::now(),
+                    path,
+                    encoder: zstd::stream::write::Encoder::new(file, ZSTD_LEVEL)?,
+                })
+            }
+        };
+        segment.encoder.write_all(raw.as_bytes())?;
+        segment.encoder.write_all(b"\n")?;
+        segment.last = cursor;
+        self.latest = Some(cursor);
+        counter!("archive_events").increment(1);
+        counter!("archive_bytes").increment(raw.len() as u64 + 1);
+        Ok(())
+    }
+
+    /// finish the current segment (if any) and apply retention
+    pub fn rotate(&mut self) -> Result<()> {
+        if let Some(segment) = self.current.take() {
+            let mut file = segment.encoder.finish()?;
+            file.flush()?;
+            file.get_ref().sync_all()?;
+            let finished = self.dir.join(segment_name(segment.first, segment.last));
+            fs::rename(&segment.path, finished)?;
+            counter!("archive_segments_finished").increment(1);
+        }
+        self.pruned = Instant::now();
+        self.prune()
+    }
+
+    fn prune(&self) -> Result<()> {
+        let segments = list_segments(&self.dir)?;
+        let mut total: u64 = segments.iter().map(|s| s.bytes).sum();
+        let now_us = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64;
+        for segment in segments {
+            let too_big = self
+                .retention
+                .max_bytes
+                .map(|max| total > max)
+                .unwrap_or(false);
+            let too_old = self
+                .retention
+                .max_age
+                .map(|age| now_us.saturating_sub(segment.last) > age.as_micros() as u64)
+                .unwrap_or(false);
+            if !(too_big || too_old) {
+                break; // oldest first, so everything after is newer (and we're under size)
+            }
+            eprintln!("archive: pruning segment {:?}", segment.path);
+            fs::remove_file(&segment.path)?;
+            total -= segment.bytes;
+            counter!("archive_segments_pruned").increment(1);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EventArchive {
+    fn drop(&mut self) {
+        if let Err(e) = self.rotate() {
+            eprintln!("archive: failed to finish the last segment on shutdown: {e:?}");
+        }
+    }
+}
+
+fn segment_name(first: u64, last: u64) -> String {
+    format!("{first:016}-{last:016}{SEGMENT_EXT}")
+}
+
+pub(super) fn parse_segment_name(name: &str) -> Option<(u64, u64)> {
+    let (first, last) = name.strip_suffix(SEGMENT_EXT)?.split_once('-')?;
+    Some((first.parse().ok()?, last.parse().ok()?))
+}
+
+/// finished segments in a directory, oldest first
+pub fn list_segments(dir: impl AsRef<Path>) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some((first, last)) = name.to_str().and_then(parse_segment_name) else {
+            continue;
+        };
+        segments.push(Segment {
+            first,
+            last,
+            path: entry.path(),
+            bytes: entry.metadata()?.len(),
+        });
+    }
+    segments.sort_by_key(|s| s.first);
+    Ok(segments)
+}
+
+/// open a jsonl file for line reading, decompressing it if it's a zstd archive segment
+pub fn open_jsonl(path: impl AsRef<Path>) -> Result<Box<dyn BufRead + Send>> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let compressed = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.ends_with(".zst") || n.ends_with(PARTIAL_EXT))
+        .unwrap_or(false);
+    if compressed {
+        Ok(Box::new(io::BufReader::new(
+            zstd::stream::read::Decoder::new(file)?,
+        )))
+    } else {
+        Ok(Box::new(io::BufReader::new(file)))
+    }
+}
+
+/// segments left unfinished by a crash get closed out under their real cursor range
+///
+/// the zstd stream in them is truncated, so readers will hit an error at the very end: everything
+/// before that is still good.
+fn recover_partials(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(first) = name
+            .to_str()
+            .and_then(|n| n.strip_suffix(PARTIAL_EXT))
+            .and_then(|f| f.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        let mut last = None;
+        let mut lines = open_jsonl(entry.path())?.lines();
+        while let Some(Ok(line)) = lines.next() {
+            if let Ok(JsonValue::Object(o)) = line.parse() {
+                if let Some(JsonValue::Number(ts)) = o.get("time_us") {
+                    last = Some(*ts as u64);
+                }
+            }
+        }
+        let Some(last) = last else {
+            eprintln!("archive: removing empty unfinished segment {name:?}");
+            fs::remove_file(entry.path())?;
+            continue;
+        };
+        if last < first {
+            bail!("archive: unfinished segment {name:?} ends before it starts?");
+        }
+        eprintln!("archive: recovering unfinished segment {name:?} (ends at {last})");
+        fs::rename(entry.path(), dir.join(segment_name(first, last)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(ts: u64) -> String {
+        format!(r#"{{"did":"did:plc:asdf","time_us":{ts},"kind":"identity"}}"#)
+    }
+
+    #[test]
+    fn test_archive_rotates_and_reads_back() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        {
+            let mut archive =
+                EventArchive::new(dir.path(), Duration::from_secs(3600), Default::default())?;
+            archive.write(&event(10), 10);
+            archive.write(&event(11), 11);
+            archive.rotate()?;
+            archive.write(&event(12), 12);
+        } // drop finishes the open segment
+
+        let segments = list_segments(dir.path())?;
+        assert_eq!(
+            segments
+                .iter()
+                .map(|s| (s.first, s.last))
+                .collect::<Vec<_>>(),
+            vec![(10, 11), (12, 12)]
+        );
+        let lines = open_jsonl(&segments[0].path)?
+            .lines()
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec![event(10), event(11)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive_recovers_partial() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        {
+            let path = dir.path().join(format!("{:016}{PARTIAL_EXT}", 20));
+            let mut encoder = zstd::stream::write::Encoder::new(File::create(path)?, ZSTD_LEVEL)?;
+            writeln!(encoder, "{}", event(20))?;
+            writeln!(encoder, "{}", event(21))?;
+            encoder.flush()?;
+            std::mem::forget(encoder); // crash: never finish the frame
+        }
+        let archive = EventArchive::new(dir.path(), Duration::from_secs(3600), Default::default())?;
+        drop(archive);
+
+        let segments = list_segments(dir.path())?;
+        assert_eq!(segments.len(), 1);
+        assert_eq!((segments[0].first, segments[0].last), (20, 21));
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive_rotates_while_idle() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut archive =
+            EventArchive::new(dir.path(), Duration::from_millis(10), Default::default())?;
+        archive.write(&event(40), 40);
+        archive.tick();
+        assert!(list_segments(dir.path())?.is_empty()); // not due yet
+
+        std::thread::sleep(Duration::from_millis(20));
+        archive.tick();
+        let segments = list_segments(dir.path())?;
+        assert_eq!(
+            segments
+                .iter()
+                .map(|s| (s.first, s.last))
+                .collect::<Vec<_>>(),
+            vec![(40, 40)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive_retention_by_size() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut archive = EventArchive::new(
+            dir.path(),
+            Duration::from_secs(3600),
+            ArchiveRetention {
+                max_bytes: Some(1),
+                max_age: None,
+            },
+        )?;
+        archive.write(&event(30), 30);
+        archive.rotate()?;
+        archive.write(&event(31), 31);
+        archive.rotate()?;
+        assert!(list_segments(dir.path())?.is_empty());
+        Ok(())
+    }
+}
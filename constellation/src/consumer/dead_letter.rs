@@ -0,0 +1,336 @@
+use super::get_actionable;
+use super::jetstream::get_event_time;
+use crate::policy::Policy;
+use crate::storage::LinkStorage;
+use anyhow::{bail, Result};
+use metrics::counter;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tinyjson::JsonValue;
+
+/// Where to put events that we couldn't (or wouldn't) index
+///
+/// Both logs are append-only jsonl. Failed writes are flushed immediately since they should be
+/// rare, but the rejected-events sink can see most of the firehose so it stays buffered.
+#[derive(Default)]
+pub struct DeadLetters {
+    failed: Option<BufWriter<File>>,
+    rejected: Option<BufWriter<File>>,
+}
+
+/// One entry from a dead-letter log
+#[derive(Debug, PartialEq)]
+pub struct DeadLetter {
+    pub cursor: Option<u64>,
+    pub error: Option<String>,
+    pub event: JsonValue,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplayReport {
+    pub replayed: usize,
+    pub skipped: usize,
+    /// dropped from the log, since the policy excludes them now
+    pub excluded: usize,
+    pub still_failing: usize,
+}
+
+fn open_append(path: &Path) -> Result<BufWriter<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+impl DeadLetters {
+    pub fn open(failed: Option<&Path>, rejected: Option<&Path>) -> Result<Self> {
+        Ok(Self {
+            failed: failed.map(open_append).transpose()?,
+            rejected: rejected.map(open_append).transpose()?,
+        })
+    }
+
+    /// record an event whose storage write failed
+    pub fn failed(&mut self, event: &JsonValue, cursor: u64, error: &anyhow::Error) {
+        counter!("consumer_events_dead_lettered", "reason" => "write_failed").increment(1);
+        let Some(ref mut log) = self.failed else {
+            eprintln!("consumer: failed to write event at cursor {cursor} and no dead-letter log is configured, dropping it: {error:?}");
+            return;
+        };
+        eprintln!(
+            "consumer: failed to write event at cursor {cursor}, dead-lettering it: {error:?}"
+        );
+        let line = DeadLetter {
+            cursor: Some(cursor),
+            error: Some(format!("{error:?}")),
+            event: event.clone(),
+        };
+        if let Err(e) = line.write_to(log).and_then(|_| Ok(log.flush()?)) {
+            counter!("consumer_dead_letter_write_fail").increment(1);
+            eprintln!(
+                "consumer: could not write to the dead-letter log either (dropping event): {e:?}"
+            );
+        }
+    }
+
+    /// record valid json that didn't produce anything actionable, if a sink is configured
+    pub fn rejected(&mut self, event: &JsonValue) {
+        let Some(ref mut log) = self.rejected else {
+            return;
+        };
+        let line = DeadLetter {
+            cursor: get_event_time(event),
+            error: None,
+            event: event.clone(),
+        };
+        if let Err(e) = line.write_to(log) {
+            counter!("consumer_dead_letter_write_fail").increment(1);
+            eprintln!("consumer: could not write to the rejected-events log: {e:?}");
+        }
+    }
+}
+
+impl DeadLetter {
+    fn write_to(&self, w: &mut impl Write) -> Result<()> {
+        let mut o = HashMap::new();
+        if let Some(cursor) = self.cursor {
+            o.insert("cursor".to_string(), JsonValue::Number(cursor as f64));
+        }
+        if let Some(ref error) = self.error {
+            o.insert("error".to_string(), JsonValue::String(error.clone()));
+        }
+        o.insert("event".to_string(), self.event.clone());
+        writeln!(w, "{}", JsonValue::Object(o).stringify()?)?;
+        Ok(())
+    }
+
+    fn parse(line: &str) -> Result<Self> {
+        let JsonValue::Object(mut o) = line.parse()? else {
+            bail!("dead letter entry is not a json object");
+        };
+        let Some(event) = o.remove("event") else {
+            bail!("dead letter entry has no event");
+        };
+        let cursor = match o.get("cursor") {
+            Some(JsonValue::Number(n)) => Some(*n as u64),
+            _ => None,
+        };
+        let error = match o.remove("error") {
+            Some(JsonValue::String(s)) => Some(s),
+            _ => None,
+        };
+        Ok(Self {
+            cursor,
+            error,
+            event,
+        })
+    }
+}
+
+pub fn read_dead_letters(path: &Path) -> Result<impl Iterator<Item = Result<DeadLetter>>> {
+    let file = File::open(path)?;
+    Ok(io::BufReader::new(file)
+        .lines()
+        .filter(|l| l.as_ref().map(|s| !s.trim().is_empty()).unwrap_or(true))
+        .map(|l| DeadLetter::parse(&l?)))
+}
+
+/// retry every event in a dead-letter log against the store
+///
+/// events that still fail are written back to the log, so it can be replayed again after another
+/// fix, and so are entries that can't be read or have nothing to index. the stored jetstream
+/// cursor is left where it is: these events are old news.
+///
+/// events the policy excludes are dropped, like the consumer would. rejected-events logs are
+/// refused: nothing in them failed, so there's nothing to retry.
+pub fn replay_dead_letters(
+    store: &mut impl LinkStorage,
+    path: &Path,
+    policy: &Policy,
+) -> Result<ReplayReport> {
+    for entry in read_dead_letters(path)?.flatten() {
+        if entry.error.is_none() {
+            bail!("{path:?} looks like a rejected-events log (an entry has no error), not replaying it");
+        }
+    }
+    let resume_cursor = store.get_cursor()?;
+    let remaining_path = {
+        let mut p = path.as_os_str().to_owned();
+        p.push(".replaying");
+        PathBuf::from(p)
+    };
+    let mut remaining = BufWriter::new(File::create(&remaining_path)?);
+    let mut report = ReplayReport::default();
+
+    for line in io::BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = match DeadLetter::parse(&line) {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("dlq replay: keeping unreadable entry: {e:?}");
+                writeln!(remaining, "{line}")?;
+                report.skipped += 1;
+                continue;
+            }
+        };
+        let Some((action, ts)) = get_actionable(&entry.event) else {
+            eprintln!(
+                "dlq replay: nothing actionable in entry at cursor {:?}, keeping it",
+                entry.cursor
+            );
+            writeln!(remaining, "{line}")?;
+            report.skipped += 1;
+            continue;
+        };
+        let Some(action) = policy.filter(action) else {
+            report.excluded += 1;
+            continue;
+        };
+        if let Err(e) = store.push(&action, resume_cursor.unwrap_or(ts)) {
+            eprintln!("dlq replay: event at cursor {ts} still failing: {e:?}");
+            DeadLetter {
+                error: Some(format!("{e:?}")),
+                ..entry
+            }
+            .write_to(&mut remaining)?;
+            report.still_failing += 1;
+        } else {
+            report.replayed += 1;
+        }
+    }
+
+    remaining.flush()?;
+    drop(remaining);
+    fs::rename(remaining_path, path)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{LinkReader, MemStorage};
+
+    const LIKE: &str = r#"{
+        "did":"did:plc:icprmty6ticzracr5urz4uum",
+        "time_us":1736448492661668,
+        "kind":"commit",
+        "commit":{"rev":"3lfddpt5qa62c","operation":"create","collection":"app.bsky.feed.like","rkey":"3lfddpt5djw2c","record":{
+            "$type":"app.bsky.feed.like",
+            "createdAt":"2025-01-09T18:48:10.412Z",
+            "subject":{"cid":"bafyreihazf62qvmusup55ojhkzwbmzee6rxtsug3e6eg33mnjrgthxvozu","uri":"at://did:plc:lphckw3dz4mnh3ogmfpdgt6z/app.bsky.feed.post/3lfdau5f7wk23"}
+        },
+        "cid":"bafyreidgcs2id7nsbp6co42ind2wcig3riwcvypwan6xdywyfqklovhdjq"}
+    }"#;
+
+    #[test]
+    fn test_dead_letter_roundtrip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dead.jsonl");
+        let event: JsonValue = LIKE.parse()?;
+        {
+            let mut dlq = DeadLetters::open(Some(&path), None)?;
+            dlq.failed(&event, 1736448492661668, &anyhow::anyhow!("disk on fire"));
+        }
+        let entries = read_dead_letters(&path)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].cursor, Some(1736448492661668));
+        assert!(entries[0].error.as_ref().unwrap().contains("disk on fire"));
+        assert_eq!(entries[0].event, event);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_dead_letters() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dead.jsonl");
+        {
+            let mut dlq = DeadLetters::open(Some(&path), None)?;
+            dlq.failed(&LIKE.parse()?, 1736448492661668, &anyhow::anyhow!("oops"));
+            dlq.failed(&r#"{"kind":"nope"}"#.parse()?, 1, &anyhow::anyhow!("oops"));
+        }
+
+        let mut storage = MemStorage::new();
+        let report = replay_dead_letters(&mut storage, &path, &Policy::default())?;
+        assert_eq!(
+            report,
+            ReplayReport {
+                replayed: 1,
+                skipped: 1,
+                excluded: 0,
+                still_failing: 0,
+            }
+        );
+        assert_eq!(
+            storage.get_count(
+                "at://did:plc:lphckw3dz4mnh3ogmfpdgt6z/app.bsky.feed.post/3lfdau5f7wk23",
+                "app.bsky.feed.like",
+                ".subject.uri"
+            )?,
+            1
+        );
+        // the entry with nothing to index stays for someone to look at
+        assert_eq!(read_dead_letters(&path)?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_leaves_out_excluded_events() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dead.jsonl");
+        {
+            let mut dlq = DeadLetters::open(Some(&path), None)?;
+            dlq.failed(&LIKE.parse()?, 1736448492661668, &anyhow::anyhow!("oops"));
+        }
+        let policy = "did did:plc:icprmty6ticzracr5urz4uum".parse()?;
+        let mut storage = MemStorage::new();
+        let report = replay_dead_letters(&mut storage, &path, &policy)?;
+        assert_eq!(report.excluded, 1);
+        assert_eq!(
+            storage
+                .get_all_record_counts(
+                    "at://did:plc:lphckw3dz4mnh3ogmfpdgt6z/app.bsky.feed.post/3lfdau5f7wk23"
+                )?
+                .len(),
+            0
+        );
+        assert_eq!(read_dead_letters(&path)?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_keeps_what_it_cannot_index() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dead.jsonl");
+        {
+            let mut dlq = DeadLetters::open(Some(&path), None)?;
+            dlq.failed(&r#"{"kind":"nope"}"#.parse()?, 1, &anyhow::anyhow!("oops"));
+        }
+        OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(b"{not json\n")?;
+        let before = fs::read_to_string(&path)?;
+
+        let report = replay_dead_letters(&mut MemStorage::new(), &path, &Policy::default())?;
+        assert_eq!(report.skipped, 2);
+        assert_eq!(fs::read_to_string(&path)?, before);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_refuses_rejected_logs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("rejected.jsonl");
+        {
+            let mut dlq = DeadLetters::open(None, Some(&path))?;
+            dlq.rejected(&r#"{"kind":"nope"}"#.parse()?);
+        }
+        let before = fs::read_to_string(&path)?;
+        assert!(replay_dead_letters(&mut MemStorage::new(), &path, &Policy::default()).is_err());
+        assert_eq!(fs::read_to_string(&path)?, before);
+        Ok(())
+    }
+}
@@ -1,22 +1,109 @@
+use super::archive::EventArchive;
+use super::pipeline;
+use super::reconnect::{ReconnectPolicy, Reconnects};
+use super::watchdog::{StreamState, StreamStatus, Watchdog, WatchdogPolicy};
+use crate::storage::LinkStorage;
 use anyhow::{bail, Result};
+use futures_util::{SinkExt, StreamExt};
 use metrics::{
     counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit,
 };
-use std::io::{Cursor, ErrorKind, Read};
-use std::net::ToSocketAddrs;
+use std::future::Future;
+use std::io::{Cursor, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time;
 use tinyjson::JsonValue;
+use tokio::net::TcpStream;
+use tokio::runtime;
+use tokio::time::MissedTickBehavior;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use tokio_util::sync::CancellationToken;
-use tungstenite::{client::IntoClientRequest, Error as TError, Message};
+use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+use tungstenite::{client::IntoClientRequest, Bytes, Error as TError, Message};
 use zstd::dict::DecoderDictionary;
 
 const JETSTREAM_ZSTD_DICTIONARY: &[u8] = include_bytes!("../../zstd/dictionary");
+/// dns, tcp, tls and the websocket handshake, all together
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(8);
+const PING_INTERVAL: time::Duration = time::Duration::from_secs(5);
+/// reconnect after hearing nothing at all (not even a pong) for this long
+const READ_TIMEOUT: time::Duration = time::Duration::from_secs(15);
+const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(4);
+/// how often the forwarder checks the archive's rotation deadline when no events are arriving
+const ARCHIVE_TICK: time::Duration = time::Duration::from_secs(1);
 
-pub fn consume_jetstream(
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A live jetstream subscription
+#[derive(Debug, Clone)]
+pub struct Jetstream {
+    /// wss:// url of the server's subscribe endpoint
+    pub url: String,
+    pub reconnect: ReconnectPolicy,
+    pub watchdog: WatchdogPolicy,
+    /// updated by the consumer as it goes, for anyone who wants to watch
+    pub status: StreamStatus,
+    /// threads decompressing and parsing messages
+    pub decoders: usize,
+    /// where the websocket client runs, like the server's runtime. `None` starts its own.
+    pub runtime: Option<runtime::Handle>,
+}
+
+impl Jetstream {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            reconnect: Default::default(),
+            watchdog: Default::default(),
+            status: Default::default(),
+            decoders: 2,
+            runtime: None,
+        }
+    }
+}
+
+/// Make sure the store's cursor is from this jetstream endpoint, recording it if it's new
+///
+/// Cursors from different jetstream instances don't line up exactly, so switching endpoints needs
+/// `switch_rewind`: the stored cursor is moved back by that much to cover any gap. Like
+/// `start_from`, the overlap is only safe from re-counting if the consumer writes `idempotent`ly.
+pub fn claim_stream(
+    store: &mut impl LinkStorage,
+    url: &str,
+    switch_rewind: Option<time::Duration>,
+    idempotent: bool,
+) -> Result<()> {
+    let Some(stored) = store.get_stream()? else {
+        return store.set_stream(url, None);
+    };
+    if stored == url {
+        return Ok(());
+    }
+    let Some(rewind) = switch_rewind else {
+        bail!("this data was indexed from jetstream {stored:?}, not {url:?}. cursors from different jetstream instances aren't interchangeable: pass --switch-streams to switch anyway (rewinding the cursor to cover the gap).");
+    };
+    let Some(rewound) = store
+        .get_cursor()?
+        .map(|c| c.saturating_sub(rewind.as_micros() as u64))
+    else {
+        println!("switching jetstream from {stored:?} to {url:?} (no cursor stored yet)");
+        return store.set_stream(url, None);
+    };
+    if !idempotent {
+        eprintln!("WARNING: switching jetstream from {stored:?} to {url:?}, rewinding the cursor by {rewind:?} to {rewound}. without idempotent writes, links from events in the overlap will be counted twice.");
+        return store.set_stream(url, Some(rewound));
+    }
+    println!("switching jetstream from {stored:?} to {url:?}, rewinding the cursor by {rewind:?} to {rewound}. events in the overlap will be checked against what's stored (slower) until caught up.");
+    store.rewind_stream(url, rewound)
+}
+
+pub async fn consume_jetstream(
     sender: flume::Sender<JsonValue>,
     cursor: Option<u64>,
-    stream: String,
+    jetstream: Jetstream,
+    archive: Option<EventArchive>,
     staying_alive: CancellationToken,
 ) -> Result<()> {
     describe_counter!(
@@ -64,11 +151,62 @@ pub fn consume_jetstream(
         Unit::Microseconds,
         "microseconds between our clock and the jetstream event's time_us"
     );
+    describe_counter!(
+        "jetstream_disconnected_ms",
+        Unit::Milliseconds,
+        "time spent without a working jetstream connection (counted while reconnecting)"
+    );
+    describe_counter!(
+        "jetstream_watchdog_reconnects",
+        Unit::Count,
+        "connections dropped by the watchdog for stalling or falling behind"
+    );
 
-    let dict = DecoderDictionary::copy(JETSTREAM_ZSTD_DICTIONARY);
-    let mut connect_retries = 0;
+    let Jetstream {
+        url: stream,
+        reconnect,
+        watchdog,
+        status,
+        decoders,
+        runtime: _,
+    } = jetstream;
+    let dict = Arc::new(DecoderDictionary::copy(JETSTREAM_ZSTD_DICTIONARY));
+    let mut reconnects = Reconnects::new(reconnect);
+    let mut watchdog = Watchdog::new(watchdog);
     let mut latest_cursor = cursor;
+    let mut first_try = true;
+
+    // decompressing and parsing happen on their own threads: this task only reads the socket
+    let progress = Arc::new(Progress::default());
+    let mut sent = 0;
+    let (frames, frames_receiver) = flume::bounded(1024);
+    let (decoded_sender, decoded) = flume::bounded(1024);
+    let decoder =
+        pipeline::ordered_pool("decode", decoders, 4096, frames_receiver, decoded_sender, {
+            let stream = stream.clone();
+            move |b| decode(&dict, &stream, b)
+        });
+    let backlog = sender.clone();
+    let forwarder = thread::spawn({
+        let stream = stream.clone();
+        let progress = progress.clone();
+        let status = status.clone();
+        move || forward(decoded, sender, archive, &stream, &progress, &status)
+    });
+
     'outer: loop {
+        if !std::mem::take(&mut first_try) {
+            // reconnect from the last event that made it all the way through decoding
+            while progress.decoded.load(Ordering::SeqCst) < sent && !forwarder.is_finished() {
+                tokio::time::sleep(time::Duration::from_millis(10)).await;
+            }
+            latest_cursor = progress.cursor().or(latest_cursor);
+            status.disconnected();
+            if !back_off(&mut reconnects, &staying_alive).await? {
+                break;
+            }
+        }
+        status.set_state(StreamState::Connecting);
         let stream_url = format!(
             "{stream}?compress=true{}",
             latest_cursor
@@ -82,210 +220,300 @@ pub fn consume_jetstream(
         let ua = format!("microcosm/constellation v{}", env!("CARGO_PKG_VERSION"));
         req.headers_mut().insert("user-agent", ua.parse()?);
 
-        let host = req.uri().host().expect("jetstream request uri has a host");
-        let port = req.uri().port().map(|p| p.as_u16()).unwrap_or(443);
-        let dest = format!("{host}:{port}");
-        let addr = match dest.to_socket_addrs().map(|mut d| d.next()) {
-            Ok(Some(a)) => a,
-            Ok(None) => {
-                eprintln!(
-                    "jetstream: could not resolve an address for {dest:?}. retrying after a bit?"
-                );
-                thread::sleep(time::Duration::from_secs(15));
-                continue;
-            }
-            Err(e) => {
-                eprintln!("jetstream failed to resolve address {dest:?}: {e:?} waiting and then retrying...");
-                thread::sleep(time::Duration::from_secs(3));
-                continue;
-            }
-        };
-        let tcp_stream = match std::net::TcpStream::connect_timeout(
-            &addr,
-            time::Duration::from_secs(8),
-        ) {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!(
-                    "jetstream failed to make tcp connection: {e:?}. (todo: clean up retry logic)"
-                );
-                connect_retries += 1;
-                if connect_retries >= 7 {
-                    eprintln!("jetstream: no more connect retries, breaking out.");
-                    break;
+        counter!("jetstream_connect", "url" => stream.clone(), "is_retry" => (reconnects.retries() > 0).to_string()).increment(1);
+        println!(
+            "jetstream connecting, attempt #{}, {stream_url:?} with user-agent: {ua:?}",
+            reconnects.retries()
+        );
+        let connecting = tokio::time::timeout(CONNECT_TIMEOUT, connect_async(req));
+        let mut socket = tokio::select! {
+            _ = staying_alive.cancelled() => break,
+            connected = connecting => match connected {
+                Ok(Ok((socket, _))) => {
+                    println!("jetstream connected.");
+                    reconnects.connected(); // retries are only reset once it's been up for a while
+                    watchdog.reset();
+                    socket
                 }
-                let backoff = time::Duration::from_secs(connect_retries.try_into().unwrap());
-                eprintln!("jetstream tcp failed to connect: {e:?}. backing off {backoff:?} before retrying...");
-                thread::sleep(backoff);
-                continue;
-            }
-        };
-        tcp_stream.set_read_timeout(Some(time::Duration::from_secs(4)))?;
-        tcp_stream.set_write_timeout(Some(time::Duration::from_secs(4)))?;
-
-        counter!("jetstream_connect", "url" => stream.clone(), "is_retry" => (connect_retries > 0).to_string()).increment(1);
-        println!("jetstream connecting, attempt #{connect_retries}, {stream_url:?} with user-agent: {ua:?}");
-        let mut socket = match tungstenite::client_tls(req, tcp_stream) {
-            Ok((socket, _)) => {
-                println!("jetstream connected.");
-                // connect_retries = 0; // only reset once we have received a message vvv
-                socket
-            }
-            Err(e) => {
-                connect_retries += 1;
-                if connect_retries >= 7 {
-                    eprintln!("jetstream: no more connect retries, breaking out.");
-                    break;
+                Ok(Err(e)) => {
+                    eprintln!("jetstream failed to connect: {e:?}");
+                    continue;
                 }
-                let backoff = time::Duration::from_secs(connect_retries.try_into().unwrap());
-                eprintln!("jetstream failed to connect: {e:?}. backing off {backoff:?} before retrying...");
-                thread::sleep(backoff);
-                continue;
-            }
+                Err(_) => {
+                    eprintln!("jetstream: timed out connecting after {CONNECT_TIMEOUT:?}");
+                    continue;
+                }
+            },
         };
 
+        let mut keepalive = tokio::time::interval(PING_INTERVAL);
+        keepalive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_heard = time::Instant::now();
+
         loop {
-            if !socket.can_read() {
-                eprintln!("jetstream: socket says we cannot read -- flushing then breaking out.");
-                if let Err(e) = socket.flush() {
-                    eprintln!("error while flushing socket: {e:?}");
+            let message = tokio::select! {
+                _ = staying_alive.cancelled() => {
+                    eprintln!("jetstream: cancelling");
+                    close(&mut socket, "shutting down").await;
+                    break 'outer;
                 }
-                break;
+                _ = keepalive.tick() => {
+                    if last_heard.elapsed() > READ_TIMEOUT {
+                        counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "timed out").increment(1);
+                        eprintln!("jetstream: nothing from the server for {READ_TIMEOUT:?}, not even a pong. reconnecting.");
+                        close(&mut socket, "timed out").await;
+                        break;
+                    }
+                    if let Err(e) = timed(socket.send(Message::Ping(Default::default()))).await {
+                        eprintln!("jetstream: failed to send a keepalive ping, reconnecting: {e:?}");
+                        break;
+                    }
+                    None
+                }
+                m = socket.next() => Some(m),
+            };
+
+            if forwarder.is_finished() {
+                close(&mut socket, "shutting down").await;
+                break 'outer; // nowhere to send events: its result says why
             }
 
-            if staying_alive.is_cancelled() {
-                eprintln!("jetstream: cancelling");
-                // TODO: cleanly close the connection?
-                break 'outer;
+            // everything up to the decoded cursor is already queued for storage, so reconnecting
+            // from it doesn't lose anything.
+            let backed_up = backlog
+                .capacity()
+                .map(|cap| backlog.len() * 2 > cap)
+                .unwrap_or(false);
+            let cursor_age = progress.cursor().or(latest_cursor).map(ts_age);
+            if watchdog.check(cursor_age, backed_up, &status) {
+                close(&mut socket, "stalled").await;
+                break;
             }
 
+            let Some(message) = message else {
+                continue; // just the keepalive
+            };
             counter!("jetstream_read").increment(1);
-            let b = match socket.read() {
-                Ok(Message::Binary(b)) => b,
-                Ok(Message::Text(_)) => {
+            let b = match message {
+                Some(Ok(m)) if m.is_ping() || m.is_pong() => {
+                    // tungstenite answers pings for us
+                    last_heard = time::Instant::now();
+                    continue;
+                }
+                Some(Ok(Message::Binary(b))) => b,
+                Some(Ok(Message::Text(_))) => {
                     counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "received text")
                         .increment(1);
                     eprintln!("jetstream: unexpected text message, should be binary for compressed (ignoring)");
                     continue;
                 }
-                Ok(Message::Close(f)) => {
+                Some(Ok(Message::Close(f))) => {
                     counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "server closed")
                         .increment(1);
                     println!("jetstream: closing the connection: {f:?}");
-                    continue;
+                    continue; // our reply goes out with the next read, which then ends the stream
                 }
-                Ok(m) => {
+                Some(Ok(m)) => {
                     counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "unexpected message", "message" => format!("{m:?}")).increment(1);
                     eprintln!("jetstream: unexpected from read (ignoring): {m:?}");
                     continue;
                 }
-                Err(TError::ConnectionClosed) => {
+                None | Some(Err(TError::ConnectionClosed)) => {
                     // clean exit
                     counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "clean close")
                         .increment(1);
                     println!("jetstream closed the websocket cleanly.");
                     break;
                 }
-                Err(TError::AlreadyClosed) => {
+                Some(Err(TError::AlreadyClosed)) => {
                     // programming error
                     counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "already closed")
                         .increment(1);
                     eprintln!(
-                        "jetstream: got AlreadyClosed trying to .read() websocket. probably a bug."
+                        "jetstream: got AlreadyClosed trying to read the websocket. probably a bug."
                     );
                     break;
                 }
-                Err(TError::Capacity(e)) => {
+                Some(Err(TError::Capacity(e))) => {
                     counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "capacity error")
                         .increment(1);
                     eprintln!("jetstream: capacity error (ignoring): {e:?}");
                     continue;
                 }
-                Err(TError::Utf8) => {
+                Some(Err(TError::Utf8)) => {
                     counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "utf8 error")
                         .increment(1);
                     eprintln!("jetstream: utf8 error (ignoring)");
                     continue;
                 }
-                Err(e) => {
-                    eprintln!("jetstream: could not read message from socket. closing: {e:?}");
-                    if let TError::Io(io_err) = e {
-                        if matches!(io_err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) {
-                            counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "timed out").increment(1);
-                            println!("jetstream socket timed out. bailing to reconnect -- should we be trying to close first?");
-                            break;
-                        }
-                    }
-                    match socket.close(None) {
-                        Err(TError::ConnectionClosed) => {
-                            counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "clean close").increment(1);
-                            println!("jetstream closed the websocket cleanly.");
-                            break;
-                        }
-                        r => eprintln!("jetstream: close result after error: {r:?}"),
-                    }
+                Some(Err(e)) => {
                     counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "read error")
                         .increment(1);
-                    // if we didn't immediately get ConnectionClosed, we should keep polling read
-                    // until we get it.
-                    continue;
+                    eprintln!("jetstream: could not read message from socket, reconnecting: {e:?}");
+                    break;
                 }
             };
+            last_heard = time::Instant::now();
 
             counter!("jetstream_read_bytes", "url" => stream.clone()).increment(b.len() as u64);
-            let mut cursor = Cursor::new(b);
-            let mut decoder = match zstd::stream::Decoder::with_prepared_dictionary(
-                &mut cursor,
-                &dict,
-            ) {
-                Ok(d) => d,
-                Err(e) => {
-                    counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "zstd decompress")
-                        .increment(1);
-                    eprintln!("jetstream: failed to decompress zstd message: {e:?}");
-                    continue;
-                }
-            };
-
-            let mut s = String::new();
-            match decoder.read_to_string(&mut s) {
-                Ok(n) => {
-                    counter!("jetstream_read_bytes_decompressed", "url" => stream.clone())
-                        .increment(n as u64);
-                    histogram!("jetstream_read_bytes_decompressed", "url" => stream.clone())
-                        .record(n as f64);
-                }
-                Err(e) => {
-                    counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "zstd string decode")
-                        .increment(1);
-                    eprintln!("jetstream: failed to decode zstd: {e:?}");
-                    continue;
-                }
+            if frames.send_async(b).await.is_err() {
+                break 'outer;
             }
+            sent += 1;
 
-            let v = match s.parse() {
-                Ok(v) => v,
-                Err(e) => {
-                    counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "json parse")
-                        .increment(1);
-                    eprintln!("jetstream: failed to parse message as json: {e:?}");
-                    continue;
-                }
-            };
+            reconnects.received();
+            watchdog.event(progress.cursor().map(ts_age));
+        }
+    }
 
-            // bit of a hack to have this here for now...
-            let ts = match get_event_time(&v) {
-                Some(ts) => ts,
-                None => {
-                    counter!("jetstream_read_fail", "url" => stream.clone(), "reason" => "invalid event")
-                        .increment(1);
-                    eprintln!("jetstream: encountered an event without a timestamp: ignoring it.");
-                    continue;
+    drop(frames);
+    tokio::task::spawn_blocking(move || {
+        let decoded = decoder.join();
+        let forwarded = forwarder.join().unwrap();
+        if decoded.is_err() {
+            bail!("jetstream: decoding panicked");
+        }
+        forwarded
+    })
+    .await?
+}
+
+/// Run the jetstream client on `runtime` (like the server's), or on one of its own
+pub(super) fn block_on(
+    runtime: Option<runtime::Handle>,
+    client: impl Future<Output = Result<()>> + Send + 'static,
+) -> Result<()> {
+    match runtime {
+        // spawned, so it runs on the runtime's workers instead of whichever thread waits for it
+        Some(runtime) => runtime.block_on(runtime.spawn(client))?,
+        None => runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(client),
+    }
+}
+
+/// give up on a write that's taking too long: the connection is probably gone
+async fn timed<T>(write: impl Future<Output = Result<T, TError>>) -> Result<T> {
+    match tokio::time::timeout(WRITE_TIMEOUT, write).await {
+        Ok(r) => Ok(r?),
+        Err(_) => bail!("write timed out after {WRITE_TIMEOUT:?}"),
+    }
+}
+
+/// say goodbye properly, waiting (a bit) for the server to close its side
+async fn close(socket: &mut Socket, reason: &str) {
+    let frame = CloseFrame {
+        code: CloseCode::Normal,
+        reason: reason.into(),
+    };
+    if let Err(e) = timed(socket.close(Some(frame))).await {
+        eprintln!("jetstream: error closing the connection (ignoring): {e:?}");
+        return;
+    }
+    let drained = tokio::time::timeout(WRITE_TIMEOUT, async {
+        while let Some(Ok(_)) = socket.next().await {}
+    });
+    if drained.await.is_err() {
+        eprintln!("jetstream: server didn't finish closing within {WRITE_TIMEOUT:?} (ignoring)");
+    }
+}
+
+/// How far the decoded events have gotten, for picking a cursor to reconnect from
+#[derive(Debug, Default)]
+struct Progress {
+    /// messages that made it through decoding (or were dropped by it)
+    decoded: AtomicU64,
+    /// last event forwarded. zero: none yet.
+    cursor: AtomicU64,
+}
+
+impl Progress {
+    fn cursor(&self) -> Option<u64> {
+        match self.cursor.load(Ordering::SeqCst) {
+            0 => None,
+            c => Some(c),
+        }
+    }
+}
+
+/// decompress and parse one jetstream message
+fn decode(dict: &DecoderDictionary, stream: &str, b: Bytes) -> Option<(String, JsonValue, u64)> {
+    let mut cursor = Cursor::new(b);
+    let mut decoder = match zstd::stream::Decoder::with_prepared_dictionary(&mut cursor, dict) {
+        Ok(d) => d,
+        Err(e) => {
+            counter!("jetstream_read_fail", "url" => stream.to_string(), "reason" => "zstd decompress")
+                .increment(1);
+            eprintln!("jetstream: failed to decompress zstd message: {e:?}");
+            return None;
+        }
+    };
+
+    let mut s = String::new();
+    match decoder.read_to_string(&mut s) {
+        Ok(n) => {
+            counter!("jetstream_read_bytes_decompressed", "url" => stream.to_string())
+                .increment(n as u64);
+            histogram!("jetstream_read_bytes_decompressed", "url" => stream.to_string())
+                .record(n as f64);
+        }
+        Err(e) => {
+            counter!("jetstream_read_fail", "url" => stream.to_string(), "reason" => "zstd string decode")
+                .increment(1);
+            eprintln!("jetstream: failed to decode zstd: {e:?}");
+            return None;
+        }
+    }
+
+    let v = match s.parse() {
+        Ok(v) => v,
+        Err(e) => {
+            counter!("jetstream_read_fail", "url" => stream.to_string(), "reason" => "json parse")
+                .increment(1);
+            eprintln!("jetstream: failed to parse message as json: {e:?}");
+            return None;
+        }
+    };
+
+    // bit of a hack to have this here for now...
+    let Some(ts) = get_event_time(&v) else {
+        counter!("jetstream_read_fail", "url" => stream.to_string(), "reason" => "invalid event")
+            .increment(1);
+        eprintln!("jetstream: encountered an event without a timestamp: ignoring it.");
+        return None;
+    };
+    Some((s, v, ts))
+}
+
+/// send decoded events on to storage in order, archiving them on the way
+fn forward(
+    decoded: flume::Receiver<Option<(String, JsonValue, u64)>>,
+    sender: flume::Sender<JsonValue>,
+    mut archive: Option<EventArchive>,
+    stream: &str,
+    progress: &Progress,
+    status: &StreamStatus,
+) -> Result<()> {
+    loop {
+        let event = match decoded.recv_timeout(ARCHIVE_TICK) {
+            Ok(event) => event,
+            Err(flume::RecvTimeoutError::Timeout) => {
+                // quiet or disconnected: segments still have to rotate on time
+                if let Some(ref mut archive) = archive {
+                    archive.tick();
                 }
-            };
+                continue;
+            }
+            Err(flume::RecvTimeoutError::Disconnected) => break,
+        };
+        if let Some((s, v, ts)) = event {
+            if let Some(ref mut archive) = archive {
+                archive.write(&s, ts);
+            }
 
             if let Err(flume::SendError(_rejected)) = sender.send(v) {
-                counter!("jetstream_events", "url" => stream.clone()).increment(1);
+                counter!("jetstream_events", "url" => stream.to_string()).increment(1);
                 if sender.is_disconnected() {
                     eprintln!("jetstream: send channel disconnected -- nothing to do, bye.");
                     bail!("jetstream: send channel disconnected");
@@ -294,22 +522,39 @@ pub fn consume_jetstream(
                     "jetstream: failed to send on channel, dropping update! (FIXME / HANDLEME)"
                 );
             }
-            histogram!("jetstream_events_queued", "url" => stream.clone())
+            histogram!("jetstream_events_queued", "url" => stream.to_string())
                 .record(sender.len() as f64);
 
             // only actually update our cursor after we've managed to queue the event
-            latest_cursor = Some(ts);
-            gauge!("jetstream_cursor_age", "url" => stream.clone())
+            progress.cursor.store(ts, Ordering::SeqCst);
+            gauge!("jetstream_cursor_age", "url" => stream.to_string())
                 .set(ts_age(ts).as_micros() as f64);
-
-            // great news if we got this far -- might be safe to assume the connection is up.
-            connect_retries = 0;
+            status.event(ts);
         }
+        progress.decoded.fetch_add(1, Ordering::SeqCst);
     }
     Ok(())
 }
 
-fn get_event_time(v: &JsonValue) -> Option<u64> {
+/// wait out the next reconnect delay. false if we got cancelled while waiting.
+async fn back_off(reconnects: &mut Reconnects, staying_alive: &CancellationToken) -> Result<bool> {
+    let Some(backoff) = reconnects.retry() else {
+        bail!(
+            "jetstream: giving up after {} reconnect attempts",
+            reconnects.retries() - 1
+        );
+    };
+    eprintln!(
+        "jetstream: backing off {backoff:?} before reconnecting (retry #{})...",
+        reconnects.retries()
+    );
+    tokio::select! {
+        _ = staying_alive.cancelled() => Ok(false),
+        _ = tokio::time::sleep(backoff) => Ok(!staying_alive.is_cancelled()),
+    }
+}
+
+pub(super) fn get_event_time(v: &JsonValue) -> Option<u64> {
     if let JsonValue::Object(root) = v {
         if let JsonValue::Number(time_us) = root.get("time_us")? {
             return Some(*time_us as u64);
@@ -318,8 +563,244 @@ fn get_event_time(v: &JsonValue) -> Option<u64> {
     None
 }
 
-fn ts_age(ts: u64) -> time::Duration {
+pub(super) fn ts_age(ts: u64) -> time::Duration {
     (time::UNIX_EPOCH + time::Duration::from_micros(ts))
         .elapsed()
         .unwrap_or(time::Duration::from_secs(0)) // saturate zero if ts > our system time
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemStorage;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use tungstenite::handshake::server::{Request, Response};
+
+    fn fast_policy(max_retries: Option<u32>) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: time::Duration::from_millis(1),
+            max_delay: time::Duration::from_millis(10),
+            max_retries,
+            ..Default::default()
+        }
+    }
+
+    fn compressed_event(ts: u64) -> Vec<u8> {
+        let event = format!(r#"{{"did":"did:plc:asdf","time_us":{ts},"kind":"identity"}}"#);
+        zstd::bulk::Compressor::with_dictionary(3, JETSTREAM_ZSTD_DICTIONARY)
+            .unwrap()
+            .compress(event.as_bytes())
+            .unwrap()
+    }
+
+    #[test]
+    #[allow(clippy::result_large_err)] // the handshake callback's signature is tungstenite's
+    fn test_reconnects_from_latest_cursor() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let (uri_sender, uris) = mpsc::channel();
+
+        // each connection gets a single event and then gets hung up on
+        let server = thread::spawn(move || {
+            for ts in [10, 11] {
+                let (tcp, _) = listener.accept().unwrap();
+                let uri_sender = uri_sender.clone();
+                let mut ws = tungstenite::accept_hdr(tcp, |req: &Request, res: Response| {
+                    uri_sender.send(req.uri().to_string()).unwrap();
+                    Ok(res)
+                })
+                .unwrap();
+                ws.send(Message::binary(compressed_event(ts))).unwrap();
+                ws.close(None).unwrap();
+                while ws.read().is_ok() {}
+            }
+        });
+
+        let (sender, receiver) = flume::bounded(8);
+        let staying_alive = CancellationToken::new();
+        let client = thread::spawn({
+            let jetstream = Jetstream {
+                reconnect: fast_policy(None),
+                ..Jetstream::new(format!("ws://127.0.0.1:{port}/subscribe"))
+            };
+            let staying_alive = staying_alive.clone();
+            move || {
+                block_on(
+                    None,
+                    consume_jetstream(sender, None, jetstream, None, staying_alive),
+                )
+            }
+        });
+
+        let received: Vec<_> = receiver
+            .iter()
+            .take(2)
+            .map(|v| get_event_time(&v))
+            .collect();
+        staying_alive.cancel();
+        client.join().unwrap()?;
+        server.join().unwrap();
+
+        assert_eq!(received, vec![Some(10), Some(11)]);
+        let uris: Vec<String> = uris.try_iter().collect();
+        assert_eq!(uris.len(), 2);
+        assert!(!uris[0].contains("cursor="));
+        assert!(uris[1].ends_with("&cursor=10"));
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::result_large_err)]
+    fn test_watchdog_reconnects_a_silent_stream() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let (uri_sender, uris) = mpsc::channel();
+
+        let server = thread::spawn(move || {
+            // first connection: one event, then nothing but pings (so the socket stays up)
+            let (tcp, _) = listener.accept().unwrap();
+            let mut ws = tungstenite::accept_hdr(tcp, |req: &Request, res: Response| {
+                uri_sender.send(req.uri().to_string()).unwrap();
+                Ok(res)
+            })
+            .unwrap();
+            ws.send(Message::binary(compressed_event(20))).unwrap();
+            let silent = thread::spawn(move || {
+                while ws.send(Message::Ping(Default::default())).is_ok() {
+                    thread::sleep(time::Duration::from_millis(20));
+                }
+            });
+
+            // second connection: things are flowing again
+            let (tcp, _) = listener.accept().unwrap();
+            let mut ws = tungstenite::accept_hdr(tcp, |req: &Request, res: Response| {
+                uri_sender.send(req.uri().to_string()).unwrap();
+                Ok(res)
+            })
+            .unwrap();
+            ws.send(Message::binary(compressed_event(21))).unwrap();
+            while ws.read().is_ok() {}
+            silent.join().unwrap();
+        });
+
+        let (sender, receiver) = flume::bounded(8);
+        let staying_alive = CancellationToken::new();
+        let jetstream = Jetstream {
+            reconnect: fast_policy(None),
+            watchdog: WatchdogPolicy {
+                stall_after: time::Duration::from_millis(200),
+                ..Default::default()
+            },
+            ..Jetstream::new(format!("ws://127.0.0.1:{port}/subscribe"))
+        };
+        let status = jetstream.status.clone();
+        let client = thread::spawn({
+            let staying_alive = staying_alive.clone();
+            move || {
+                block_on(
+                    None,
+                    consume_jetstream(sender, None, jetstream, None, staying_alive),
+                )
+            }
+        });
+
+        let received: Vec<_> = receiver
+            .iter()
+            .take(2)
+            .map(|v| get_event_time(&v))
+            .collect();
+        staying_alive.cancel();
+        client.join().unwrap()?;
+        server.join().unwrap();
+        let health = status.health();
+
+        assert_eq!(received, vec![Some(20), Some(21)]);
+        assert_eq!(health.forced_reconnects, 1);
+        assert_eq!(health.cursor, Some(21));
+        let uris: Vec<String> = uris.try_iter().collect();
+        assert!(uris[1].ends_with("&cursor=20"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancelling_closes_the_connection() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+
+        let server = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let mut ws = tungstenite::accept(tcp).unwrap();
+            ws.send(Message::binary(compressed_event(30))).unwrap();
+            loop {
+                match ws.read().unwrap() {
+                    Message::Close(frame) => return frame,
+                    _ => continue,
+                }
+            }
+        });
+
+        let (sender, receiver) = flume::bounded(8);
+        let staying_alive = CancellationToken::new();
+        let client = thread::spawn({
+            let jetstream = Jetstream::new(format!("ws://127.0.0.1:{port}/subscribe"));
+            let staying_alive = staying_alive.clone();
+            move || {
+                block_on(
+                    None,
+                    consume_jetstream(sender, None, jetstream, None, staying_alive),
+                )
+            }
+        });
+
+        assert_eq!(get_event_time(&receiver.recv()?), Some(30));
+        let t0 = time::Instant::now();
+        staying_alive.cancel();
+        client.join().unwrap()?;
+        assert!(t0.elapsed() < time::Duration::from_secs(2));
+        let frame = server.join().unwrap().expect("a close frame");
+        assert_eq!(frame.code, CloseCode::Normal);
+        Ok(())
+    }
+
+    #[test]
+    fn test_claim_stream() -> Result<()> {
+        let mut store = MemStorage::new();
+        claim_stream(&mut store, "wss://a", None, true)?;
+        store.push(
+            &crate::ActionableEvent::ActivateAccount("did:plc:asdf".into()),
+            5_000_000,
+        )?;
+        claim_stream(&mut store, "wss://a", None, true)?;
+        assert!(claim_stream(&mut store, "wss://b", None, true).is_err());
+        assert_eq!(store.get_cursor()?, Some(5_000_000));
+
+        claim_stream(
+            &mut store,
+            "wss://b",
+            Some(time::Duration::from_secs(2)),
+            true,
+        )?;
+        assert_eq!(store.get_stream()?.as_deref(), Some("wss://b"));
+        assert_eq!(store.get_cursor()?, Some(3_000_000));
+        assert_eq!(store.get_seen_until()?, Some(5_000_000));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gives_up_after_max_retries() -> Result<()> {
+        // grab a free port and then close it, so every connection is refused
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let (sender, _receiver) = flume::bounded(1);
+        let jetstream = Jetstream {
+            reconnect: fast_policy(Some(2)),
+            ..Jetstream::new(format!("ws://127.0.0.1:{port}/subscribe"))
+        };
+        let res = block_on(
+            None,
+            consume_jetstream(sender, None, jetstream, None, CancellationToken::new()),
+        );
+        assert!(res.unwrap_err().to_string().contains("giving up after 2"));
+        Ok(())
+    }
+}
@@ -1,25 +1,45 @@
+mod archive;
+mod dead_letter;
 mod jetstream;
-mod jsonl_file;
+mod pipeline;
+mod reconnect;
+mod reindex;
+mod replay;
+mod start;
+mod watchdog;
 
+use crate::policy::PurgeQueue;
 use crate::storage::LinkStorage;
 use crate::{ActionableEvent, RecordId};
-use anyhow::Result;
+use anyhow::{bail, Result};
+pub use archive::{list_segments, ArchiveRetention, EventArchive, Segment};
+pub use dead_letter::{read_dead_letters, replay_dead_letters, DeadLetter, DeadLetters};
 use jetstream::consume_jetstream;
-use jsonl_file::consume_jsonl_file;
+pub use jetstream::{claim_stream, Jetstream};
 use links::collect_links;
-use metrics::{counter, describe_counter, describe_histogram, histogram, Unit};
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
+use metrics::{
+    counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit,
+};
+pub use pipeline::Pipeline;
+pub use reconnect::ReconnectPolicy;
+pub use reindex::{catch_up, diff_source_counts, rebuild, SourceCountDiff};
+pub use replay::{Replay, ReplaySpeed, ReplayStats};
+pub use start::{start_from, StartFrom};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
 use std::thread;
+use std::time::Duration;
 use tinyjson::JsonValue;
 use tokio_util::sync::CancellationToken;
+pub use watchdog::{StreamHealth, StreamState, StreamStatus, WatchdogPolicy};
 
 pub fn consume(
-    mut store: impl LinkStorage,
-    qsize: Arc<AtomicU32>,
-    fixture: Option<PathBuf>,
-    stream: String,
+    store: &mut impl LinkStorage,
+    pipeline: Pipeline,
+    replay: Option<Replay>,
+    jetstream: Jetstream,
+    mut dead_letters: DeadLetters,
+    archive: Option<EventArchive>,
     staying_alive: CancellationToken,
 ) -> Result<()> {
     describe_counter!(
@@ -42,34 +62,238 @@ pub fn consume(
         Unit::Count,
         "number of links per message"
     );
+    describe_counter!(
+        "consumer_events_dead_lettered",
+        Unit::Count,
+        "events that failed to be written to storage"
+    );
+    describe_counter!(
+        "consumer_dead_letter_write_fail",
+        Unit::Count,
+        "failures to save an event to a dead-letter log"
+    );
+    describe_histogram!(
+        "consumer_write_batch_events",
+        Unit::Count,
+        "events stored together in one push to storage"
+    );
+    describe_counter!(
+        "consumer_events_excluded",
+        Unit::Count,
+        "events dropped because of the exclusion policy"
+    );
+    describe_gauge!(
+        "consumer_idempotent_writes",
+        Unit::Count,
+        "1 while checking for already-stored records, because events might overlap"
+    );
 
-    let (receiver, consumer_handle) = if let Some(f) = fixture {
+    // archives are usually replayed over something that already has (some of) them
+    let replaying = replay.is_some();
+    let mut overlap = pipeline
+        .idempotent_window
+        .map(|window| Overlap::from_store(store, window))
+        .transpose()?;
+    let mut idempotent = false;
+
+    let (receiver, consumer_handle) = if let Some(replay) = replay {
         let (sender, receiver) = flume::bounded(21);
         (
             receiver,
-            thread::spawn(move || consume_jsonl_file(f, sender)),
+            thread::spawn(move || {
+                replay::consume_replay(replay, sender, staying_alive).map(|_| ())
+            }),
         )
     } else {
         let (sender, receiver) = flume::bounded(32_768); // eek
         let cursor = store.get_cursor().unwrap();
+        let runtime = jetstream.runtime.clone();
+        let client = consume_jetstream(sender, cursor, jetstream, archive, staying_alive);
         (
             receiver,
-            thread::spawn(move || consume_jetstream(sender, cursor, stream, staying_alive)),
+            thread::spawn(move || jetstream::block_on(runtime, client)),
         )
     };
 
-    for update in receiver.iter() {
-        if let Some((action, ts)) = get_actionable(&update) {
-            {
-                store.push(&action, ts).unwrap();
-                qsize.store(receiver.len().try_into().unwrap(), Ordering::Relaxed);
+    // link extraction is cpu-heavy, so it gets a pool, leaving this thread to just write
+    let (extracted_sender, extracted) = flume::bounded(pipeline.window);
+    let extractor = pipeline::ordered_pool(
+        "extract",
+        pipeline.workers,
+        pipeline.window,
+        receiver,
+        extracted_sender,
+        |update| {
+            let actionable = get_actionable(&update);
+            (update, actionable)
+        },
+    );
+
+    // this store's own: another consumer purging its store doesn't purge this one
+    let purges = pipeline.policy.purge_queue();
+    let max_batch = pipeline.max_batch.max(1);
+    let mut batch = WriteBatch::default();
+    loop {
+        let next = match extracted.recv_timeout(IDLE_PURGE_INTERVAL) {
+            Ok(next) => next,
+            Err(flume::RecvTimeoutError::Timeout) => {
+                // newly excluded accounts shouldn't have to wait for the stream to pick up
+                if let Ok(Some(cursor)) = store.get_cursor() {
+                    purge_excluded(store, &purges, cursor);
+                }
+                continue;
             }
+            Err(flume::RecvTimeoutError::Disconnected) => break,
+        };
+        let policy = pipeline.policy.current();
+        let mut last_ts = None;
+        // take whatever else is already waiting: single events while keeping up, bigger writes
+        // while behind
+        let waiting = extracted.len().min(max_batch - 1);
+        for (update, actionable) in std::iter::once(next).chain(extracted.try_iter().take(waiting))
+        {
+            let Some((action, ts)) = actionable else {
+                counter!("consumer_events_non_actionable").increment(1);
+                dead_letters.rejected(&update);
+                continue;
+            };
+            // not even to the rejected log
+            let Some(action) = policy.filter(action) else {
+                counter!("consumer_events_excluded").increment(1);
+                continue;
+            };
+            last_ts = Some(ts);
+            let careful = replaying || overlap.as_mut().is_some_and(|o| o.check(ts));
+            if careful != idempotent {
+                batch.write(store, &mut dead_letters, idempotent);
+                if careful {
+                    println!("consumer: events at {ts} may already be stored, switching to idempotent writes");
+                } else {
+                    println!("consumer: caught up at {ts}, switching back to fast writes");
+                }
+                store.set_idempotent(careful);
+                gauge!("consumer_idempotent_writes").set(if careful { 1.0 } else { 0.0 });
+                idempotent = careful;
+            }
+            batch.events.push((action, ts));
+            batch.updates.push(update);
+        }
+        batch.write(store, &mut dead_letters, idempotent);
+        if let Some(ts) = last_ts {
+            purge_excluded(store, &purges, ts);
+        }
+        pipeline
+            .qsize
+            .store(extracted.len().try_into().unwrap(), Ordering::Relaxed);
+    }
+    // a short run (or a quiet one that got stopped) may not have gotten to them yet
+    if let Ok(Some(cursor)) = store.get_cursor() {
+        purge_excluded(store, &purges, cursor);
+    }
+
+    let extracted = extractor.join();
+    let consumed = consumer_handle.join().unwrap();
+    if extracted.is_err() {
+        bail!("consumer: link extraction panicked");
+    }
+    consumed
+}
+
+/// How long the consumer waits for events before checking for purges anyway
+const IDLE_PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Remove everything stored for accounts the policy newly excludes
+///
+/// It's the same as the account being deleted. Ones that fail are tried again next time.
+fn purge_excluded(store: &mut impl LinkStorage, purges: &PurgeQueue, cursor: u64) {
+    let mut failed = Vec::new();
+    for did in purges.take() {
+        println!("consumer: purging links from excluded account {did:?}");
+        if let Err(e) = store.push(&ActionableEvent::DeleteAccount(did.clone()), cursor) {
+            eprintln!("consumer: failed to purge {did:?}, will retry: {e:?}");
+            failed.push(did);
         } else {
-            counter!("consumer_events_non_actionable").increment(1);
+            purges.purged();
         }
     }
+    if !failed.is_empty() {
+        purges.retry(failed);
+    }
+}
 
-    consumer_handle.join().unwrap()
+/// Whether events might have been stored already
+///
+/// That's everything up to the newest cursor seen, and a window past it: right after starting up,
+/// and after any rewind (like jetstream reconnecting from a cursor a bit behind).
+struct Overlap {
+    window: u64,
+    newest: u64,
+    until: Option<u64>,
+}
+
+impl Overlap {
+    fn new(seen: Option<u64>, window: Duration) -> Self {
+        let window = window.as_micros() as u64;
+        Self {
+            window,
+            newest: seen.unwrap_or(0),
+            until: seen.map(|c| c + window),
+        }
+    }
+
+    /// everything the store may have stored: up to its cursor, or further if it was rewound
+    fn from_store(store: &mut impl LinkStorage, window: Duration) -> Result<Self> {
+        let seen = store.get_cursor()?.max(store.get_seen_until()?);
+        Ok(Self::new(seen, window))
+    }
+
+    /// whether the event at `cursor` needs an idempotent write
+    fn check(&mut self, cursor: u64) -> bool {
+        if cursor < self.newest {
+            let until = self.newest + self.window;
+            self.until = Some(self.until.map_or(until, |u| u.max(until)));
+        }
+        self.newest = self.newest.max(cursor);
+        self.until.is_some_and(|until| cursor <= until)
+    }
+}
+
+/// Events waiting to be stored together, with their originals for dead-lettering
+#[derive(Default)]
+struct WriteBatch {
+    events: Vec<(ActionableEvent, u64)>,
+    updates: Vec<JsonValue>,
+}
+
+impl WriteBatch {
+    fn write(
+        &mut self,
+        store: &mut impl LinkStorage,
+        dead_letters: &mut DeadLetters,
+        idempotent: bool,
+    ) {
+        if self.events.is_empty() {
+            return;
+        }
+        histogram!("consumer_write_batch_events").record(self.events.len() as f64);
+        if let Err(e) = store.push_batch(&self.events) {
+            // find the broken event(s) by going one at a time. some of the batch may have been
+            // stored before it failed, so go carefully to not count those twice.
+            eprintln!(
+                "consumer: failed to write a batch of {} events, retrying them one by one: {e:?}",
+                self.events.len()
+            );
+            store.set_idempotent(true);
+            for ((action, ts), update) in self.events.iter().zip(&self.updates) {
+                if let Err(e) = store.push(action, *ts) {
+                    dead_letters.failed(update, *ts, &e);
+                }
+            }
+            store.set_idempotent(idempotent);
+        }
+        self.events.clear();
+        self.updates.clear();
+    }
 }
 
 pub fn get_actionable(event: &JsonValue) -> Option<(ActionableEvent, u64)> {
@@ -97,6 +321,9 @@ pub fn get_actionable(event: &JsonValue) -> Option<(ActionableEvent, u64)> {
             let JsonValue::String(rkey) = commit.get("rkey")? else {
                 return None;
             };
+            if collection == PROFILE_COLLECTION && rkey == "self" {
+                return get_profile_update(did, commit, cursor);
+            }
             match commit.get("operation")? {
                 JsonValue::String(op) if op == "create" => {
                     let links = collect_links(commit.get("record")?);
@@ -192,13 +419,88 @@ pub fn get_actionable(event: &JsonValue) -> Option<(ActionableEvent, u64)> {
                 _ => None,
             }
         }
+        JsonValue::Object(root)
+            if root.get("kind") == Some(&JsonValue::String("identity".to_string())) =>
+        {
+            let JsonValue::Object(identity) = root.get("identity")? else {
+                return None;
+            };
+            let did = identity.get("did")?.get::<String>()?.clone();
+            let handle = match identity.get("handle") {
+                Some(JsonValue::String(handle)) => Some(handle.to_lowercase()),
+                _ => None,
+            };
+            counter!("consumer_events_actionable", "action_type" => "identity").increment(1);
+            Some((
+                ActionableEvent::Identity {
+                    did: did.into(),
+                    handle,
+                },
+                cursor,
+            ))
+        }
         _ => None,
     }
 }
 
+const PROFILE_COLLECTION: &str = "app.bsky.actor.profile";
+/// self-label for "don't show my account to logged-out users", which is everyone here
+const NO_UNAUTHENTICATED: &str = "!no-unauthenticated";
+
+fn get_profile_update(
+    did: &str,
+    commit: &HashMap<String, JsonValue>,
+    cursor: u64,
+) -> Option<(ActionableEvent, u64)> {
+    let (new_links, private) = match commit.get("operation")? {
+        JsonValue::String(op) if op == "create" || op == "update" => {
+            let record = commit.get("record")?;
+            (
+                collect_links(record),
+                has_self_label(record, NO_UNAUTHENTICATED),
+            )
+        }
+        JsonValue::String(op) if op == "delete" => (vec![], false),
+        _ => return None,
+    };
+    counter!("consumer_events_actionable", "action_type" => "update_profile", "collection" => PROFILE_COLLECTION).increment(1);
+    Some((
+        ActionableEvent::UpdateProfile {
+            record_id: RecordId {
+                did: did.into(),
+                collection: PROFILE_COLLECTION.into(),
+                rkey: "self".into(),
+            },
+            new_links,
+            private,
+        },
+        cursor,
+    ))
+}
+
+/// `{"labels": {"$type": "com.atproto.label.defs#selfLabels", "values": [{"val": ...}]}}`
+fn has_self_label(record: &JsonValue, label: &str) -> bool {
+    let JsonValue::Object(record) = record else {
+        return false;
+    };
+    let Some(JsonValue::Object(labels)) = record.get("labels") else {
+        return false;
+    };
+    let Some(JsonValue::Array(values)) = labels.get("values") else {
+        return false;
+    };
+    values.iter().any(|value| match value {
+        JsonValue::Object(value) => {
+            matches!(value.get("val"), Some(JsonValue::String(v)) if v == label)
+        }
+        _ => false,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::{LinkReader, MemStorage};
     use links::{CollectedLink, Link};
 
     #[test]
@@ -263,7 +565,7 @@ mod tests {
         assert_eq!(
             action,
             Some((
-                ActionableEvent::UpdateLinks {
+                ActionableEvent::UpdateProfile {
                     record_id: RecordId {
                         did: "did:plc:tcmiubbjtkwhmnwmrvr2eqnx".into(),
                         collection: "app.bsky.actor.profile".into(),
@@ -276,12 +578,67 @@ mod tests {
                             .into()
                     ),
                 },],
+                    private: false,
                 },
                 1736453696817289
             ))
         )
     }
 
+    #[test]
+    fn test_private_profile() {
+        let rec = r#"{
+            "did":"did:plc:tcmiubbjtkwhmnwmrvr2eqnx",
+            "time_us":1736453696817290,"kind":"commit",
+            "commit":{
+                "rev":"3lfdikw7q772d",
+                "operation":"create",
+                "collection":"app.bsky.actor.profile",
+                "rkey":"self",
+                "record":{
+                    "$type":"app.bsky.actor.profile",
+                    "displayName":"Colin Harvey",
+                    "labels":{"$type":"com.atproto.label.defs#selfLabels","values":[{"val":"!no-unauthenticated"}]}
+                },
+                "cid":"bafyreiem4j5p7duz67negvqarq3s5h7o45fvytevhrzkkn2p6eqdkcf74m"
+            }
+        }"#.parse().unwrap();
+        let record_id = || RecordId {
+            did: "did:plc:tcmiubbjtkwhmnwmrvr2eqnx".into(),
+            collection: "app.bsky.actor.profile".into(),
+            rkey: "self".into(),
+        };
+        assert_eq!(
+            get_actionable(&rec),
+            Some((
+                ActionableEvent::UpdateProfile {
+                    record_id: record_id(),
+                    new_links: vec![],
+                    private: true,
+                },
+                1736453696817290
+            ))
+        );
+
+        // deleting the profile takes the label with it
+        let rec = r#"{
+            "did":"did:plc:tcmiubbjtkwhmnwmrvr2eqnx",
+            "time_us":1736453696817291,"kind":"commit",
+            "commit":{"rev":"3lfdikw7q772e","operation":"delete","collection":"app.bsky.actor.profile","rkey":"self"}
+        }"#.parse().unwrap();
+        assert_eq!(
+            get_actionable(&rec),
+            Some((
+                ActionableEvent::UpdateProfile {
+                    record_id: record_id(),
+                    new_links: vec![],
+                    private: false,
+                },
+                1736453696817291
+            ))
+        );
+    }
+
     #[test]
     fn test_delete_like() {
         let rec = r#"{
@@ -351,4 +708,125 @@ mod tests {
             ))
         )
     }
+
+    #[test]
+    fn test_identity() {
+        let rec = r#"{
+            "did":"did:plc:hdhoaan3xa3jiuq4fg4mefid","time_us":1736451748340553,"kind":"identity","identity":{"did":"did:plc:hdhoaan3xa3jiuq4fg4mefid","handle":"Bad-Example.com","seq":3040941527,"time":"2025-01-09T19:42:27.972Z"}
+        }"#.parse().unwrap();
+        let action = get_actionable(&rec);
+        assert_eq!(
+            action,
+            Some((
+                ActionableEvent::Identity {
+                    did: "did:plc:hdhoaan3xa3jiuq4fg4mefid".into(),
+                    handle: Some("bad-example.com".into()),
+                },
+                1736451748340553
+            ))
+        );
+
+        let rec = r#"{
+            "did":"did:plc:hdhoaan3xa3jiuq4fg4mefid","time_us":1736451748340554,"kind":"identity","identity":{"did":"did:plc:hdhoaan3xa3jiuq4fg4mefid","seq":3040941528,"time":"2025-01-09T19:42:27.973Z"}
+        }"#.parse().unwrap();
+        let action = get_actionable(&rec);
+        assert_eq!(
+            action,
+            Some((
+                ActionableEvent::Identity {
+                    did: "did:plc:hdhoaan3xa3jiuq4fg4mefid".into(),
+                    handle: None,
+                },
+                1736451748340554
+            ))
+        )
+    }
+
+    #[test]
+    fn test_overlap_window() {
+        let sec = 1_000_000;
+        let mut overlap = Overlap::new(Some(100 * sec), Duration::from_secs(10));
+        assert!(overlap.check(95 * sec)); // before the stored cursor
+        assert!(overlap.check(105 * sec)); // inside the window after it
+        assert!(!overlap.check(111 * sec)); // caught up
+        assert!(!overlap.check(120 * sec));
+        // rewound by a reconnect: careful again until past where we were
+        assert!(overlap.check(118 * sec));
+        assert!(overlap.check(125 * sec));
+        assert!(!overlap.check(131 * sec));
+
+        let mut fresh = Overlap::new(None, Duration::from_secs(10));
+        assert!(!fresh.check(sec));
+    }
+
+    #[test]
+    fn test_each_store_purges_excluded_accounts() -> Result<()> {
+        let like = |did: &str| ActionableEvent::CreateLinks {
+            record_id: RecordId {
+                did: did.into(),
+                collection: "app.t.c".into(),
+                rkey: "a".into(),
+            },
+            links: vec![CollectedLink {
+                target: Link::Uri("e.com".into()),
+                path: ".uri".into(),
+            }],
+        };
+        let dir = tempfile::tempdir()?;
+        let quiet = dir.path().join("events.jsonl");
+        std::fs::write(&quiet, "")?;
+
+        // a serving store and a reindex's fresh one, sharing a policy
+        let pipeline = Pipeline::default();
+        let (mut serving, mut fresh) = (MemStorage::new(), MemStorage::new());
+        for store in [&mut serving, &mut fresh] {
+            store.push(&like("did:plc:gone"), 1)?;
+            store.push(&like("did:plc:ok"), 2)?;
+        }
+        pipeline.policy.set("did did:plc:gone".parse()?);
+        for store in [&mut serving, &mut fresh] {
+            consume(
+                store,
+                pipeline.clone(),
+                Some(Replay::new(quiet.clone())),
+                Jetstream::new("wss://j"),
+                DeadLetters::default(),
+                None,
+                CancellationToken::new(),
+            )?;
+            assert_eq!(store.get_count("e.com", "app.t.c", ".uri")?, 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_switching_streams_rewrites_the_overlap_idempotently() -> Result<()> {
+        let sec = 1_000_000;
+        let like = |s: u64| ActionableEvent::CreateLinks {
+            record_id: RecordId {
+                did: "did:plc:asdf".into(),
+                collection: "app.t.c".into(),
+                rkey: s.to_string(),
+            },
+            links: vec![CollectedLink {
+                target: Link::Uri("e.com".into()),
+                path: ".uri".into(),
+            }],
+        };
+        let mut store = MemStorage::new();
+        claim_stream(&mut store, "wss://a", None, true)?;
+        for s in 1..=5 {
+            store.push(&like(s), s * sec)?;
+        }
+
+        // the new instance sends the last three seconds again, past a short idempotent window
+        claim_stream(&mut store, "wss://b", Some(Duration::from_secs(3)), true)?;
+        let mut overlap = Overlap::from_store(&mut store, Duration::from_secs(1))?;
+        for s in 2..=6 {
+            store.set_idempotent(overlap.check(s * sec));
+            store.push(&like(s), s * sec)?;
+        }
+        assert_eq!(store.get_count("e.com", "app.t.c", ".uri")?, 6);
+        Ok(())
+    }
 }
@@ -0,0 +1,184 @@
+use crate::policy::SharedPolicy;
+use metrics::{describe_histogram, histogram, Unit};
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::AtomicU32;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How events get from the source to the writer
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    /// threads pulling links out of events
+    pub workers: usize,
+    /// most events in flight between the source and the writer
+    pub window: usize,
+    /// most events the writer stores together, when enough are waiting
+    pub max_batch: usize,
+    /// write idempotently for this much event time past the newest stored cursor, and again
+    /// after anything rewinds. `None` never does (except for replays).
+    pub idempotent_window: Option<Duration>,
+    /// what to leave out, and whose links to purge
+    pub policy: SharedPolicy,
+    /// events waiting for the writer, for anyone watching
+    pub qsize: Arc<AtomicU32>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self {
+            workers: 2,
+            window: 4096,
+            max_batch: 512,
+            idempotent_window: Some(Duration::from_secs(60)),
+            policy: Default::default(),
+            qsize: Default::default(),
+        }
+    }
+}
+
+/// Run `f` over everything from `input` on `workers` threads, sending the results to `output` in
+/// their original order
+///
+/// At most `window` items are in flight: one slow item holds up everything behind it instead of
+/// letting results pile up, and a slow `output` pushes back all the way to `input`.
+///
+/// The returned handle finishes once `input` is drained and disconnected (or `output` is gone). If
+/// `f` panics, everything stops and the handle's `join` returns the panic.
+pub(super) fn ordered_pool<T, U, F>(
+    stage: &'static str,
+    workers: usize,
+    window: usize,
+    input: flume::Receiver<T>,
+    output: flume::Sender<U>,
+    f: F,
+) -> thread::JoinHandle<()>
+where
+    T: Send + 'static,
+    U: Send + 'static,
+    F: Fn(T) -> U + Send + Sync + 'static,
+{
+    describe_histogram!(
+        "consumer_pipeline_queued",
+        Unit::Count,
+        "items waiting to enter a pipeline stage"
+    );
+    describe_histogram!(
+        "consumer_pipeline_reordering",
+        Unit::Count,
+        "finished items held back by a pipeline stage until earlier ones are done"
+    );
+
+    let workers = workers.max(1);
+    let window = window.max(1);
+    let (work_sender, work) = flume::bounded::<(u64, T)>(workers * 2);
+    let (done_sender, done) = flume::bounded::<(u64, thread::Result<U>)>(window);
+    // one token per item in flight: taken when dispatched, returned when it's sent on
+    let (slot_taken, slot_returned) = flume::bounded::<()>(window);
+
+    let f = Arc::new(f);
+    for _ in 0..workers {
+        let work = work.clone();
+        let done_sender = done_sender.clone();
+        let f = f.clone();
+        thread::spawn(move || {
+            for (seq, item) in work.iter() {
+                // a lost item would hold up everything after it forever, so the panic goes on
+                let result = panic::catch_unwind(AssertUnwindSafe(|| f(item)));
+                if done_sender.send((seq, result)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(done_sender);
+
+    thread::spawn(move || {
+        for (seq, item) in (0..).zip(input.iter()) {
+            histogram!("consumer_pipeline_queued", "stage" => stage).record(input.len() as f64);
+            if slot_taken.send(()).is_err() || work_sender.send((seq, item)).is_err() {
+                break;
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        let mut next = 0;
+        let mut pending = BTreeMap::new();
+        for (seq, result) in done.iter() {
+            let result = match result {
+                Ok(result) => result,
+                Err(payload) => {
+                    eprintln!("pipeline: {stage} worker panicked on item {seq}, shutting down");
+                    panic::resume_unwind(payload);
+                }
+            };
+            pending.insert(seq, result);
+            while let Some(result) = pending.remove(&next) {
+                next += 1;
+                if output.send(result).is_err() {
+                    return;
+                }
+                let _ = slot_returned.recv();
+            }
+            histogram!("consumer_pipeline_reordering", "stage" => stage)
+                .record(pending.len() as f64);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_ordered_pool_keeps_order() {
+        let (input, input_receiver) = flume::unbounded();
+        let (output_sender, output) = flume::unbounded();
+        let pool = ordered_pool("test", 4, 8, input_receiver, output_sender, |n: u64| {
+            // later items finish first
+            thread::sleep(Duration::from_micros(100 * (10 - n % 10)));
+            n * 2
+        });
+        for n in 0..100 {
+            input.send(n).unwrap();
+        }
+        drop(input);
+        pool.join().unwrap();
+        assert_eq!(
+            output.iter().collect::<Vec<_>>(),
+            (0..100).map(|n| n * 2).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_ordered_pool_forwards_panics() {
+        let (input, input_receiver) = flume::unbounded();
+        let (output_sender, output) = flume::unbounded();
+        let pool = ordered_pool("test", 2, 4, input_receiver, output_sender, |n: u64| {
+            assert_ne!(n, 5, "five is right out");
+            n
+        });
+        for n in 0..100 {
+            input.send(n).unwrap();
+        }
+        // the output ends instead of waiting on item 5 forever
+        assert!(output.iter().all(|n| n < 5));
+        assert!(pool.join().is_err());
+    }
+
+    #[test]
+    fn test_ordered_pool_backpressure() {
+        let (input, input_receiver) = flume::bounded(1);
+        let (output_sender, output) = flume::bounded(1);
+        let _pool = ordered_pool("test", 2, 4, input_receiver, output_sender, |n: u64| n);
+        // nobody reads the output: the window fills up and then the input stops moving
+        let sent = (0..100)
+            .take_while(|n| input.send_timeout(*n, Duration::from_millis(50)).is_ok())
+            .count();
+        assert!(sent < 20, "sent {sent} without anything being read");
+        assert_eq!(output.recv().unwrap(), 0);
+    }
+}
@@ -0,0 +1,189 @@
+use metrics::counter;
+use std::hash::{BuildHasher, RandomState};
+use std::time::{Duration, Instant};
+
+/// How jetstream reconnects are paced, and when to stop trying
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// wait before the first retry
+    pub initial_delay: Duration,
+    /// each further retry waits this many times longer...
+    pub multiplier: f64,
+    /// ...up to this
+    pub max_delay: Duration,
+    /// randomize each wait by up to this fraction (either way), so clients don't retry in lockstep
+    pub jitter: f64,
+    /// a connection that has been receiving events for this long resets the retry count
+    pub reset_after: Duration,
+    /// give up after this many retries in a row. `None` retries forever.
+    pub max_retries: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(60),
+            jitter: 0.25,
+            reset_after: Duration::from_secs(30),
+            max_retries: Some(10),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// the (un-jittered) wait before retry number `retry`, starting from 1
+    fn base_delay(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.powi(retry.saturating_sub(1) as i32);
+        Duration::from_secs_f64(
+            (self.initial_delay.as_secs_f64() * factor).min(self.max_delay.as_secs_f64()),
+        )
+    }
+
+    fn jittered(&self, delay: Duration) -> Duration {
+        if self.jitter <= 0.0 {
+            return delay;
+        }
+        // cheap randomness: std's hasher keys are randomly seeded
+        let r = RandomState::new().hash_one(Instant::now()) as f64 / u64::MAX as f64; // [0, 1]
+        delay.mul_f64((1.0 + self.jitter * (2.0 * r - 1.0)).max(0.0))
+    }
+}
+
+/// Retry bookkeeping for one jetstream consumer
+pub(super) struct Reconnects {
+    policy: ReconnectPolicy,
+    retries: u32,
+    connected_at: Option<Instant>,
+    down_since: Option<Instant>,
+}
+
+impl Reconnects {
+    pub(super) fn new(policy: ReconnectPolicy) -> Self {
+        Self {
+            policy,
+            retries: 0,
+            connected_at: None,
+            down_since: Some(Instant::now()),
+        }
+    }
+
+    /// the websocket is up (but might not last)
+    pub(super) fn connected(&mut self) {
+        self.connected_at = Some(Instant::now());
+    }
+
+    /// an event came in. a connection is only trusted once it has stayed up for a while.
+    pub(super) fn received(&mut self) {
+        self.count_downtime();
+        self.down_since = None;
+        if self.retries > 0
+            && self
+                .connected_at
+                .map(|t| t.elapsed() >= self.policy.reset_after)
+                .unwrap_or(false)
+        {
+            self.retries = 0;
+        }
+    }
+
+    /// the connection failed or dropped: how long to wait before the next try, if we should
+    pub(super) fn retry(&mut self) -> Option<Duration> {
+        self.connected_at = None;
+        if self.down_since.is_none() {
+            self.down_since = Some(Instant::now());
+        }
+        self.count_downtime();
+        self.retries += 1;
+        if self
+            .policy
+            .max_retries
+            .map(|max| self.retries > max)
+            .unwrap_or(false)
+        {
+            return None;
+        }
+        Some(self.policy.jittered(self.policy.base_delay(self.retries)))
+    }
+
+    pub(super) fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    fn count_downtime(&mut self) {
+        if let Some(ref mut since) = self.down_since {
+            counter!("jetstream_disconnected_ms").increment(since.elapsed().as_millis() as u64);
+            *since = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_jitter() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(10),
+            jitter: 0.0,
+            reset_after: Duration::from_secs(30),
+            max_retries: Some(6),
+        }
+    }
+
+    #[test]
+    fn test_exponential_backoff_caps_and_gives_up() {
+        let mut r = Reconnects::new(no_jitter());
+        let delays: Vec<_> = (0..7).map(|_| r.retry()).collect();
+        assert_eq!(
+            delays,
+            [1, 2, 4, 8, 10, 10]
+                .into_iter()
+                .map(|s| Some(Duration::from_secs(s)))
+                .chain([None])
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_never_give_up() {
+        let mut r = Reconnects::new(ReconnectPolicy {
+            max_retries: None,
+            ..no_jitter()
+        });
+        for _ in 0..1000 {
+            assert!(r.retry().unwrap() <= Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn test_reset_needs_time_not_just_a_message() {
+        let mut r = Reconnects::new(ReconnectPolicy {
+            reset_after: Duration::from_millis(50),
+            ..no_jitter()
+        });
+        r.retry();
+        r.retry();
+        r.connected();
+        r.received(); // one message from a flapping connection doesn't count
+        assert_eq!(r.retries(), 2);
+        std::thread::sleep(Duration::from_millis(60));
+        r.received();
+        assert_eq!(r.retries(), 0);
+    }
+
+    #[test]
+    fn test_jitter_stays_in_range() {
+        let policy = ReconnectPolicy {
+            jitter: 0.5,
+            ..no_jitter()
+        };
+        for _ in 0..100 {
+            let d = policy.jittered(Duration::from_secs(4));
+            assert!(d >= Duration::from_secs(2) && d <= Duration::from_secs(6));
+        }
+    }
+}
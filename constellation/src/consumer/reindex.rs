@@ -0,0 +1,202 @@
+use super::jetstream::ts_age;
+use super::{consume, purge_excluded, DeadLetters, Jetstream, Pipeline, Replay};
+use crate::storage::{LinkReader, LinkStorage};
+use anyhow::{bail, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::thread;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+const CATCH_UP_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Index archived events into a (fresh) store, then follow jetstream until it's caught up
+///
+/// The archive has to reach back to a point jetstream can still replay from, otherwise there will
+/// be a gap between the end of the archive and the live stream.
+pub fn rebuild(
+    store: &mut impl LinkStorage,
+    pipeline: Pipeline,
+    archive: Replay,
+    jetstream: Jetstream,
+    caught_up: Duration,
+    staying_alive: &CancellationToken,
+) -> Result<()> {
+    println!("reindex: replaying archive from {:?}...", archive.source);
+    consume(
+        store,
+        pipeline.clone(),
+        Some(archive),
+        jetstream.clone(),
+        DeadLetters::default(),
+        None,
+        staying_alive.child_token(),
+    )?;
+    if staying_alive.is_cancelled() {
+        bail!("reindex: cancelled during archive replay");
+    }
+    let Some(cursor) = store.get_cursor()? else {
+        bail!("reindex: nothing was indexed from the archive, refusing to start from the live tip");
+    };
+    println!("reindex: archive replayed up to cursor {cursor}, catching up with jetstream...");
+    catch_up(store, pipeline, jetstream, caught_up, staying_alive)
+}
+
+/// Consume jetstream from the store's own cursor until it's within `caught_up` of now
+///
+/// Everything the policy excludes by then is purged before returning, so the store is ready to be
+/// served from.
+pub fn catch_up(
+    store: &mut impl LinkStorage,
+    pipeline: Pipeline,
+    jetstream: Jetstream,
+    caught_up: Duration,
+    staying_alive: &CancellationToken,
+) -> Result<()> {
+    let reader = store.to_readable();
+    let policy = pipeline.policy.clone();
+    let catching_up = staying_alive.child_token();
+    thread::scope(|s| {
+        let consumer = s.spawn(|| {
+            consume(
+                store,
+                pipeline,
+                None,
+                jetstream,
+                DeadLetters::default(),
+                None,
+                catching_up.clone(),
+            )
+        });
+        while !consumer.is_finished() {
+            thread::sleep(CATCH_UP_CHECK_INTERVAL);
+            match reader.get_last_cursor() {
+                Ok(Some(cursor)) if ts_age(cursor) <= caught_up => {
+                    catching_up.cancel();
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("reindex: failed to check the catch-up cursor: {e:?}"),
+            }
+        }
+        consumer.join().unwrap()
+    })?;
+    if staying_alive.is_cancelled() {
+        bail!("reindex: cancelled while catching up");
+    }
+
+    // the consumer may have stopped before getting through them
+    let purges = policy.purge_queue();
+    if let Some(cursor) = store.get_cursor()? {
+        purge_excluded(store, &purges, cursor);
+    }
+    let failed = purges.take();
+    if !failed.is_empty() {
+        bail!(
+            "reindex: failed to purge {} excluded accounts from the new index",
+            failed.len()
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SourceCountDiff {
+    pub collection: String,
+    pub path: String,
+    pub old: u64,
+    pub new: u64,
+}
+
+/// link counts per (collection, path) that differ between two indexes, sorted by source
+pub fn diff_source_counts(
+    old: &impl LinkReader,
+    new: &impl LinkReader,
+) -> Result<Vec<SourceCountDiff>> {
+    let old = old.get_source_counts()?;
+    let new = new.get_source_counts()?;
+    let count = |counts: &HashMap<String, HashMap<String, u64>>, collection, path| {
+        counts
+            .get(collection)
+            .and_then(|paths| paths.get(path))
+            .copied()
+            .unwrap_or(0)
+    };
+    let sources: BTreeSet<(&String, &String)> = old
+        .iter()
+        .chain(new.iter())
+        .flat_map(|(collection, paths)| paths.keys().map(move |path| (collection, path)))
+        .collect();
+    Ok(sources
+        .into_iter()
+        .filter_map(|(collection, path)| {
+            let (o, n) = (count(&old, collection, path), count(&new, collection, path));
+            (o != n).then(|| SourceCountDiff {
+                collection: collection.clone(),
+                path: path.clone(),
+                old: o,
+                new: n,
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemStorage, SwitchableReader};
+    use crate::{ActionableEvent, RecordId};
+    use links::{CollectedLink, Link};
+
+    fn like(rkey: &str, target: &str) -> ActionableEvent {
+        ActionableEvent::CreateLinks {
+            record_id: RecordId {
+                did: "did:plc:asdf".into(),
+                collection: "app.t.c".into(),
+                rkey: rkey.into(),
+            },
+            links: vec![CollectedLink {
+                target: Link::Uri(target.into()),
+                path: ".subject.uri".into(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_diff_source_counts() -> Result<()> {
+        let mut old = MemStorage::new();
+        old.push(&like("a", "a.com"), 1)?;
+        old.push(&like("b", "b.com"), 2)?;
+        let mut new = MemStorage::new();
+        new.push(&like("a", "a.com"), 1)?;
+
+        assert_eq!(
+            diff_source_counts(&old, &new)?,
+            vec![SourceCountDiff {
+                collection: "app.t.c".into(),
+                path: ".subject.uri".into(),
+                old: 2,
+                new: 1,
+            }]
+        );
+        new.push(&like("b", "b.com"), 2)?;
+        assert_eq!(diff_source_counts(&old, &new)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_switch_reader() -> Result<()> {
+        let mut old = MemStorage::new();
+        old.push(&like("a", "a.com"), 1)?;
+        let mut new = MemStorage::new();
+        new.push(&like("a", "a.com"), 1)?;
+        new.push(&like("b", "a.com"), 2)?;
+
+        let serving = SwitchableReader::new(old.to_readable());
+        let in_flight = serving.clone();
+        assert_eq!(serving.get_count("a.com", "app.t.c", ".subject.uri")?, 1);
+        serving.switch(new.to_readable());
+        assert_eq!(in_flight.get_count("a.com", "app.t.c", ".subject.uri")?, 2);
+        assert_eq!(serving.get_last_cursor()?, Some(2));
+        Ok(())
+    }
+}
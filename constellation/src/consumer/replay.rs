@@ -0,0 +1,300 @@
+use super::archive::{open_jsonl, parse_segment_name};
+use super::jetstream::get_event_time;
+use anyhow::{bail, Result};
+use metrics::{counter, describe_counter, Unit};
+use std::fs;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+use tinyjson::JsonValue;
+use tokio_util::sync::CancellationToken;
+
+/// How fast to replay saved events, relative to their original jetstream timestamps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// as fast as the consumer will take them
+    Max,
+    /// multiple of real time: 1.0 is real-time
+    Times(f64),
+}
+
+impl FromStr for ReplaySpeed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max" => Ok(Self::Max),
+            "realtime" | "real-time" => Ok(Self::Times(1.0)),
+            other => {
+                let n = other.strip_suffix('x').unwrap_or(other);
+                match n.parse::<f64>() {
+                    Ok(n) if n > 0.0 => Ok(Self::Times(n)),
+                    _ => Err(format!(
+                        "expected 'max', 'realtime', or a speed like '10x', got {s:?}"
+                    )),
+                }
+            }
+        }
+    }
+}
+
+impl ReplaySpeed {
+    /// how long after the replay started an event at `ts` should be sent
+    fn due(&self, first_ts: u64, ts: u64) -> Option<Duration> {
+        match self {
+            Self::Max => None,
+            Self::Times(factor) => Some(Duration::from_secs_f64(
+                ts.saturating_sub(first_ts) as f64 / 1_000_000. / factor,
+            )),
+        }
+    }
+}
+
+/// Saved events to consume instead of a live jetstream subscription
+#[derive(Debug, Clone)]
+pub struct Replay {
+    /// a single jsonl file, or a directory of (optionally zstd) jsonl segments
+    pub source: PathBuf,
+    /// skip events before this cursor
+    pub from: Option<u64>,
+    /// skip events after this cursor
+    pub until: Option<u64>,
+    pub speed: ReplaySpeed,
+}
+
+impl Replay {
+    pub fn new(source: PathBuf) -> Self {
+        Self {
+            source,
+            from: None,
+            until: None,
+            speed: ReplaySpeed::Max,
+        }
+    }
+
+    fn in_range(&self, ts: u64) -> bool {
+        self.from.map(|from| ts >= from).unwrap_or(true)
+            && self.until.map(|until| ts <= until).unwrap_or(true)
+    }
+
+    /// the files to read, in order. archive segments outside the cursor range are left out.
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        if !self.source.is_dir() {
+            return Ok(vec![self.source.clone()]);
+        }
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.source)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if !(name.ends_with(".jsonl") || name.ends_with(".jsonl.zst")) {
+                continue;
+            }
+            if let Some((first, last)) = parse_segment_name(name) {
+                let before = self.until.map(|until| first > until).unwrap_or(false);
+                let after = self.from.map(|from| last < from).unwrap_or(false);
+                if before || after {
+                    continue;
+                }
+            }
+            files.push(path);
+        }
+        files.sort(); // archive segment names are zero-padded cursors
+        Ok(files)
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplayStats {
+    pub sent: usize,
+    pub out_of_range: usize,
+    pub malformed: usize,
+}
+
+pub fn consume_replay(
+    replay: Replay,
+    sender: flume::Sender<JsonValue>,
+    staying_alive: CancellationToken,
+) -> Result<ReplayStats> {
+    describe_counter!(
+        "replay_events",
+        Unit::Count,
+        "saved events sent to the consumer"
+    );
+    describe_counter!(
+        "replay_lines_malformed",
+        Unit::Count,
+        "lines in saved event files that could not be used"
+    );
+
+    let mut stats = ReplayStats::default();
+    let mut started: Option<(Instant, u64)> = None;
+
+    for file in replay.files()? {
+        println!("replay: reading {file:?}...");
+        if read_file(
+            &file,
+            &replay,
+            &sender,
+            &staying_alive,
+            &mut started,
+            &mut stats,
+        )? {
+            break;
+        }
+    }
+    println!("replay: finished: {stats:?}");
+    Ok(stats)
+}
+
+/// returns true if the replay should stop
+fn read_file(
+    file: &Path,
+    replay: &Replay,
+    sender: &flume::Sender<JsonValue>,
+    staying_alive: &CancellationToken,
+    started: &mut Option<(Instant, u64)>,
+    stats: &mut ReplayStats,
+) -> Result<bool> {
+    for line in open_jsonl(file)?.lines() {
+        if staying_alive.is_cancelled() {
+            println!("replay: cancelling");
+            return Ok(true);
+        }
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => {
+                // most likely the truncated tail of a segment that was being written in a crash
+                eprintln!("replay: could not read more from {file:?}, moving on: {e:?}");
+                counter!("replay_lines_malformed", "reason" => "read error").increment(1);
+                stats.malformed += 1;
+                return Ok(false);
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Ok(event) = line.parse::<JsonValue>() else {
+            counter!("replay_lines_malformed", "reason" => "json parse").increment(1);
+            stats.malformed += 1;
+            continue;
+        };
+        let Some(ts) = get_event_time(&event) else {
+            counter!("replay_lines_malformed", "reason" => "no timestamp").increment(1);
+            stats.malformed += 1;
+            continue;
+        };
+        if !replay.in_range(ts) {
+            stats.out_of_range += 1;
+            continue;
+        }
+
+        let (t0, first_ts) = *started.get_or_insert((Instant::now(), ts));
+        if let Some(due) = replay.speed.due(first_ts, ts) {
+            if let Some(wait) = due.checked_sub(t0.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+
+        if let Err(flume::SendError(_rejected)) = sender.send(event) {
+            if sender.is_disconnected() {
+                bail!("replay: send channel disconnected -- nothing to do, bye.");
+            }
+            eprintln!("replay: failed to send on channel, dropping update! (FIXME / HANDLEME)");
+        }
+        counter!("replay_events").increment(1);
+        stats.sent += 1;
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn event(ts: u64) -> String {
+        format!(r#"{{"did":"did:plc:asdf","time_us":{ts},"kind":"identity"}}"#)
+    }
+
+    #[test]
+    fn test_parse_speed() {
+        assert_eq!("max".parse(), Ok(ReplaySpeed::Max));
+        assert_eq!("realtime".parse(), Ok(ReplaySpeed::Times(1.0)));
+        assert_eq!("10x".parse(), Ok(ReplaySpeed::Times(10.0)));
+        assert_eq!("0.5x".parse(), Ok(ReplaySpeed::Times(0.5)));
+        assert!("0x".parse::<ReplaySpeed>().is_err());
+        assert!("fast".parse::<ReplaySpeed>().is_err());
+    }
+
+    #[test]
+    fn test_speed_due() {
+        assert_eq!(ReplaySpeed::Max.due(0, 5_000_000), None);
+        assert_eq!(
+            ReplaySpeed::Times(1.0).due(1_000_000, 3_000_000),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            ReplaySpeed::Times(4.0).due(1_000_000, 3_000_000),
+            Some(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn test_replay_dir_with_range() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        {
+            let mut segment = zstd::stream::write::Encoder::new(
+                fs::File::create(dir.path().join(format!("{:016}-{:016}.jsonl.zst", 1, 3)))?,
+                3,
+            )?;
+            for ts in 1..=3 {
+                writeln!(segment, "{}", event(ts))?;
+            }
+            segment.finish()?;
+
+            let mut plain = fs::File::create(dir.path().join("later.jsonl"))?;
+            writeln!(plain, "{}", event(4))?;
+            writeln!(plain, "{{not json")?;
+            writeln!(plain, r#"{{"kind":"no time"}}"#)?;
+            writeln!(plain, "{}", event(5))?;
+            writeln!(plain, "{}", event(6))?;
+
+            // entirely out of range: should not even be opened
+            fs::write(
+                dir.path()
+                    .join(format!("{:016}-{:016}.jsonl.zst", 100, 200)),
+                "not even zstd",
+            )?;
+        }
+
+        let (sender, receiver) = flume::unbounded();
+        let stats = consume_replay(
+            Replay {
+                source: dir.path().to_path_buf(),
+                from: Some(2),
+                until: Some(5),
+                speed: ReplaySpeed::Max,
+            },
+            sender,
+            CancellationToken::new(),
+        )?;
+        assert_eq!(
+            stats,
+            ReplayStats {
+                sent: 4,
+                out_of_range: 2,
+                malformed: 2,
+            }
+        );
+        let got: Vec<_> = receiver
+            .drain()
+            .filter_map(|e| get_event_time(&e))
+            .collect();
+        assert_eq!(got, vec![2, 3, 4, 5]);
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+use crate::storage::LinkStorage;
+use anyhow::Result;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where to (re-)start consuming jetstream from, instead of the stored cursor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StartFrom {
+    /// an explicit jetstream cursor (unix microseconds)
+    Cursor(u64),
+    /// a point in time (unix microseconds), from an RFC 3339 timestamp
+    Time(u64),
+    /// this long before now
+    Ago(Duration),
+}
+
+impl FromStr for StartFrom {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(cursor) = s.parse::<u64>() {
+            return Ok(Self::Cursor(cursor));
+        }
+        if let Some(ago) = parse_duration(s) {
+            return Ok(Self::Ago(ago));
+        }
+        if let Some(us) = parse_rfc3339_us(s) {
+            return Ok(Self::Time(us));
+        }
+        Err(format!(
+            "expected a cursor like '1739000000000000', a duration like '2h' or '90m', or a timestamp like '2025-02-08T07:33:20Z', got {s:?}"
+        ))
+    }
+}
+
+impl StartFrom {
+    /// the jetstream cursor this start point refers to, as of `now`
+    pub fn cursor(&self, now: SystemTime) -> u64 {
+        match self {
+            Self::Cursor(c) | Self::Time(c) => *c,
+            Self::Ago(ago) => now
+                .checked_sub(*ago)
+                .unwrap_or(UNIX_EPOCH)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64,
+        }
+    }
+}
+
+/// Replace the store's cursor, so the consumer picks up from `start`
+///
+/// Going back behind the stored cursor means events between the two get pushed again, which
+/// double-counts their links unless the consumer writes them `idempotent`ly. In that case the old
+/// cursor is kept as the store's seen-until, so it still counts after a restart.
+pub fn start_from(
+    store: &mut impl LinkStorage,
+    url: &str,
+    start: StartFrom,
+    idempotent: bool,
+) -> Result<()> {
+    let cursor = start.cursor(SystemTime::now());
+    let stored = store.get_cursor()?;
+    match stored {
+        Some(stored) if cursor < stored && idempotent => {
+            println!(
+                "starting from cursor {cursor}, {:?} behind the stored cursor {stored}. events in between will be checked against what's stored (slower) until caught up.",
+                Duration::from_micros(stored - cursor)
+            );
+            return store.rewind_stream(url, cursor);
+        }
+        Some(stored) if cursor < stored => eprintln!(
+            "WARNING: starting from cursor {cursor}, {:?} behind the stored cursor {stored}. links from events in between will be counted twice.",
+            Duration::from_micros(stored - cursor)
+        ),
+        Some(stored) if cursor > stored => eprintln!(
+            "starting from cursor {cursor}, skipping {:?} ahead of the stored cursor {stored}: events in between won't be indexed.",
+            Duration::from_micros(cursor - stored)
+        ),
+        _ => println!("starting from cursor {cursor} (stored: {stored:?})"),
+    }
+    store.set_stream(url, Some(cursor))
+}
+
+/// '30s', '90m', '2h', '7d'
+fn parse_duration(s: &str) -> Option<Duration> {
+    let unit = match s.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let n: u64 = s[..s.len() - 1].parse().ok()?;
+    Some(Duration::from_secs(n.checked_mul(unit)?))
+}
+
+/// 'YYYY-MM-DDTHH:MM:SS[.fraction](Z|±HH:MM)' to unix microseconds
+fn parse_rfc3339_us(s: &str) -> Option<u64> {
+    let num = |part: &str| -> Option<i64> {
+        part.bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| part.parse().ok())?
+    };
+    let (date, time) = s.split_once(['T', 't', ' '])?;
+    let mut ymd = date.splitn(3, '-');
+    let (y, m, d) = (num(ymd.next()?)?, num(ymd.next()?)?, num(ymd.next()?)?);
+
+    let (time, offset_s) = if let Some(t) = time.strip_suffix(['Z', 'z']) {
+        (t, 0)
+    } else {
+        let at = time.rfind(['+', '-'])?;
+        let (t, off) = time.split_at(at);
+        let sign = if off.starts_with('-') { -1 } else { 1 };
+        let (oh, om) = off[1..].split_once(':')?;
+        (t, sign * (num(oh)? * 3600 + num(om)? * 60))
+    };
+    let (hms, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut hms = hms.splitn(3, ':');
+    let (hh, mm, ss) = (num(hms.next()?)?, num(hms.next()?)?, num(hms.next()?)?);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) || hh > 23 || mm > 59 || ss > 60 {
+        return None;
+    }
+    let us = if fraction.is_empty() {
+        0
+    } else {
+        let digits: String = fraction.chars().chain("000000".chars()).take(6).collect();
+        num(&digits)?
+    };
+
+    let secs = days_from_civil(y, m, d) * 86_400 + hh * 3600 + mm * 60 + ss - offset_s;
+    u64::try_from(secs * 1_000_000 + us).ok()
+}
+
+/// days since 1970-01-01 for a proleptic gregorian date (howard hinnant's algorithm)
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemStorage;
+
+    #[test]
+    fn test_parse_start_from() {
+        assert_eq!(
+            "1739000000000000".parse(),
+            Ok(StartFrom::Cursor(1739000000000000))
+        );
+        assert_eq!("2h".parse(), Ok(StartFrom::Ago(Duration::from_secs(7200))));
+        assert_eq!("90m".parse(), Ok(StartFrom::Ago(Duration::from_secs(5400))));
+        assert_eq!(
+            "2025-02-08T07:33:20Z".parse(),
+            Ok(StartFrom::Time(1739000000000000))
+        );
+        assert_eq!(
+            "2025-02-08T08:33:20.5+01:00".parse(),
+            Ok(StartFrom::Time(1739000000500000))
+        );
+        assert_eq!("1970-01-01T00:00:00Z".parse(), Ok(StartFrom::Time(0)));
+        assert!("2025-13-08T07:33:20Z".parse::<StartFrom>().is_err());
+        assert!("2025-02-08T07:33:20".parse::<StartFrom>().is_err());
+        assert!("yesterday".parse::<StartFrom>().is_err());
+    }
+
+    #[test]
+    fn test_start_from_overrides_stored_cursor() -> Result<()> {
+        let mut store = MemStorage::new();
+        store.set_stream("wss://j", Some(5_000_000))?;
+        start_from(&mut store, "wss://j", StartFrom::Cursor(3_000_000), true)?;
+        assert_eq!(store.get_cursor()?, Some(3_000_000));
+        assert_eq!(store.get_seen_until()?, Some(5_000_000));
+
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+        assert_eq!(
+            StartFrom::Ago(Duration::from_secs(60)).cursor(now),
+            40_000_000
+        );
+        assert_eq!(StartFrom::Ago(Duration::from_secs(600)).cursor(now), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// When to give up on a jetstream connection that's still technically open
+#[derive(Debug, Clone)]
+pub struct WatchdogPolicy {
+    /// reconnect if no event has arrived for this long
+    pub stall_after: Duration,
+    /// reconnect if the cursor is older than this, and still getting older
+    pub max_lag: Option<Duration>,
+    /// how often the cursor age trend is sampled for `max_lag`
+    pub lag_window: Duration,
+}
+
+impl Default for WatchdogPolicy {
+    fn default() -> Self {
+        Self {
+            stall_after: Duration::from_secs(30),
+            max_lag: Some(Duration::from_secs(5 * 60)),
+            lag_window: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamState {
+    Connecting,
+    Live,
+    Stalled,
+    Lagging,
+    Disconnected,
+}
+
+impl fmt::Display for StreamState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            StreamState::Connecting => "connecting",
+            StreamState::Live => "live",
+            StreamState::Stalled => "stalled",
+            StreamState::Lagging => "lagging",
+            StreamState::Disconnected => "disconnected",
+        })
+    }
+}
+
+/// Point-in-time view of the jetstream consumer, for status pages
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamHealth {
+    pub state: StreamState,
+    pub cursor: Option<u64>,
+    pub since_last_event_ms: Option<u64>,
+    pub forced_reconnects: u64,
+}
+
+#[derive(Debug)]
+struct StatusInner {
+    state: StreamState,
+    cursor: Option<u64>,
+    last_event: Option<Instant>,
+    forced_reconnects: u64,
+}
+
+/// Shared handle to the consumer's view of its stream
+#[derive(Debug, Clone)]
+pub struct StreamStatus(Arc<Mutex<StatusInner>>);
+
+impl Default for StreamStatus {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(StatusInner {
+            state: StreamState::Disconnected,
+            cursor: None,
+            last_event: None,
+            forced_reconnects: 0,
+        })))
+    }
+}
+
+impl StreamStatus {
+    pub fn health(&self) -> StreamHealth {
+        let inner = self.0.lock().unwrap();
+        StreamHealth {
+            state: inner.state,
+            cursor: inner.cursor,
+            since_last_event_ms: inner.last_event.map(|t| t.elapsed().as_millis() as u64),
+            forced_reconnects: inner.forced_reconnects,
+        }
+    }
+
+    pub(super) fn set_state(&self, state: StreamState) {
+        self.0.lock().unwrap().state = state;
+    }
+
+    /// connection lost: keep showing why if the watchdog dropped it
+    pub(super) fn disconnected(&self) {
+        let mut inner = self.0.lock().unwrap();
+        if matches!(inner.state, StreamState::Connecting | StreamState::Live) {
+            inner.state = StreamState::Disconnected;
+        }
+    }
+
+    pub(super) fn event(&self, cursor: u64) {
+        let mut inner = self.0.lock().unwrap();
+        inner.state = StreamState::Live;
+        inner.cursor = Some(cursor);
+        inner.last_event = Some(Instant::now());
+    }
+
+    fn forced_reconnect(&self, state: StreamState) {
+        let mut inner = self.0.lock().unwrap();
+        inner.state = state;
+        inner.forced_reconnects += 1;
+    }
+}
+
+/// Per-connection stall and lag tracking
+pub(super) struct Watchdog {
+    policy: WatchdogPolicy,
+    last_event: Instant,
+    lag_mark: Option<(Instant, Duration)>,
+}
+
+impl Watchdog {
+    pub(super) fn new(policy: WatchdogPolicy) -> Self {
+        Self {
+            policy,
+            last_event: Instant::now(),
+            lag_mark: None,
+        }
+    }
+
+    /// start fresh for a new connection
+    pub(super) fn reset(&mut self) {
+        self.last_event = Instant::now();
+        self.lag_mark = None;
+    }
+
+    /// a message arrived. `cursor_age` is for the latest event we know the time of.
+    pub(super) fn event(&mut self, cursor_age: Option<Duration>) {
+        self.last_event = Instant::now();
+        let Some(cursor_age) = cursor_age else {
+            return;
+        };
+        match self.lag_mark {
+            None => self.lag_mark = Some((self.last_event, cursor_age)),
+            Some((t, _)) if t.elapsed() >= self.policy.lag_window => {
+                self.lag_mark = Some((self.last_event, cursor_age))
+            }
+            Some(_) => {}
+        }
+    }
+
+    /// whether this connection should be dropped, updating the status if so
+    ///
+    /// falling behind because our own queue is backed up isn't the stream's fault, so `backed_up`
+    /// skips the lag check.
+    pub(super) fn check(
+        &self,
+        cursor_age: Option<Duration>,
+        backed_up: bool,
+        status: &StreamStatus,
+    ) -> bool {
+        if self.last_event.elapsed() >= self.policy.stall_after {
+            eprintln!(
+                "jetstream watchdog: no events for {:?}, forcing a reconnect",
+                self.last_event.elapsed()
+            );
+            counter!("jetstream_watchdog_reconnects", "reason" => "stalled").increment(1);
+            status.forced_reconnect(StreamState::Stalled);
+            return true;
+        }
+        let (Some(max_lag), Some(age), Some((marked_at, marked_age))) =
+            (self.policy.max_lag, cursor_age, self.lag_mark)
+        else {
+            return false;
+        };
+        // only judge the trend once a full window has passed
+        if !backed_up
+            && age > max_lag
+            && age > marked_age
+            && marked_at.elapsed() >= self.policy.lag_window
+        {
+            eprintln!(
+                "jetstream watchdog: cursor is {age:?} behind and falling further back (was {marked_age:?}), forcing a reconnect"
+            );
+            counter!("jetstream_watchdog_reconnects", "reason" => "lagging").increment(1);
+            status.forced_reconnect(StreamState::Lagging);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stall() {
+        let status = StreamStatus::default();
+        let mut dog = Watchdog::new(WatchdogPolicy {
+            stall_after: Duration::from_millis(20),
+            ..Default::default()
+        });
+        dog.event(Some(Duration::ZERO));
+        assert!(!dog.check(None, false, &status));
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(dog.check(None, false, &status));
+        assert_eq!(status.health().state, StreamState::Stalled);
+        assert_eq!(status.health().forced_reconnects, 1);
+    }
+
+    #[test]
+    fn test_lag_needs_a_worsening_trend() {
+        let status = StreamStatus::default();
+        let mut dog = Watchdog::new(WatchdogPolicy {
+            stall_after: Duration::from_secs(60),
+            max_lag: Some(Duration::from_secs(10)),
+            lag_window: Duration::from_millis(20),
+        });
+        let behind = Duration::from_secs(100);
+        dog.event(Some(behind));
+        std::thread::sleep(Duration::from_millis(25));
+        // catching up: way behind, but getting closer
+        assert!(!dog.check(Some(behind / 2), false, &status));
+        // falling further behind, but it's our own queue's fault
+        assert!(!dog.check(Some(behind * 2), true, &status));
+        assert!(dog.check(Some(behind * 2), false, &status));
+        assert_eq!(status.health().state, StreamState::Lagging);
+    }
+}
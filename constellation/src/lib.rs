@@ -1,4 +1,5 @@
 pub mod consumer;
+pub mod policy;
 pub mod server;
 pub mod storage;
 
@@ -20,6 +21,18 @@ pub enum ActionableEvent {
     ActivateAccount(Did),
     DeactivateAccount(Did),
     DeleteAccount(Did),
+    /// the handle a DID claims changed (or was re-announced). `None`: no valid handle.
+    Identity {
+        did: Did,
+        handle: Option<String>,
+    },
+    /// the account's profile record changed. its links replace any old ones, and `private` is
+    /// whether it asks to be hidden from logged-out viewers. deletes come through with neither.
+    UpdateProfile {
+        record_id: RecordId,
+        new_links: Vec<CollectedLink>,
+        private: bool,
+    },
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
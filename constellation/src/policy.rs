@@ -0,0 +1,396 @@
+//! Operator exclusions: accounts, collections and targets that shouldn't be indexed or served
+//!
+//! The policy file is plain text, one rule per line:
+//!
+//! ```text
+//! # opted out 2025-02-10
+//! did did:plc:abc123
+//! collection app.example.private
+//! target https://example.com/private/*
+//! ```
+//!
+//! `target` patterns match exactly, or by prefix when they end with `*`.
+
+use crate::{ActionableEvent, Did};
+use anyhow::{anyhow, bail, Context, Result};
+use metrics::{counter, describe_counter, Unit};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Policy {
+    dids: HashSet<Did>,
+    collections: HashSet<String>,
+    targets: Vec<TargetPattern>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TargetPattern {
+    Exact(String),
+    Prefix(String),
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut policy = Self::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((kind, value)) = line.split_once(char::is_whitespace) else {
+                bail!("line {}: expected '<did|collection|target> <value>'", i + 1);
+            };
+            let value = value.trim();
+            match kind {
+                "did" if value.starts_with("did:") => {
+                    policy.dids.insert(value.into());
+                }
+                "did" => bail!("line {}: {value:?} is not a DID", i + 1),
+                "collection" => {
+                    policy.collections.insert(value.to_string());
+                }
+                "target" => policy.targets.push(match value.strip_suffix('*') {
+                    Some(prefix) => TargetPattern::Prefix(prefix.to_string()),
+                    None => TargetPattern::Exact(value.to_string()),
+                }),
+                _ => bail!("line {}: unknown rule {kind:?}", i + 1),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+impl Policy {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        std::fs::read_to_string(path)
+            .with_context(|| format!("reading policy file {path:?}"))?
+            .parse()
+            .with_context(|| format!("parsing policy file {path:?}"))
+    }
+
+    pub fn excludes_did(&self, did: &Did) -> bool {
+        self.dids.contains(did)
+    }
+
+    pub fn excludes_collection(&self, collection: &str) -> bool {
+        self.collections.contains(collection)
+    }
+
+    pub fn excludes_target(&self, target: &str) -> bool {
+        self.targets.iter().any(|pattern| match pattern {
+            TargetPattern::Exact(t) => target == t,
+            TargetPattern::Prefix(p) => target.starts_with(p),
+        })
+    }
+
+    /// the event without anything excluded, or `None` if there's nothing left to do
+    ///
+    /// deletions always go through: removing things is never a problem.
+    pub fn filter(&self, event: ActionableEvent) -> Option<ActionableEvent> {
+        match event {
+            ActionableEvent::CreateLinks {
+                ref record_id,
+                links: _,
+            }
+            | ActionableEvent::UpdateLinks {
+                ref record_id,
+                new_links: _,
+            }
+            | ActionableEvent::UpdateProfile { ref record_id, .. }
+                if self.excludes_did(&record_id.did)
+                    || self.excludes_collection(&record_id.collection) =>
+            {
+                None
+            }
+            ActionableEvent::CreateLinks {
+                record_id,
+                mut links,
+            } => {
+                links.retain(|link| !self.excludes_target(link.target.as_str()));
+                (!links.is_empty()).then_some(ActionableEvent::CreateLinks { record_id, links })
+            }
+            ActionableEvent::UpdateLinks {
+                record_id,
+                mut new_links,
+            } => {
+                // still drops the old links, even if none of the new ones are left
+                new_links.retain(|link| !self.excludes_target(link.target.as_str()));
+                Some(ActionableEvent::UpdateLinks {
+                    record_id,
+                    new_links,
+                })
+            }
+            ActionableEvent::UpdateProfile {
+                record_id,
+                mut new_links,
+                private,
+            } => {
+                new_links.retain(|link| !self.excludes_target(link.target.as_str()));
+                Some(ActionableEvent::UpdateProfile {
+                    record_id,
+                    new_links,
+                    private,
+                })
+            }
+            ActionableEvent::Identity { ref did, .. } if self.excludes_did(did) => None,
+            event => Some(event),
+        }
+    }
+}
+
+/// Counts from a (re)load, for logs and the admin endpoint
+#[derive(Debug, Serialize)]
+pub struct PolicySummary {
+    pub dids: usize,
+    pub collections: usize,
+    pub targets: usize,
+    /// excluded DIDs that weren't before, queued for purging
+    pub newly_excluded_dids: usize,
+}
+
+/// The current policy, swappable while the consumer and server are running
+///
+/// Newly excluded DIDs queue up for each consumer to purge their already-indexed links from its
+/// own store (see `purge_queue`).
+#[derive(Debug, Clone, Default)]
+pub struct SharedPolicy(Arc<SharedPolicyInner>);
+
+#[derive(Debug, Default)]
+struct SharedPolicyInner {
+    path: Option<PathBuf>,
+    current: RwLock<Arc<Policy>>,
+    purge_queues: Mutex<Vec<Weak<Mutex<Vec<Did>>>>>,
+}
+
+/// Excluded DIDs whose existing data still needs to be removed from one store
+///
+/// Stores purge independently: a reindex's fresh store has to catch every exclusion too, not
+/// just the ones the serving store's consumer hasn't gotten to yet.
+#[derive(Debug)]
+pub struct PurgeQueue(Arc<Mutex<Vec<Did>>>);
+
+impl PurgeQueue {
+    /// take everything queued. put back any that couldn't be removed with `retry`, and count the
+    /// rest with `purged`.
+    pub fn take(&self) -> Vec<Did> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+
+    /// queue DIDs from `take` again, since removing their data failed
+    pub fn retry(&self, dids: Vec<Did>) {
+        self.0.lock().unwrap().extend(dids);
+    }
+
+    /// note that a DID from `take` is all gone
+    pub fn purged(&self) {
+        counter!("policy_purges").increment(1);
+    }
+}
+
+impl SharedPolicy {
+    /// read the policy file, to be reloaded from the same path later
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        describe_counter!(
+            "policy_purges",
+            Unit::Count,
+            "accounts purged after being excluded by the policy"
+        );
+        let shared = Self(Arc::new(SharedPolicyInner {
+            path: Some(path.into()),
+            ..Default::default()
+        }));
+        let summary = shared.reload()?;
+        println!(
+            "policy: excluding {} dids, {} collections and {} target patterns",
+            summary.dids, summary.collections, summary.targets
+        );
+        Ok(shared)
+    }
+
+    /// whether there's a file to reload from
+    pub fn reloadable(&self) -> bool {
+        self.0.path.is_some()
+    }
+
+    pub fn current(&self) -> Arc<Policy> {
+        self.0.current.read().unwrap().clone()
+    }
+
+    /// re-read the policy file. if it's broken, the current policy is kept.
+    pub fn reload(&self) -> Result<PolicySummary> {
+        let path = self
+            .0
+            .path
+            .as_ref()
+            .ok_or_else(|| anyhow!("no policy file to reload"))?;
+        Ok(self.set(Policy::load(path)?))
+    }
+
+    /// switch to `policy`, queueing purges for DIDs it newly excludes
+    pub fn set(&self, policy: Policy) -> PolicySummary {
+        let mut current = self.0.current.write().unwrap();
+        let new_dids: Vec<Did> = policy.dids.difference(&current.dids).cloned().collect();
+        let summary = PolicySummary {
+            dids: policy.dids.len(),
+            collections: policy.collections.len(),
+            targets: policy.targets.len(),
+            newly_excluded_dids: new_dids.len(),
+        };
+        self.0.purge_queues.lock().unwrap().retain(|queue| {
+            let Some(queue) = queue.upgrade() else {
+                return false; // its consumer is done
+            };
+            queue.lock().unwrap().extend(new_dids.iter().cloned());
+            true
+        });
+        *current = Arc::new(policy);
+        summary
+    }
+
+    /// a purge queue for one store, starting with every DID excluded so far
+    ///
+    /// the store may have data from any of them: it might have been written while we were down,
+    /// or before they were excluded. DIDs the policy newly excludes get queued for as long as the
+    /// queue is around.
+    pub fn purge_queue(&self) -> PurgeQueue {
+        let current = self.0.current.read().unwrap(); // so `set` can't slip in between
+        let queue = Arc::new(Mutex::new(current.dids.iter().cloned().collect()));
+        self.0
+            .purge_queues
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&queue));
+        PurgeQueue(queue)
+    }
+}
+
+/// reload the policy whenever the process gets a SIGHUP
+#[cfg(unix)]
+pub async fn reload_on_sighup(
+    policy: SharedPolicy,
+    stay_alive: tokio_util::sync::CancellationToken,
+) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            _ = stay_alive.cancelled() => return Ok(()),
+            _ = hangups.recv() => match policy.reload() {
+                Ok(summary) => println!("policy: reloaded on SIGHUP: {summary:?}"),
+                Err(e) => eprintln!("policy: failed to reload on SIGHUP, keeping the old one: {e:?}"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecordId;
+    use links::{CollectedLink, Link};
+
+    fn create(did: &str, collection: &str, targets: &[&str]) -> ActionableEvent {
+        ActionableEvent::CreateLinks {
+            record_id: RecordId {
+                did: did.into(),
+                collection: collection.into(),
+                rkey: "a".into(),
+            },
+            links: targets
+                .iter()
+                .map(|t| CollectedLink {
+                    target: Link::Uri(t.to_string()),
+                    path: ".uri".into(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_policy_filters_events() -> Result<()> {
+        let policy: Policy = "
+            # comment
+            did did:plc:gone
+            collection app.t.secret
+            target https://hidden.com/*
+            target at://did:plc:x/app.t.c/a
+        "
+        .parse()?;
+
+        assert_eq!(
+            policy.filter(create("did:plc:gone", "app.t.c", &["a.com"])),
+            None
+        );
+        assert_eq!(
+            policy.filter(create("did:plc:ok", "app.t.secret", &["a.com"])),
+            None
+        );
+        assert_eq!(
+            policy.filter(create(
+                "did:plc:ok",
+                "app.t.c",
+                &["https://hidden.com/x", "a.com"]
+            )),
+            Some(create("did:plc:ok", "app.t.c", &["a.com"]))
+        );
+        assert_eq!(
+            policy.filter(create(
+                "did:plc:ok",
+                "app.t.c",
+                &["at://did:plc:x/app.t.c/a"]
+            )),
+            None
+        );
+        assert!(!policy.excludes_target("at://did:plc:x/app.t.c/ab"));
+        let delete = ActionableEvent::DeleteAccount("did:plc:gone".into());
+        assert_eq!(
+            policy.filter(delete),
+            Some(ActionableEvent::DeleteAccount("did:plc:gone".into()))
+        );
+
+        assert!("did plc:nope".parse::<Policy>().is_err());
+        assert!("block did:plc:a".parse::<Policy>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_newly_excluded_dids_get_purged() -> Result<()> {
+        let (a, b) = (Did::from("did:plc:a"), Did::from("did:plc:b"));
+        let shared = SharedPolicy::default();
+        shared.set("did did:plc:a".parse()?);
+        let serving = shared.purge_queue();
+        assert_eq!(serving.take(), vec![a.clone()]);
+
+        // a second store, like a reindex: it gets everything, whatever the first has done
+        let rebuilding = shared.purge_queue();
+        let summary = shared.set("did did:plc:a\ndid did:plc:b".parse()?);
+        assert_eq!(summary.newly_excluded_dids, 1);
+        assert_eq!(serving.take(), vec![b.clone()]);
+        assert!(serving.take().is_empty());
+        assert_eq!(rebuilding.take(), vec![a.clone(), b.clone()]);
+        assert_eq!(
+            shared
+                .purge_queue()
+                .take()
+                .into_iter()
+                .collect::<HashSet<_>>(),
+            HashSet::from([a, b.clone()])
+        );
+
+        serving.retry(vec![b.clone()]);
+        assert_eq!(serving.take(), vec![b]);
+        drop(rebuilding);
+        assert_eq!(shared.0.purge_queues.lock().unwrap().len(), 3);
+        shared.set(Policy::default());
+        assert_eq!(shared.0.purge_queues.lock().unwrap().len(), 1); // dropped ones go
+        assert!(shared.reload().is_err()); // no file
+        Ok(())
+    }
+}
@@ -13,15 +13,21 @@ const AVAILABLE: &[MediaType] = &[APPLICATION_JSON, TEXT_HTML];
 
 pub type ExtractAccept = Option<TypedHeader<Accept>>;
 
+/// whether the response will be rendered as html, for extras only shown to browsers
+pub fn wants_html(accept: &ExtractAccept) -> bool {
+    accept
+        .as_ref()
+        .map(|accepting| accepting.negotiate(AVAILABLE) == Some(&TEXT_HTML))
+        .unwrap_or(false)
+}
+
 pub fn acceptable<T: Serialize + Template>(accept: ExtractAccept, thing: T) -> Response {
-    if let Some(accepting) = accept {
-        if accepting.negotiate(AVAILABLE) == Some(&TEXT_HTML) {
-            match thing.render() {
-                Ok(content) => return Html(content).into_response(),
-                Err(e) => {
-                    eprintln!("template rendering failed: {e:?}");
-                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-                }
+    if wants_html(&accept) {
+        match thing.render() {
+            Ok(content) => return Html(content).into_response(),
+            Err(e) => {
+                eprintln!("template rendering failed: {e:?}");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
             }
         }
     }
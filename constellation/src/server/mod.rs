@@ -4,8 +4,8 @@ use axum::{
     http::{self, header},
     middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::get,
-    Router,
+    routing::{get, post},
+    Json, Router,
 };
 use axum_metrics::{ExtraMetricLabels, MetricLayer};
 use bincode::Options;
@@ -17,31 +17,46 @@ use tokio::net::{TcpListener, ToSocketAddrs};
 use tokio::task::block_in_place;
 use tokio_util::sync::CancellationToken;
 
+use crate::consumer::{StreamHealth, StreamStatus};
+use crate::policy::SharedPolicy;
 use crate::storage::{LinkReader, StorageStats};
 use crate::{CountsByCount, Did, RecordId};
 
 mod acceptable;
 mod filters;
 
-use acceptable::{acceptable, ExtractAccept};
+use acceptable::{acceptable, wants_html, ExtractAccept};
 
 const DEFAULT_CURSOR_LIMIT: u64 = 16;
 const DEFAULT_CURSOR_LIMIT_MAX: u64 = 100;
 
 const INDEX_BEGAN_AT_TS: u64 = 1738083600; // TODO: not this
 
-pub async fn serve<S, A>(store: S, addr: A, stay_alive: CancellationToken) -> anyhow::Result<()>
+/// Operator-only endpoints, behind a bearer token
+#[derive(Debug, Clone)]
+pub struct Admin {
+    pub token: String,
+    pub policy: SharedPolicy,
+}
+
+pub async fn serve<S, A>(
+    store: S,
+    stream: StreamStatus,
+    admin: Option<Admin>,
+    addr: A,
+    stay_alive: CancellationToken,
+) -> anyhow::Result<()>
 where
     S: LinkReader,
     A: ToSocketAddrs,
 {
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/robots.txt", get(robots))
         .route(
             "/",
             get({
                 let store = store.clone();
-                move |accept| async { block_in_place(|| hello(accept, store)) }
+                move |accept| async { block_in_place(|| hello(accept, store, stream)) }
             }),
         )
         .route(
@@ -94,7 +109,14 @@ where
                     block_in_place(|| explore_links(accept, query, store))
                 }
             }),
-        )
+        );
+    if let Some(admin) = admin {
+        app = app.route(
+            "/admin/reload-policy",
+            post(move |headers| async { block_in_place(|| reload_policy(headers, admin)) }),
+        );
+    }
+    let app = app
         .layer(tower_http::cors::CorsLayer::new().allow_origin(tower_http::cors::Any))
         .layer(middleware::from_fn(add_lables))
         .layer(MetricLayer::default());
@@ -138,6 +160,25 @@ async fn add_lables(request: Request, next: Next) -> Response {
     res
 }
 
+fn reload_policy(
+    headers: http::HeaderMap,
+    admin: Admin,
+) -> Result<impl IntoResponse, (http::StatusCode, String)> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if token != Some(admin.token.as_str()) {
+        return Err((http::StatusCode::UNAUTHORIZED, "unauthorized".into()));
+    }
+    let summary = admin.policy.reload().map_err(|e| {
+        eprintln!("policy: failed to reload from the admin endpoint: {e:?}");
+        (http::StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))
+    })?;
+    println!("policy: reloaded from the admin endpoint: {summary:?}");
+    Ok(Json(summary))
+}
+
 async fn robots() -> &'static str {
     "\
 User-agent: *
@@ -152,10 +193,12 @@ struct HelloReponse {
     help: &'static str,
     days_indexed: u64,
     stats: StorageStats,
+    stream: StreamHealth,
 }
 fn hello(
     accept: ExtractAccept,
     store: impl LinkReader,
+    stream: StreamStatus,
 ) -> Result<impl IntoResponse, http::StatusCode> {
     let stats = store
         .get_stats()
@@ -169,6 +212,7 @@ fn hello(
         help: "open this URL in a web browser (or request with Accept: text/html) for information about this API.",
         days_indexed,
         stats,
+        stream: stream.health(),
     }))
 }
 
@@ -190,8 +234,9 @@ fn count_links(
     query: Query<GetLinksCountQuery>,
     store: impl LinkReader,
 ) -> Result<impl IntoResponse, http::StatusCode> {
+    let target = resolve_target(&store, &query.target)?;
     let total = store
-        .get_count(&query.target, &query.collection, &query.path)
+        .get_count(&target, &query.collection, &query.path)
         .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(acceptable(
         accept,
@@ -220,8 +265,9 @@ fn count_distinct_dids(
     query: Query<GetDidsCountQuery>,
     store: impl LinkReader,
 ) -> Result<impl IntoResponse, http::StatusCode> {
+    let target = resolve_target(&store, &query.target)?;
     let total = store
-        .get_distinct_did_count(&query.target, &query.collection, &query.path)
+        .get_distinct_did_count(&target, &query.collection, &query.path)
         .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(acceptable(
         accept,
@@ -251,6 +297,8 @@ struct GetLinkItemsResponse {
     linking_records: Vec<RecordId>,
     cursor: Option<OpaqueApiCursor>,
     #[serde(skip_serializing)]
+    handles: HashMap<String, String>,
+    #[serde(skip_serializing)]
     query: GetLinkItemsQuery,
 }
 fn get_links(
@@ -258,6 +306,7 @@ fn get_links(
     query: Query<GetLinkItemsQuery>,
     store: impl LinkReader,
 ) -> Result<impl IntoResponse, http::StatusCode> {
+    let target = resolve_target(&store, &query.target)?;
     let until = query
         .cursor
         .clone()
@@ -271,7 +320,7 @@ fn get_links(
     }
 
     let paged = store
-        .get_links(&query.target, &query.collection, &query.path, limit, until)
+        .get_links(&target, &query.collection, &query.path, limit, until)
         .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
 
     let cursor = paged.next.map(|next| {
@@ -282,12 +331,15 @@ fn get_links(
         .into()
     });
 
+    let handles = get_handles(&accept, &store, paged.items.iter().map(|r| &r.did))?;
+
     Ok(acceptable(
         accept,
         GetLinkItemsResponse {
             total: paged.version.0,
             linking_records: paged.items,
             cursor,
+            handles,
             query: (*query).clone(),
         },
     ))
@@ -312,6 +364,8 @@ struct GetDidItemsResponse {
     linking_dids: Vec<Did>,
     cursor: Option<OpaqueApiCursor>,
     #[serde(skip_serializing)]
+    handles: HashMap<String, String>,
+    #[serde(skip_serializing)]
     query: GetDidItemsQuery,
 }
 fn get_distinct_dids(
@@ -319,6 +373,7 @@ fn get_distinct_dids(
     query: Query<GetDidItemsQuery>,
     store: impl LinkReader,
 ) -> Result<impl IntoResponse, http::StatusCode> {
+    let target = resolve_target(&store, &query.target)?;
     let until = query
         .cursor
         .clone()
@@ -332,7 +387,7 @@ fn get_distinct_dids(
     }
 
     let paged = store
-        .get_distinct_dids(&query.target, &query.collection, &query.path, limit, until)
+        .get_distinct_dids(&target, &query.collection, &query.path, limit, until)
         .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
 
     let cursor = paged.next.map(|next| {
@@ -343,12 +398,15 @@ fn get_distinct_dids(
         .into()
     });
 
+    let handles = get_handles(&accept, &store, paged.items.iter())?;
+
     Ok(acceptable(
         accept,
         GetDidItemsResponse {
             total: paged.version.0,
             linking_dids: paged.items,
             cursor,
+            handles,
             query: (*query).clone(),
         },
     ))
@@ -370,8 +428,9 @@ fn count_all_links(
     query: Query<GetAllLinksQuery>,
     store: impl LinkReader,
 ) -> Result<impl IntoResponse, http::StatusCode> {
+    let target = resolve_target(&store, &query.target)?;
     let links = store
-        .get_all_record_counts(&query.target)
+        .get_all_record_counts(&target)
         .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(acceptable(
         accept,
@@ -398,8 +457,9 @@ fn explore_links(
     query: Query<ExploreLinksQuery>,
     store: impl LinkReader,
 ) -> Result<impl IntoResponse, http::StatusCode> {
+    let target = resolve_target(&store, &query.target)?;
     let links = store
-        .get_all_counts(&query.target)
+        .get_all_counts(&target)
         .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(acceptable(
         accept,
@@ -410,10 +470,53 @@ fn explore_links(
     ))
 }
 
+/// swap a handle for its DID in an at-uri target, if we've seen that handle on the firehose
+///
+/// unknown handles are left alone: they'll just find no links.
+fn resolve_target(store: &impl LinkReader, target: &str) -> Result<String, http::StatusCode> {
+    let Some(rest) = target.strip_prefix("at://") else {
+        return Ok(target.to_string());
+    };
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    if authority.starts_with("did:") {
+        return Ok(target.to_string());
+    }
+    let resolved = store
+        .resolve_handle(&authority.to_lowercase())
+        .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(match resolved {
+        Some(Did(did)) => format!("at://{did}{path}"),
+        None => target.to_string(),
+    })
+}
+
+/// current handles for the DIDs on a page, for html responses
+fn get_handles<'a>(
+    accept: &ExtractAccept,
+    store: &impl LinkReader,
+    dids: impl Iterator<Item = &'a Did>,
+) -> Result<HashMap<String, String>, http::StatusCode> {
+    if !wants_html(accept) {
+        return Ok(HashMap::new());
+    }
+    let dids: Vec<Did> = dids.cloned().collect();
+    let handles = store
+        .get_handles(&dids)
+        .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(dids
+        .into_iter()
+        .zip(handles)
+        .filter_map(|(did, handle)| Some((did.0, handle?)))
+        .collect())
+}
+
 #[serde_as]
 #[derive(Clone, Serialize, Deserialize)] // for json
 struct OpaqueApiCursor(#[serde_as(as = "serde_with::hex::Hex")] Vec<u8>);
 
+// `next` is the storage's own cursor, from the page before. rocks moves linkers when it collects
+// deleted ones, so its cursors carry the generation of the chunk they point into, and ones from
+// before a collection get mapped through what it took out.
 #[derive(Serialize, Deserialize)] // for bincode
 struct ApiCursor {
     version: (u64, u64), // (collection length, deleted item count)
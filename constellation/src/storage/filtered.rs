@@ -0,0 +1,213 @@
+use super::{HandleEntry, LinkReader, PagedAppendingCollection, StorageStats};
+use crate::policy::SharedPolicy;
+use crate::{CountsByCount, Did, RecordId};
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// A reader that hides whatever the policy excludes
+///
+/// Counts for excluded targets and collections come back empty. Excluded DIDs are dropped from
+/// listings, but still count until their links are purged.
+#[derive(Debug, Clone)]
+pub struct FilteredReader<R: LinkReader> {
+    inner: R,
+    policy: SharedPolicy,
+}
+
+impl<R: LinkReader> FilteredReader<R> {
+    pub fn new(inner: R, policy: SharedPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    fn hides(&self, target: &str, collection: &str) -> bool {
+        let policy = self.policy.current();
+        policy.excludes_target(target) || policy.excludes_collection(collection)
+    }
+
+    fn without_collections<T>(&self, mut counts: HashMap<String, T>) -> HashMap<String, T> {
+        let policy = self.policy.current();
+        counts.retain(|collection, _| !policy.excludes_collection(collection));
+        counts
+    }
+}
+
+fn nothing<T>() -> PagedAppendingCollection<T> {
+    PagedAppendingCollection {
+        version: (0, 0),
+        items: vec![],
+        next: None,
+    }
+}
+
+impl<R: LinkReader> LinkReader for FilteredReader<R> {
+    fn get_count(&self, target: &str, collection: &str, path: &str) -> Result<u64> {
+        if self.hides(target, collection) {
+            return Ok(0);
+        }
+        self.inner.get_count(target, collection, path)
+    }
+
+    fn get_distinct_did_count(&self, target: &str, collection: &str, path: &str) -> Result<u64> {
+        if self.hides(target, collection) {
+            return Ok(0);
+        }
+        self.inner.get_distinct_did_count(target, collection, path)
+    }
+
+    fn get_links(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        limit: u64,
+        until: Option<u64>,
+    ) -> Result<PagedAppendingCollection<RecordId>> {
+        if self.hides(target, collection) {
+            return Ok(nothing());
+        }
+        let mut links = self
+            .inner
+            .get_links(target, collection, path, limit, until)?;
+        let policy = self.policy.current();
+        links
+            .items
+            .retain(|record| !policy.excludes_did(&record.did));
+        Ok(links)
+    }
+
+    fn get_distinct_dids(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        limit: u64,
+        until: Option<u64>,
+    ) -> Result<PagedAppendingCollection<Did>> {
+        if self.hides(target, collection) {
+            return Ok(nothing());
+        }
+        let mut dids = self
+            .inner
+            .get_distinct_dids(target, collection, path, limit, until)?;
+        let policy = self.policy.current();
+        dids.items.retain(|did| !policy.excludes_did(did));
+        Ok(dids)
+    }
+
+    fn get_all_record_counts(&self, target: &str) -> Result<HashMap<String, HashMap<String, u64>>> {
+        if self.policy.current().excludes_target(target) {
+            return Ok(HashMap::new());
+        }
+        Ok(self.without_collections(self.inner.get_all_record_counts(target)?))
+    }
+
+    fn get_all_counts(
+        &self,
+        target: &str,
+    ) -> Result<HashMap<String, HashMap<String, CountsByCount>>> {
+        if self.policy.current().excludes_target(target) {
+            return Ok(HashMap::new());
+        }
+        Ok(self.without_collections(self.inner.get_all_counts(target)?))
+    }
+
+    fn get_stats(&self) -> Result<StorageStats> {
+        self.inner.get_stats()
+    }
+
+    fn get_last_cursor(&self) -> Result<Option<u64>> {
+        self.inner.get_last_cursor()
+    }
+
+    fn get_source_counts(&self) -> Result<HashMap<String, HashMap<String, u64>>> {
+        Ok(self.without_collections(self.inner.get_source_counts()?))
+    }
+
+    fn get_handle(&self, did: &Did) -> Result<Option<String>> {
+        if self.policy.current().excludes_did(did) {
+            return Ok(None);
+        }
+        self.inner.get_handle(did)
+    }
+
+    fn get_handles(&self, dids: &[Did]) -> Result<Vec<Option<String>>> {
+        let policy = self.policy.current();
+        let mut handles = self.inner.get_handles(dids)?;
+        for (did, handle) in dids.iter().zip(handles.iter_mut()) {
+            if policy.excludes_did(did) {
+                *handle = None;
+            }
+        }
+        Ok(handles)
+    }
+
+    fn get_handle_history(&self, did: &Did) -> Result<Vec<HandleEntry>> {
+        if self.policy.current().excludes_did(did) {
+            return Ok(vec![]);
+        }
+        self.inner.get_handle_history(did)
+    }
+
+    fn resolve_handle(&self, handle: &str) -> Result<Option<Did>> {
+        let did = self.inner.resolve_handle(handle)?;
+        Ok(did.filter(|did| !self.policy.current().excludes_did(did)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{LinkStorage, MemStorage};
+    use crate::ActionableEvent;
+    use links::{CollectedLink, Link};
+
+    #[test]
+    fn test_filtered_reader_hides_excluded() -> Result<()> {
+        let mut store = MemStorage::new();
+        for did in ["did:plc:a", "did:plc:b"] {
+            store.push(
+                &ActionableEvent::CreateLinks {
+                    record_id: RecordId {
+                        did: did.into(),
+                        collection: "app.t.c".into(),
+                        rkey: "a".into(),
+                    },
+                    links: vec![CollectedLink {
+                        target: Link::Uri("e.com".into()),
+                        path: ".uri".into(),
+                    }],
+                },
+                0,
+            )?;
+            store.push(
+                &ActionableEvent::Identity {
+                    did: did.into(),
+                    handle: Some(format!("{did}.test")),
+                },
+                0,
+            )?;
+        }
+        let policy = SharedPolicy::default();
+        let reader = FilteredReader::new(store.to_readable(), policy.clone());
+        assert_eq!(reader.get_count("e.com", "app.t.c", ".uri")?, 2);
+
+        policy.set("did did:plc:a".parse()?);
+        let dids = reader.get_distinct_dids("e.com", "app.t.c", ".uri", 10, None)?;
+        assert_eq!(dids.items, vec![Did::from("did:plc:b")]);
+        assert_eq!(
+            reader.get_handles(&["did:plc:a".into(), "did:plc:b".into()])?,
+            vec![None, Some("did:plc:b.test".into())]
+        );
+
+        policy.set("collection app.t.c".parse()?);
+        assert_eq!(reader.get_count("e.com", "app.t.c", ".uri")?, 0);
+        assert!(reader.get_all_record_counts("e.com")?.is_empty());
+
+        policy.set("target e.*".parse()?);
+        assert!(reader
+            .get_links("e.com", "app.t.c", ".uri", 10, None)?
+            .items
+            .is_empty());
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use super::{LinkReader, LinkStorage, PagedAppendingCollection, StorageStats};
+use super::{HandleEntry, LinkReader, LinkStorage, PagedAppendingCollection, StorageStats};
 use crate::{ActionableEvent, CountsByCount, Did, RecordId};
 use anyhow::Result;
 use links::CollectedLink;
@@ -14,8 +14,23 @@ type Linkers = Vec<Option<(Did, RKey)>>; // optional because we replace with Non
 #[derive(Debug, Default)]
 struct MemStorageData {
     dids: HashMap<Did, bool>,                           // bool: active or nah
+    private: HashSet<Did>,                              // self-labeled !no-unauthenticated
     targets: HashMap<Target, HashMap<Source, Linkers>>, // target -> (collection, path) -> (did, rkey)?[]
     links: HashMap<Did, HashMap<RepoId, Vec<(RecordPath, Target)>>>, // did -> collection:rkey -> (path, target)[]
+    handles: HashMap<Did, Vec<HandleEntry>>, // did -> handle history, current last
+    handle_dids: HashMap<String, Did>,       // handle -> did currently claiming it
+    cursor: Option<u64>,
+    seen_until: Option<u64>,
+    stream: Option<String>,
+    idempotent: bool,
+    show_private: bool,
+}
+
+impl MemStorageData {
+    fn shown(&self, did: &Did) -> bool {
+        *self.dids.get(did).expect("did must be in dids")
+            && (self.show_private || !self.private.contains(did))
+    }
 }
 
 impl MemStorage {
@@ -25,6 +40,25 @@ impl MemStorage {
 
     fn add_links(&mut self, record_id: &RecordId, links: &[CollectedLink]) {
         let mut data = self.0.lock().unwrap();
+        if data.idempotent {
+            let existing = data
+                .links
+                .get(&record_id.did)
+                .and_then(|records| records.get(&RepoId::from_record_id(record_id)));
+            if let Some(existing) = existing {
+                let same = existing.len() == links.len()
+                    && existing.iter().zip(links).all(|((path, target), link)| {
+                        *path == RecordPath::new(&link.path)
+                            && *target == Target::new(link.target.as_str())
+                    });
+                drop(data);
+                if !same {
+                    // a different version of the record: replace it
+                    self.update_links(record_id, links);
+                }
+                return; // otherwise seen it already
+            }
+        }
         for link in links {
             data.dids.entry(record_id.did()).or_insert(true); // if they are inserting a link, presumably they are active
             data.targets
@@ -68,9 +102,58 @@ impl MemStorage {
             .map(|cr| cr.remove(&repo_id));
     }
 
+    /// only touch the links that changed, so the rest keep their place
     fn update_links(&mut self, record_id: &RecordId, new_links: &[CollectedLink]) {
-        self.remove_links(record_id);
-        self.add_links(record_id, new_links);
+        let mut data = self.0.lock().unwrap();
+        let repo_id = RepoId::from_record_id(record_id);
+        let linker = Some((record_id.did(), RKey(record_id.rkey())));
+        let old_links = data
+            .links
+            .get_mut(&record_id.did)
+            .and_then(|records| records.remove(&repo_id))
+            .unwrap_or_default();
+        let links: Vec<_> = new_links
+            .iter()
+            .map(|link| {
+                (
+                    RecordPath::new(&link.path),
+                    Target::new(link.target.as_str()),
+                )
+            })
+            .collect();
+
+        let mut added = links.clone();
+        for old in old_links {
+            if let Some(i) = added.iter().position(|new| *new == old) {
+                added.remove(i); // still there
+                continue;
+            }
+            let (record_path, target) = old;
+            data.targets
+                .get_mut(&target)
+                .expect("must have the target if we have a link saved")
+                .get_mut(&Source::new(&record_id.collection, &record_path.0))
+                .expect("must have the target at this path if we have a link to it saved")
+                .iter_mut()
+                .rfind(|d| **d == linker)
+                .expect("must be in dids list if we have a link to it")
+                .take();
+        }
+        for (record_path, target) in added {
+            data.dids.entry(record_id.did()).or_insert(true);
+            data.targets
+                .entry(target)
+                .or_default()
+                .entry(Source::new(&record_id.collection, &record_path.0))
+                .or_default()
+                .push(linker.clone());
+        }
+        if !links.is_empty() {
+            data.links
+                .entry(record_id.did())
+                .or_default()
+                .insert(repo_id, links);
+        }
     }
 
     fn set_account(&mut self, did: &Did, active: bool) {
@@ -80,6 +163,37 @@ impl MemStorage {
         }
     }
 
+    fn set_private(&mut self, did: &Did, private: bool) {
+        let mut data = self.0.lock().unwrap();
+        if private {
+            data.private.insert(did.clone());
+        } else {
+            data.private.remove(did);
+        }
+    }
+
+    fn set_handle(&mut self, did: &Did, handle: &Option<String>, cursor: u64) {
+        let mut guard = self.0.lock().unwrap();
+        let data = &mut *guard;
+        let history = data.handles.entry(did.clone()).or_default();
+        let previous = history.last().and_then(|entry| entry.handle.clone());
+        if !history.is_empty() && previous == *handle {
+            return;
+        }
+        history.push(HandleEntry {
+            handle: handle.clone(),
+            since: cursor,
+        });
+        if let Some(previous) = previous {
+            if data.handle_dids.get(&previous) == Some(did) {
+                data.handle_dids.remove(&previous);
+            }
+        }
+        if let Some(handle) = handle {
+            data.handle_dids.insert(handle.clone(), did.clone());
+        }
+    }
+
     fn delete_account(&mut self, did: &Did) {
         let mut data = self.0.lock().unwrap();
         if let Some(links) = data.links.get(did) {
@@ -101,6 +215,16 @@ impl MemStorage {
         }
         data.links.remove(did); // nb: this is removing by a whole prefix in kv context
         data.dids.remove(did);
+        data.private.remove(did);
+        let current = data
+            .handles
+            .remove(did)
+            .and_then(|mut history| history.pop()?.handle);
+        if let Some(current) = current {
+            if data.handle_dids.get(&current) == Some(did) {
+                data.handle_dids.remove(&current);
+            }
+        }
     }
 }
 
@@ -111,26 +235,92 @@ impl Default for MemStorage {
 }
 
 impl LinkStorage for MemStorage {
-    fn push(&mut self, event: &ActionableEvent, _cursor: u64) -> Result<()> {
+    fn get_cursor(&mut self) -> Result<Option<u64>> {
+        self.get_last_cursor()
+    }
+
+    fn get_stream(&mut self) -> Result<Option<String>> {
+        Ok(self.0.lock().unwrap().stream.clone())
+    }
+
+    fn set_stream(&mut self, url: &str, cursor: Option<u64>) -> Result<()> {
+        let mut data = self.0.lock().unwrap();
+        data.stream = Some(url.to_string());
+        if cursor.is_some() {
+            data.cursor = cursor;
+        }
+        Ok(())
+    }
+
+    fn rewind_stream(&mut self, url: &str, cursor: u64) -> Result<()> {
+        let mut data = self.0.lock().unwrap();
+        data.seen_until = data.seen_until.max(data.cursor);
+        data.stream = Some(url.to_string());
+        data.cursor = Some(cursor);
+        Ok(())
+    }
+
+    fn get_seen_until(&mut self) -> Result<Option<u64>> {
+        Ok(self.0.lock().unwrap().seen_until)
+    }
+
+    fn push(&mut self, event: &ActionableEvent, cursor: u64) -> Result<()> {
         match event {
             ActionableEvent::CreateLinks { record_id, links } => self.add_links(record_id, links),
             ActionableEvent::UpdateLinks {
                 record_id,
                 new_links,
             } => self.update_links(record_id, new_links),
+            ActionableEvent::UpdateProfile {
+                record_id,
+                new_links,
+                private,
+            } => {
+                self.update_links(record_id, new_links);
+                self.set_private(&record_id.did, *private);
+            }
             ActionableEvent::DeleteRecord(record_id) => self.remove_links(record_id),
             ActionableEvent::ActivateAccount(did) => self.set_account(did, true),
             ActionableEvent::DeactivateAccount(did) => self.set_account(did, false),
             ActionableEvent::DeleteAccount(did) => self.delete_account(did),
+            ActionableEvent::Identity { did, handle } => self.set_handle(did, handle, cursor),
         }
+        self.0.lock().unwrap().cursor = Some(cursor);
         Ok(())
     }
 
-    fn to_readable(&mut self) -> impl LinkReader {
+    fn set_idempotent(&mut self, idempotent: bool) {
+        self.0.lock().unwrap().idempotent = idempotent;
+    }
+
+    fn set_show_private(&mut self, show: bool) {
+        self.0.lock().unwrap().show_private = show;
+    }
+
+    fn to_readable(&mut self) -> impl LinkReader + use<> {
         self.clone()
     }
 }
 
+/// up to `limit` of the items before `until`, newest first, and where the next page starts if
+/// there are more
+///
+/// empty slots (deleted links, or dids already seen) don't count toward the limit.
+fn page_back<T>(slots: &[Option<T>], limit: u64, until: Option<u64>) -> (Vec<&T>, Option<u64>) {
+    let end = until.map_or(slots.len(), |u| (u as usize).min(slots.len()));
+    let mut page = Vec::new();
+    for (i, item) in slots[..end].iter().enumerate().rev() {
+        let Some(item) = item else {
+            continue;
+        };
+        if page.len() as u64 == limit {
+            return (page, Some(i as u64 + 1));
+        }
+        page.push(item);
+    }
+    (page, None)
+}
+
 impl LinkReader for MemStorage {
     fn get_count(&self, target: &str, collection: &str, path: &str) -> Result<u64> {
         let data = self.0.lock().unwrap();
@@ -184,20 +374,13 @@ impl LinkReader for MemStorage {
         };
 
         let total = did_rkeys.len();
-        let end = until
-            .map(|u| std::cmp::min(u as usize, total))
-            .unwrap_or(total);
-        let begin = end.saturating_sub(limit as usize);
-        let next = if begin == 0 { None } else { Some(begin as u64) };
-
         let alive = did_rkeys.iter().flatten().count();
         let gone = total - alive;
 
-        let items: Vec<_> = did_rkeys[begin..end]
-            .iter()
-            .rev()
-            .flatten()
-            .filter(|(did, _)| *data.dids.get(did).expect("did must be in dids"))
+        let (page, next) = page_back(did_rkeys, limit, until);
+        let items: Vec<_> = page
+            .into_iter()
+            .filter(|(did, _)| data.shown(did))
             .map(|(did, rkey)| RecordId {
                 did: did.clone(),
                 rkey: rkey.0.clone(),
@@ -254,20 +437,13 @@ impl LinkReader for MemStorage {
         };
 
         let total = dids.len();
-        let end = until
-            .map(|u| std::cmp::min(u as usize, total))
-            .unwrap_or(total);
-        let begin = end.saturating_sub(limit as usize);
-        let next = if begin == 0 { None } else { Some(begin as u64) };
-
         let alive = dids.iter().flatten().count();
         let gone = total - alive;
 
-        let items: Vec<Did> = dids[begin..end]
-            .iter()
-            .rev()
-            .flatten()
-            .filter(|did| *data.dids.get(did).expect("did must be in dids"))
+        let (page, next) = page_back(&dids, limit, until);
+        let items: Vec<Did> = page
+            .into_iter()
+            .filter(|did| data.shown(did))
             .cloned()
             .collect();
 
@@ -334,6 +510,43 @@ impl LinkReader for MemStorage {
             linking_records,
         })
     }
+
+    fn get_last_cursor(&self) -> Result<Option<u64>> {
+        Ok(self.0.lock().unwrap().cursor)
+    }
+
+    fn get_source_counts(&self) -> Result<HashMap<String, HashMap<String, u64>>> {
+        let data = self.0.lock().unwrap();
+        let mut out: HashMap<String, HashMap<String, u64>> = HashMap::new();
+        for sources in data.targets.values() {
+            for (Source { collection, path }, linkers) in sources {
+                *out.entry(collection.to_string())
+                    .or_default()
+                    .entry(path.to_string())
+                    .or_default() += linkers.iter().flatten().count() as u64;
+            }
+        }
+        Ok(out)
+    }
+
+    fn get_handle(&self, did: &Did) -> Result<Option<String>> {
+        let data = self.0.lock().unwrap();
+        Ok(data
+            .handles
+            .get(did)
+            .and_then(|history| history.last())
+            .and_then(|entry| entry.handle.clone())
+            .filter(|handle| data.handle_dids.get(handle) == Some(did)))
+    }
+
+    fn get_handle_history(&self, did: &Did) -> Result<Vec<HandleEntry>> {
+        let data = self.0.lock().unwrap();
+        Ok(data.handles.get(did).cloned().unwrap_or_default())
+    }
+
+    fn resolve_handle(&self, handle: &str) -> Result<Option<Did>> {
+        Ok(self.0.lock().unwrap().handle_dids.get(handle).cloned())
+    }
 }
 
 #[derive(Debug, PartialEq, Hash, Eq, Clone)]
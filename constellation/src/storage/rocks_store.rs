@@ -1,17 +1,21 @@
-use super::{ActionableEvent, LinkReader, LinkStorage, PagedAppendingCollection, StorageStats};
+use super::{
+    ActionableEvent, HandleEntry, LinkReader, LinkStorage, PagedAppendingCollection, StorageStats,
+};
 use crate::{CountsByCount, Did, RecordId};
 use anyhow::{bail, Result};
 use bincode::Options as BincodeOptions;
 use links::CollectedLink;
-use metrics::{counter, describe_counter, describe_histogram, histogram, Unit};
+use metrics::{
+    counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit,
+};
 use ratelimit::Ratelimiter;
 use rocksdb::backup::{BackupEngine, BackupEngineOptions};
 use rocksdb::{
-    AsColumnFamilyRef, ColumnFamilyDescriptor, DBWithThreadMode, IteratorMode, MergeOperands,
-    MultiThreaded, Options, PrefixRange, ReadOptions, WriteBatch,
+    AsColumnFamilyRef, ColumnFamilyDescriptor, DBWithThreadMode, Direction, IteratorMode,
+    MergeOperands, MultiThreaded, Options, PrefixRange, ReadOptions, WriteBatch,
 };
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 use std::io::Read;
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
@@ -24,11 +28,102 @@ use std::time::{Duration, Instant};
 use tokio_util::sync::CancellationToken;
 
 static DID_IDS_CF: &str = "did_ids";
+static COLLECTION_IDS_CF: &str = "collection_ids";
+static SOURCE_IDS_CF: &str = "source_ids";
 static TARGET_IDS_CF: &str = "target_ids";
 static TARGET_LINKERS_CF: &str = "target_links";
-static LINK_TARGETS_CF: &str = "link_targets";
+static TARGET_LINK_COUNTS_CF: &str = "target_link_counts";
+static TARGET_DID_LINKS_CF: &str = "target_did_links";
+static LINK_TARGETS_CF: &str = "record_link_targets";
+/// record links from before collections and paths had ids: only opened to migrate them
+static LEGACY_LINK_TARGETS_CF: &str = "link_targets";
+static DID_HANDLES_CF: &str = "did_handles";
+static HANDLE_DIDS_CF: &str = "handle_dids";
+static TASKS_CF: &str = "tasks";
 
 static JETSTREAM_CURSOR_KEY: &str = "jetstream_cursor";
+static JETSTREAM_URL_KEY: &str = "jetstream_url";
+/// the furthest the cursor got before it was last rewound
+static JETSTREAM_SEEN_UNTIL_KEY: &str = "jetstream_seen_until";
+/// how many of `MIGRATIONS` the db has had (online ones count once they're queued)
+static SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// a change to how things are stored, for dbs from before it
+struct Migration {
+    name: &'static str,
+    /// if readonly mode has to refuse dbs that haven't had it
+    reads_need_it: bool,
+    /// the key set when this was done, from before there were schema versions
+    legacy_key: Option<&'static str>,
+    step: MigrationStep,
+}
+
+enum MigrationStep {
+    /// the writer does it all when it opens the db
+    Offline(fn(&mut RocksStorage) -> Result<()>),
+    /// reads work without it, so the writer goes through each key of the column family a few at
+    /// a time between events
+    Online(
+        &'static str,
+        fn(&RocksStorage, &[u8], &[u8], &mut Batch) -> Result<()>,
+    ),
+}
+
+/// every migration in order: a db's schema version is how many of them it's had
+///
+/// only ever add to the end. offline steps can't count on an earlier online one being done.
+static MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: "split target linkers into chunks",
+        reads_need_it: true,
+        legacy_key: Some("target_linkers_chunked"),
+        step: MigrationStep::Offline(RocksStorage::chunk_target_linkers),
+    },
+    Migration {
+        name: "re-encode linker chunks compactly",
+        reads_need_it: false,
+        legacy_key: Some("target_linkers_compact"),
+        step: MigrationStep::Online(TARGET_LINKERS_CF, RocksStorage::compact_linker_chunk),
+    },
+    Migration {
+        name: "count target linkers",
+        reads_need_it: true,
+        legacy_key: Some("target_linkers_counted"),
+        step: MigrationStep::Offline(RocksStorage::count_target_linkers),
+    },
+    Migration {
+        name: "give collections and paths ids",
+        reads_need_it: true,
+        legacy_key: Some("sources_interned"),
+        step: MigrationStep::Offline(RocksStorage::intern_sources),
+    },
+    Migration {
+        name: "fill values into reverse id entries",
+        reads_need_it: false,
+        legacy_key: Some("reverse_ids_filled"),
+        step: MigrationStep::Offline(RocksStorage::fill_reverse_ids),
+    },
+    Migration {
+        name: "drop reverse entries for deleted dids",
+        reads_need_it: false,
+        legacy_key: Some("deleted_dids_dropped"),
+        step: MigrationStep::Online(DID_IDS_CF, RocksStorage::drop_deleted_did),
+    },
+];
+
+/// linkers are appended into fixed-size chunks, so paging only reads the chunks it needs
+pub const LINKERS_PER_CHUNK: u64 = 256;
+/// how much background work to do in each round: deleted accounts' records to unlink, linker
+/// chunks to rewrite, or keys for an online migration to look at
+const TASK_WORK_PER_ROUND: usize = 1024;
+/// collect the tombstones out of a target's linker chunks once they're this share of what the
+/// chunks hold
+///
+/// that moves linkers down in their chunks, so cursors carry their chunk's generation to be
+/// mapped through what was taken out.
+pub const DEFAULT_TOMBSTONE_RATIO: f64 = 0.2;
+/// fewer tombstones than this aren't worth a pass over the target's chunks
+const MIN_COLLECTED_TOMBSTONES: i64 = LINKERS_PER_CHUNK as i64 / 8;
 
 // todo: actually understand and set these options probably better
 fn rocks_opts_base() -> Options {
@@ -57,9 +152,16 @@ fn get_db_read_opts() -> Options {
 pub struct RocksStorage {
     pub db: Arc<DBWithThreadMode<MultiThreaded>>, // TODO: mov seqs here (concat merge op will be fun)
     did_id_table: IdTable<Did, DidIdValue, true>,
+    collection_id_table: IdTable<Collection, CollectionId, true>,
+    source_id_table: IdTable<SourceKey, SourceId, true>,
     target_id_table: IdTable<TargetKey, TargetId, false>,
     is_writer: bool,
     backup_task: Arc<Option<thread::JoinHandle<Result<()>>>>,
+    idempotent: bool,
+    show_private: bool,
+    tombstone_ratio: f64,
+    next_task_id: u64,
+    queued_tasks: u64,
 }
 
 trait IdTableValue: ValueFromRocks + Clone {
@@ -74,7 +176,7 @@ where
 {
     _key_marker: PhantomData<Orig>,
     _val_marker: PhantomData<IdVal>,
-    name: String,
+    name: &'static str,
     id_seq: Arc<AtomicU64>,
 }
 impl<Orig, IdVal: IdTableValue> IdTableBase<Orig, IdVal>
@@ -83,13 +185,13 @@ where
     for<'a> &'a Orig: AsRocksKey,
 {
     fn cf_descriptor(&self) -> ColumnFamilyDescriptor {
-        ColumnFamilyDescriptor::new(&self.name, rocks_opts_base())
+        ColumnFamilyDescriptor::new(self.name, rocks_opts_base())
     }
     fn init<const WITH_REVERSE: bool>(
         self,
         db: &DBWithThreadMode<MultiThreaded>,
     ) -> Result<IdTable<Orig, IdVal, WITH_REVERSE>> {
-        if db.cf_handle(&self.name).is_none() {
+        if db.cf_handle(self.name).is_none() {
             bail!("failed to get cf handle from db -- was the db open with our .cf_descriptor()?");
         }
         let priv_id_seq = if let Some(seq_bytes) = db.get(self.seq_key())? {
@@ -134,11 +236,11 @@ where
     for<'k> &'k Orig: AsRocksKey,
 {
     #[must_use]
-    fn setup(name: &str) -> IdTableBase<Orig, IdVal> {
+    fn setup(name: &'static str) -> IdTableBase<Orig, IdVal> {
         IdTableBase::<Orig, IdVal> {
             _key_marker: PhantomData,
             _val_marker: PhantomData,
-            name: name.into(),
+            name,
             id_seq: Arc::new(AtomicU64::new(0)), // zero is "uninint", first seq num will be 1
         }
     }
@@ -147,41 +249,67 @@ where
         db: &DBWithThreadMode<MultiThreaded>,
         orig: &Orig,
     ) -> Result<Option<IdVal>> {
-        let cf = db.cf_handle(&self.base.name).unwrap();
+        let cf = db.cf_handle(self.base.name).unwrap();
         if let Some(_id_bytes) = db.get_cf(&cf, _rk(orig))? {
             Ok(Some(_vr(&_id_bytes)?))
         } else {
             Ok(None)
         }
     }
-    fn __get_or_create_id_val<CF>(
-        &mut self,
-        cf: &CF,
+    /// new ids come from the batch, and only count once it's written (see `commit`)
+    fn __get_or_create_id_val(
+        &self,
         db: &DBWithThreadMode<MultiThreaded>,
-        batch: &mut WriteBatch,
+        batch: &mut Batch,
         orig: &Orig,
-    ) -> Result<IdVal>
+    ) -> Result<IdVal> {
+        // the batch might have allocated this one already
+        if let Some(id_val) = batch.get_value(db, self.base.name, &_rk(orig))? {
+            return Ok(id_val);
+        }
+        let seq = batch.ids.entry(self.base.name).or_insert(self.priv_id_seq);
+        *seq += 1;
+        let seq = *seq;
+        let id_value = IdVal::new(seq);
+        batch.put(self.base.seq_key(), seq.to_le_bytes());
+        batch.put_cf(db, self.base.name, _rk(orig), _rv(&id_value));
+        Ok(id_value)
+    }
+    /// take the ids a batch allocated, now that it's been written
+    fn commit(&mut self, ids: &HashMap<&'static str, u64>) {
+        let Some(&seq) = ids.get(self.base.name) else {
+            return;
+        };
+        let prev_priv_seq = self.priv_id_seq;
+        self.priv_id_seq = seq;
+        let prev_public_seq = self.base.id_seq.swap(self.priv_id_seq, Ordering::SeqCst);
+        assert_eq!(
+            prev_public_seq, prev_priv_seq,
+            "public seq may have been modified??"
+        );
+    }
+    /// values for many originals in one read, in the same order
+    fn multi_get_id_vals<'o>(
+        &self,
+        db: &DBWithThreadMode<MultiThreaded>,
+        origs: impl IntoIterator<Item = &'o Orig>,
+    ) -> Result<Vec<Option<IdVal>>>
     where
-        CF: AsColumnFamilyRef,
+        Orig: 'o,
     {
-        Ok(self.get_id_val(db, orig)?.unwrap_or_else(|| {
-            let prev_priv_seq = self.priv_id_seq;
-            self.priv_id_seq += 1;
-            let prev_public_seq = self.base.id_seq.swap(self.priv_id_seq, Ordering::SeqCst);
-            assert_eq!(
-                prev_public_seq, prev_priv_seq,
-                "public seq may have been modified??"
-            );
-            let id_value = IdVal::new(self.priv_id_seq);
-            batch.put(self.base.seq_key(), self.priv_id_seq.to_le_bytes());
-            batch.put_cf(cf, _rk(orig), _rv(&id_value));
-            id_value
-        }))
+        let cf = db.cf_handle(self.base.name).unwrap();
+        let keys: Vec<_> = origs.into_iter().map(_rk).collect();
+        db.batched_multi_get_cf(&cf, &keys, false)
+            .into_iter()
+            .map(|id_bytes| id_bytes?.map(|b| _vr(&b)).transpose())
+            .collect()
     }
     fn estimate_count(&self) -> u64 {
         self.base.id_seq.load(Ordering::SeqCst) - 1 // -1 because seq zero is reserved
     }
 }
+/// an original and its id value, which reverse entries from before didn't hold
+type ReverseEntry<Orig, IdVal> = (Orig, Option<IdVal>);
 impl<Orig: Clone, IdVal: IdTableValue> IdTable<Orig, IdVal, true>
 where
     Orig: KeyFromRocks,
@@ -189,31 +317,106 @@ where
     for<'k> &'k Orig: AsRocksKey,
 {
     fn get_or_create_id_val(
-        &mut self,
+        &self,
         db: &DBWithThreadMode<MultiThreaded>,
-        batch: &mut WriteBatch,
+        batch: &mut Batch,
         orig: &Orig,
     ) -> Result<IdVal> {
-        let cf = db.cf_handle(&self.base.name).unwrap();
-        let id_val = self.__get_or_create_id_val(&cf, db, batch, orig)?;
-        // TODO: assert that the original is never a u64 that could collide
-        batch.put_cf(&cf, id_val.id().to_be_bytes(), _rk(orig)); // reversed rk/rv on purpose here :/
+        let id_val = self.__get_or_create_id_val(db, batch, orig)?;
+        self.put_reverse(db, batch, orig, &id_val);
         Ok(id_val)
     }
+    /// change the value for an existing id, keeping the reverse entry in sync
+    fn put_id_val(
+        &self,
+        db: &DBWithThreadMode<MultiThreaded>,
+        batch: &mut Batch,
+        orig: &Orig,
+        id_val: &IdVal,
+    ) {
+        batch.put_cf(db, self.base.name, _rk(orig), _rv(id_val));
+        self.put_reverse(db, batch, orig, id_val);
+    }
+    /// the reverse entry holds the id value after the original, so one read gets both
+    fn put_reverse(
+        &self,
+        db: &DBWithThreadMode<MultiThreaded>,
+        batch: &mut Batch,
+        orig: &Orig,
+        id_val: &IdVal,
+    ) {
+        // TODO: assert that the original is never a u64 that could collide
+        let mut entry = _rk(orig); // reversed rk/rv on purpose here :/
+        entry.extend(_rv(id_val));
+        batch.put_cf(
+            db,
+            self.base.name,
+            id_val.id().to_be_bytes().to_vec(),
+            entry,
+        );
+    }
+    /// drop the reverse entry once nothing refers to the id anymore
+    fn delete_reverse(&self, db: &DBWithThreadMode<MultiThreaded>, batch: &mut Batch, id: u64) {
+        batch.delete_cf(db, self.base.name, id.to_be_bytes().to_vec());
+    }
+    /// reverse entries from before they held the value only have the original
+    fn read_reverse(bytes: &[u8]) -> Result<ReverseEntry<Orig, IdVal>> {
+        if let Ok((orig, id_val)) = _bincode_opts().deserialize(bytes) {
+            return Ok((orig, Some(id_val)));
+        }
+        Ok((_kr(bytes)?, None))
+    }
 
     fn get_val_from_id(
         &self,
         db: &DBWithThreadMode<MultiThreaded>,
         id: u64,
     ) -> Result<Option<Orig>> {
-        let cf = db.cf_handle(&self.base.name).unwrap();
-        if let Some(orig_bytes) = db.get_cf(&cf, id.to_be_bytes())? {
-            // HACK ish
-            Ok(Some(_kr(&orig_bytes)?))
+        let cf = db.cf_handle(self.base.name).unwrap();
+        if let Some(entry_bytes) = db.get_cf(&cf, id.to_be_bytes())? {
+            Ok(Some(Self::read_reverse(&entry_bytes)?.0))
         } else {
             Ok(None)
         }
     }
+    /// reverse entries for many ids in one read, in the same order
+    fn multi_get_from_ids(
+        &self,
+        db: &DBWithThreadMode<MultiThreaded>,
+        ids: impl IntoIterator<Item = u64>,
+    ) -> Result<Vec<Option<ReverseEntry<Orig, IdVal>>>> {
+        let cf = db.cf_handle(self.base.name).unwrap();
+        let keys: Vec<_> = ids.into_iter().map(u64::to_be_bytes).collect();
+        db.batched_multi_get_cf(&cf, &keys, false)
+            .into_iter()
+            .map(|entry_bytes| entry_bytes?.map(|b| Self::read_reverse(&b)).transpose())
+            .collect()
+    }
+    /// rewrite reverse entries that don't have the value yet
+    fn fill_reverse(&self, db: &DBWithThreadMode<MultiThreaded>) -> Result<usize> {
+        let cf = db.cf_handle(self.base.name).unwrap();
+        let mut filled = 0;
+        let mut batch = Batch::default();
+        for kv in db.iterator_cf(&cf, IteratorMode::Start) {
+            let (k, v) = kv?;
+            if k.len() != 8 {
+                continue; // forward entry
+            }
+            let (orig, None) = Self::read_reverse(&v)? else {
+                continue;
+            };
+            // a missing forward entry means the original was deleted: leave it to fall back
+            if let Some(id_val) = self.get_id_val(db, &orig)? {
+                self.put_reverse(db, &mut batch, &orig, &id_val);
+                filled += 1;
+            }
+            if batch.len() >= 10_000 {
+                db.write(std::mem::take(&mut batch).batch)?;
+            }
+        }
+        db.write(batch.batch)?;
+        Ok(filled)
+    }
 }
 impl<Orig: Clone, IdVal: IdTableValue> IdTable<Orig, IdVal, false>
 where
@@ -222,24 +425,39 @@ where
     for<'k> &'k Orig: AsRocksKey,
 {
     fn get_or_create_id_val(
-        &mut self,
+        &self,
         db: &DBWithThreadMode<MultiThreaded>,
-        batch: &mut WriteBatch,
+        batch: &mut Batch,
         orig: &Orig,
     ) -> Result<IdVal> {
-        let cf = db.cf_handle(&self.base.name).unwrap();
-        self.__get_or_create_id_val(&cf, db, batch, orig)
+        self.__get_or_create_id_val(db, batch, orig)
     }
 }
 
 impl IdTableValue for DidIdValue {
     fn new(v: u64) -> Self {
-        DidIdValue(DidId(v), true)
+        DidIdValue(DidId(v), AccountFlags::ACTIVE)
     }
     fn id(&self) -> u64 {
         self.0 .0
     }
 }
+impl IdTableValue for CollectionId {
+    fn new(v: u64) -> Self {
+        CollectionId(v)
+    }
+    fn id(&self) -> u64 {
+        self.0
+    }
+}
+impl IdTableValue for SourceId {
+    fn new(v: u64) -> Self {
+        SourceId(v)
+    }
+    fn id(&self) -> u64 {
+        self.0
+    }
+}
 impl IdTableValue for TargetId {
     fn new(v: u64) -> Self {
         TargetId(v)
@@ -260,12 +478,17 @@ impl RocksStorage {
     }
 
     fn open_readmode(path: impl AsRef<Path>, readonly: bool) -> Result<Self> {
+        let path = path.as_ref();
         let did_id_table = IdTable::<_, _, true>::setup(DID_IDS_CF);
+        let collection_id_table = IdTable::<_, _, true>::setup(COLLECTION_IDS_CF);
+        let source_id_table = IdTable::<_, _, true>::setup(SOURCE_IDS_CF);
         let target_id_table = IdTable::<_, _, false>::setup(TARGET_IDS_CF);
 
-        let cfs = vec![
+        let mut cfs = vec![
             // id reference tables
             did_id_table.cf_descriptor(),
+            collection_id_table.cf_descriptor(),
+            source_id_table.cf_descriptor(),
             target_id_table.cf_descriptor(),
             // the reverse links:
             ColumnFamilyDescriptor::new(TARGET_LINKERS_CF, {
@@ -276,9 +499,46 @@ impl RocksStorage {
                 );
                 opts
             }),
+            // how many linkers each target has had, how many of those are deleted, and how many
+            // distinct dids are left
+            ColumnFamilyDescriptor::new(TARGET_LINK_COUNTS_CF, {
+                let mut opts = rocks_opts_base();
+                opts.set_merge_operator_associative(
+                    "merge_op_add_counts",
+                    Self::merge_op_add_counts,
+                );
+                opts
+            }),
+            // which chunks each did's links to each target are in, for keeping the distinct count
+            // and finding first links
+            ColumnFamilyDescriptor::new(TARGET_DID_LINKS_CF, rocks_opts_base()),
             // unfortunately we also need forward links to handle deletes
             ColumnFamilyDescriptor::new(LINK_TARGETS_CF, rocks_opts_base()),
+            // identities: handle history per did, and the did currently claiming each handle
+            ColumnFamilyDescriptor::new(DID_HANDLES_CF, rocks_opts_base()),
+            ColumnFamilyDescriptor::new(HANDLE_DIDS_CF, rocks_opts_base()),
+            // background work the writer does a bit at a time between events
+            ColumnFamilyDescriptor::new(TASKS_CF, rocks_opts_base()),
         ];
+        let existing_cfs = DBWithThreadMode::<MultiThreaded>::list_cf(&Options::default(), path)
+            .unwrap_or_default();
+        if existing_cfs.iter().any(|cf| cf == LEGACY_LINK_TARGETS_CF) {
+            cfs.push(ColumnFamilyDescriptor::new(
+                LEGACY_LINK_TARGETS_CF,
+                rocks_opts_base(),
+            ));
+        }
+        // readonly mode can't create column families, and rocks refuses to open any that are
+        // missing, so older dbs get sent to the writer (which creates them and migrates)
+        if readonly && !existing_cfs.is_empty() {
+            if let Some(missing) = cfs
+                .iter()
+                .map(|cf| cf.name())
+                .find(|name| !existing_cfs.iter().any(|cf| cf == name))
+            {
+                bail!("this db is from an older constellation (it has no {missing} column family yet): open it once with the writer first to migrate it");
+            }
+        }
 
         let db = if readonly {
             DBWithThreadMode::open_cf_descriptors_read_only(&get_db_read_opts(), path, cfs, false)?
@@ -288,14 +548,363 @@ impl RocksStorage {
 
         let db = Arc::new(db);
         let did_id_table = did_id_table.init(&db)?;
+        let collection_id_table = collection_id_table.init(&db)?;
+        let source_id_table = source_id_table.init(&db)?;
         let target_id_table = target_id_table.init(&db)?;
-        Ok(Self {
+        let (next_task_id, queued_tasks) = Self::count_tasks(&db)?;
+        let mut store = Self {
             db,
             did_id_table,
+            collection_id_table,
+            source_id_table,
             target_id_table,
-            is_writer: true,
+            is_writer: !readonly,
             backup_task: None.into(),
-        })
+            idempotent: false,
+            show_private: false,
+            tombstone_ratio: DEFAULT_TOMBSTONE_RATIO,
+            next_task_id,
+            queued_tasks,
+        };
+        store.migrate(readonly)?;
+        gauge!("storage_rocksdb_tasks_queued").set(store.queued_tasks as f64);
+        Ok(store)
+    }
+
+    /// bring the db up to the current schema version
+    ///
+    /// the writer runs offline steps right away and queues online ones. readonly mode can't do
+    /// either, so it only opens dbs that aren't missing any steps that reads need.
+    fn migrate(&mut self, readonly: bool) -> Result<()> {
+        let latest = MIGRATIONS.len() as u64;
+        let version = self.schema_version()?;
+        if version > latest {
+            bail!("this db has schema version {version}, but this build only knows up to {latest}: it needs a newer constellation");
+        }
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let name = migration.name;
+            let mut batch = Batch::default();
+            match (&migration.step, readonly) {
+                (_, true) if !migration.reads_need_it => continue,
+                (_, true) => {
+                    bail!("this db needs a migration ({name}): open it once with the writer first")
+                }
+                (MigrationStep::Offline(run), false) => {
+                    println!("rocks: migrating ({}/{latest}): {name}...", i + 1);
+                    let t0 = Instant::now();
+                    run(self)?;
+                    println!(
+                        "rocks: migrated ({name}) in {:.1}s",
+                        t0.elapsed().as_secs_f32()
+                    );
+                }
+                (MigrationStep::Online(..), false) => {
+                    println!(
+                        "rocks: migrating ({}/{latest}): {name}, in the background",
+                        i + 1
+                    );
+                    self.queue_task(&mut batch, &Task::Migrate(i as u64, vec![], 0));
+                }
+            }
+            batch.put(SCHEMA_VERSION_KEY, _rv(i as u64 + 1));
+            self.write(batch)?;
+        }
+        if !readonly {
+            // also for new dbs, and ones that only had the old keys
+            let mut batch = WriteBatch::default();
+            batch.put(SCHEMA_VERSION_KEY, _rv(latest));
+            for legacy_key in MIGRATIONS.iter().filter_map(|m| m.legacy_key) {
+                batch.delete(legacy_key);
+            }
+            self.db.write(batch)?;
+        }
+        let version = if readonly { version } else { latest };
+        gauge!("storage_rocksdb_schema_version").set(version as f64);
+        Ok(())
+    }
+    /// dbs from before schema versions have the keys each migration set when it was done
+    fn schema_version(&self) -> Result<u64> {
+        if let Some(bytes) = self.db.get(SCHEMA_VERSION_KEY)? {
+            return _vr(&bytes);
+        }
+        let has_data = [DID_IDS_CF, TARGET_IDS_CF].into_iter().any(|cf| {
+            let cf = self.db.cf_handle(cf).unwrap();
+            self.db
+                .iterator_cf(&cf, IteratorMode::Start)
+                .next()
+                .is_some()
+        });
+        if !has_data && self.db.cf_handle(LEGACY_LINK_TARGETS_CF).is_none() {
+            return Ok(MIGRATIONS.len() as u64); // new: nothing to migrate
+        }
+        let mut version = 0;
+        for migration in MIGRATIONS {
+            let Some(legacy_key) = migration.legacy_key else {
+                break;
+            };
+            if self.db.get(legacy_key)?.is_none() {
+                break;
+            }
+            version += 1;
+        }
+        Ok(version)
+    }
+    /// run an online migration over up to `limit` keys from `from`, returning where to carry on
+    /// (if there's more), and how many keys it looked at
+    fn migrate_some(
+        &self,
+        step: u64,
+        from: &[u8],
+        limit: usize,
+        batch: &mut Batch,
+    ) -> Result<(Option<Vec<u8>>, usize)> {
+        let Some(MigrationStep::Online(cf, run)) = MIGRATIONS.get(step as usize).map(|m| &m.step)
+        else {
+            bail!("bug? migration task for step {step}, which isn't an online migration");
+        };
+        let cf = self.db.cf_handle(cf).unwrap();
+        let mut last = None;
+        let mut looked_at = 0;
+        let from = IteratorMode::From(from, rocksdb::Direction::Forward);
+        for kv in self.db.iterator_cf(&cf, from).take(limit) {
+            let (k, v) = kv?;
+            run(self, &k, &v, batch)?;
+            looked_at += 1;
+            last = Some(k);
+        }
+        match last {
+            Some(last) if looked_at == limit => {
+                let mut next = last.to_vec();
+                next.push(0); // the first key after it
+                Ok((Some(next), looked_at))
+            }
+            _ => Ok((None, looked_at)),
+        }
+    }
+
+    /// add values to reverse id entries from before they held them
+    ///
+    /// reads fall back to the forward entry for old ones, but this is quick enough to just do.
+    fn fill_reverse_ids(&mut self) -> Result<()> {
+        let filled = self.did_id_table.fill_reverse(&self.db)?
+            + self.collection_id_table.fill_reverse(&self.db)?
+            + self.source_id_table.fill_reverse(&self.db)?;
+        println!("rocks: filled values into {filled} reverse id entries");
+        Ok(())
+    }
+
+    /// drop a reverse did entry that an account deletion used to leave behind
+    ///
+    /// an entry is left over when its did's forward entry is gone or has a newer id. ones still
+    /// hiding an account whose links are being removed go when their task finishes instead.
+    fn drop_deleted_did(&self, key: &[u8], value: &[u8], batch: &mut Batch) -> Result<()> {
+        let Ok(id_bytes) = <[u8; 8]>::try_from(key) else {
+            return Ok(()); // forward entry
+        };
+        let did_id = DidId(u64::from_be_bytes(id_bytes));
+        let (did, _) = IdTable::<Did, DidIdValue, true>::read_reverse(value)?;
+        let current = self.did_id_table.get_id_val(&self.db, &did)?;
+        if matches!(current, Some(DidIdValue(current_id, _)) if current_id == did_id) {
+            return Ok(());
+        }
+        let tasks_cf = self.db.cf_handle(TASKS_CF).unwrap();
+        for kv in self.db.iterator_cf(&tasks_cf, IteratorMode::Start) {
+            if matches!(_vr(&kv?.1)?, Task::DeleteAccountLinks(deleting) if deleting == did_id) {
+                return Ok(());
+            }
+        }
+        self.did_id_table.delete_reverse(&self.db, batch, did_id.0);
+        Ok(())
+    }
+
+    /// give collections and (collection, path) sources ids, replacing their strings in the
+    /// target ids table and the record links
+    ///
+    /// target keys are rewritten in place: a key in the old format never decodes as a new one,
+    /// since the collection and path leave trailing bytes after where the source id would be.
+    /// record links move to a new column family, and the old one is dropped when they're done.
+    fn intern_sources(&mut self) -> Result<()> {
+        // a separate handle, since batches are written through &mut self while iterating
+        let db = self.db.clone();
+        let legacy_links_cf = db.cf_handle(LEGACY_LINK_TARGETS_CF);
+        let target_ids_cf = db.cf_handle(TARGET_IDS_CF).unwrap();
+        let (mut targets, mut records) = (0, 0);
+
+        let mut batch = Batch::default();
+        for kv in db.iterator_cf(&target_ids_cf, IteratorMode::Start) {
+            let (k, v) = kv?;
+            if _kr::<TargetKey>(&k).is_ok() {
+                continue; // already done (an earlier run was interrupted)
+            }
+            let LegacyTargetKey(target, Collection(collection), RPath(path)) = _kr(&k)?;
+            let collection_id = self.get_or_create_collection_id(&mut batch, &collection)?;
+            let source_id = self.get_or_create_source_id(&mut batch, collection_id, &path)?;
+            batch.put_cf(
+                &self.db,
+                TARGET_IDS_CF,
+                _rk(&TargetKey(target, source_id)),
+                v.to_vec(),
+            );
+            batch.delete_cf(&self.db, TARGET_IDS_CF, k.to_vec());
+            targets += 1;
+            if batch.len() >= 10_000 {
+                self.write(std::mem::take(&mut batch))?;
+            }
+            if targets % 1_000_000 == 0 {
+                println!("rocks: gave sources to {targets} target keys so far...");
+            }
+        }
+        self.write(std::mem::take(&mut batch))?;
+
+        if let Some(legacy_links_cf) = legacy_links_cf {
+            for kv in db.iterator_cf(&legacy_links_cf, IteratorMode::Start) {
+                let (k, v) = kv?;
+                let LegacyRecordLinkKey(did_id, Collection(collection), rkey) = _kr(&k)?;
+                let LegacyRecordLinkTargets(legacy_targets) = _vr(&v)?;
+                let collection_id = self.get_or_create_collection_id(&mut batch, &collection)?;
+                let mut record_link_targets =
+                    RecordLinkTargets::with_capacity(legacy_targets.len());
+                for (RPath(path), target_id) in legacy_targets {
+                    let source_id =
+                        self.get_or_create_source_id(&mut batch, collection_id, &path)?;
+                    record_link_targets.add(RecordLinkTarget(source_id, target_id));
+                }
+                let record_link_key = RecordLinkKey(did_id, collection_id, rkey);
+                self.put_link_targets(&mut batch, &record_link_key, &record_link_targets);
+                records += 1;
+                if batch.len() >= 10_000 {
+                    self.write(std::mem::take(&mut batch))?;
+                }
+                if records % 1_000_000 == 0 {
+                    println!("rocks: moved {records} record links to source ids so far...");
+                }
+            }
+            self.write(std::mem::take(&mut batch))?;
+            drop(legacy_links_cf);
+            db.drop_cf(LEGACY_LINK_TARGETS_CF)?;
+        }
+        println!("rocks: gave sources to {targets} target keys and {records} record links");
+        Ok(())
+    }
+
+    /// split linkers from before chunking (one whole value per target) into chunks
+    ///
+    /// their counts are left for `count_target_linkers`.
+    fn chunk_target_linkers(&mut self) -> Result<()> {
+        let db = &self.db;
+        let linkers_cf = db.cf_handle(TARGET_LINKERS_CF).unwrap();
+        let mut targets = 0;
+        let mut batch = WriteBatch::default();
+        for kv in db.iterator_cf(&linkers_cf, IteratorMode::Start) {
+            let (k, v) = kv?;
+            let Ok(target_id) = _kr::<TargetId>(&k) else {
+                continue; // already chunked (an earlier run was interrupted)
+            };
+            let linkers = TargetLinkers::decode(&v)?;
+            for (n, chunk) in linkers.0.chunks(LINKERS_PER_CHUNK as usize).enumerate() {
+                let key = LinkerChunkKey(target_id.clone(), n as u64);
+                batch.put_cf(
+                    &linkers_cf,
+                    _rk(&key),
+                    TargetLinkers(chunk.to_vec()).encode(),
+                );
+            }
+            batch.delete_cf(&linkers_cf, &k);
+            targets += 1;
+            if batch.len() >= 10_000 {
+                db.write(std::mem::take(&mut batch))?;
+            }
+            if targets % 1_000_000 == 0 {
+                println!("rocks: split linkers for {targets} targets into chunks so far...");
+            }
+        }
+        db.write(batch)?;
+        println!("rocks: split linkers for {targets} targets into chunks");
+        Ok(())
+    }
+
+    /// rewrite a linker chunk if it's still in the old plain-bincode encoding
+    ///
+    /// both encodings can be read, so this can happen whenever.
+    fn compact_linker_chunk(&self, key: &[u8], value: &[u8], batch: &mut Batch) -> Result<()> {
+        if !TargetLinkers::is_compact(value) {
+            let compact = TargetLinkers::decode(value)?.encode();
+            batch.put_cf(&self.db, TARGET_LINKERS_CF, key.to_vec(), compact);
+        }
+        Ok(())
+    }
+
+    /// (re)build every target's counts from its linkers
+    fn count_target_linkers(&mut self) -> Result<()> {
+        let db = &self.db;
+        let linkers_cf = db.cf_handle(TARGET_LINKERS_CF).unwrap();
+        let counts_cf = db.cf_handle(TARGET_LINK_COUNTS_CF).unwrap();
+        let did_links_cf = db.cf_handle(TARGET_DID_LINKS_CF).unwrap();
+        let mut targets = 0;
+        let mut batch = WriteBatch::default();
+        // per-did counts left from before are rebuilt along with the rest
+        for kv in db.iterator_cf(&did_links_cf, IteratorMode::Start) {
+            let (k, _) = kv?;
+            batch.delete_cf(&did_links_cf, k);
+        }
+        // tombstones are only counted in `gone` once the collected ones are known
+        type CountedTarget = (TargetId, LinkerCounts, HashMap<DidId, DidLinkChunks>);
+        let mut put_counts = |batch: &mut WriteBatch, target: CountedTarget| {
+            let (target_id, mut counts, did_links) = target;
+            counts.gone += counts.collected;
+            counts.distinct = did_links.len() as i64;
+            batch.put_cf(&counts_cf, _rk(&target_id), _rv(&counts));
+            for (did_id, chunks) in did_links {
+                let key = TargetDidKey(target_id.clone(), did_id);
+                batch.put_cf(&did_links_cf, _rk(&key), _rv(&chunks));
+            }
+            targets += 1;
+        };
+        // chunks for a target are next to each other, in order
+        let mut current: Option<CountedTarget> = None;
+        for kv in db.iterator_cf(&linkers_cf, IteratorMode::Start) {
+            let (k, v) = kv?;
+            let LinkerChunkKey(target_id, n) = _kr(&k)?;
+            let TargetLinkers(chunk) = TargetLinkers::decode(&v)?;
+            match current {
+                Some((ref id, ..)) if *id == target_id => {}
+                _ => {
+                    let next = (target_id, LinkerCounts::default(), HashMap::new());
+                    if let Some(counted) = current.replace(next) {
+                        put_counts(&mut batch, counted);
+                    }
+                }
+            }
+            let (_, counts, did_links) = current.as_mut().unwrap();
+            // appends go to the chunk the total points into, so it only has to reach the end
+            // of the last one: anything collected out of that is left out
+            let stored = counts.total - counts.collected + chunk.len() as i64;
+            counts.total = (n * LINKERS_PER_CHUNK) as i64 + chunk.len() as i64;
+            counts.collected = counts.total - stored;
+            for (did_id, _) in chunk {
+                if did_id.is_empty() {
+                    counts.gone += 1;
+                } else {
+                    did_links.entry(did_id).or_default().add(n);
+                }
+            }
+            if batch.len() >= 10_000 {
+                db.write(std::mem::take(&mut batch))?;
+            }
+        }
+        if let Some(counted) = current {
+            put_counts(&mut batch, counted);
+        }
+        db.write(batch)?;
+        println!("rocks: counted linkers for {targets} targets");
+        Ok(())
+    }
+
+    /// share of the linkers in a target's chunks that can be tombstones before they're collected
+    ///
+    /// anything above 1 never is.
+    pub fn set_tombstone_ratio(&mut self, ratio: f64) {
+        self.tombstone_ratio = ratio;
     }
 
     pub fn start_backup(
@@ -413,7 +1022,27 @@ impl RocksStorage {
         describe_histogram!(
             "storage_rocksdb_delete_account_ops",
             Unit::Count,
-            "total batched ops for account deletions"
+            "batched ops for each step of removing a deleted account's links"
+        );
+        describe_gauge!(
+            "storage_rocksdb_tasks_queued",
+            Unit::Count,
+            "background tasks (like removing a deleted account's links) waiting or in progress"
+        );
+        describe_counter!(
+            "storage_rocksdb_tasks_done",
+            Unit::Count,
+            "background tasks finished"
+        );
+        describe_gauge!(
+            "storage_rocksdb_schema_version",
+            Unit::Count,
+            "migrations the db has had (online ones may still be running as tasks)"
+        );
+        describe_histogram!(
+            "storage_rocksdb_batch_events",
+            Unit::Count,
+            "events coalesced into each write from push_batch"
         );
     }
 
@@ -422,12 +1051,16 @@ impl RocksStorage {
         existing: Option<&[u8]>,
         operands: &MergeOperands,
     ) -> Option<Vec<u8>> {
-        let mut linkers: Vec<_> = if let Some(existing_bytes) = existing {
-            match _vr(existing_bytes) {
-                Ok(TargetLinkers(mut existing_linkers)) => {
-                    existing_linkers.reserve(operands.len());
-                    existing_linkers
-                }
+        Self::extend_did_ids(key, existing, &mut operands.iter())
+    }
+    fn extend_did_ids(
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &mut dyn Iterator<Item = &[u8]>,
+    ) -> Option<Vec<u8>> {
+        let mut chunk = if let Some(existing_bytes) = existing {
+            match LinkerChunk::decode(existing_bytes) {
+                Ok(chunk) => chunk,
                 Err(e) => {
                     eprintln!("bug? could not deserialize existing target linkers: {e:?}. key={key:?}. continuing, but data will be lost!");
                     if existing_bytes.len() < 1000 {
@@ -435,15 +1068,16 @@ impl RocksStorage {
                     } else {
                         eprintln!("(too long to print)");
                     }
-                    Vec::with_capacity(operands.len())
+                    LinkerChunk::default()
                 }
             }
         } else {
-            Vec::with_capacity(operands.len())
+            LinkerChunk::default()
         };
+        chunk.linkers.0.reserve(operands.size_hint().0);
         for new_linkers in operands {
-            match _vr(new_linkers) {
-                Ok(TargetLinkers(new_linkers)) => linkers.extend(new_linkers),
+            match TargetLinkers::decode(new_linkers) {
+                Ok(TargetLinkers(new_linkers)) => chunk.linkers.0.extend(new_linkers),
                 Err(e) => {
                     eprintln!("bug? could not deserialize new target linkers: {e:?}. key={key:?}. continuing, but data will be lost!");
                     if new_linkers.len() < 1000 {
@@ -454,7 +1088,31 @@ impl RocksStorage {
                 }
             }
         }
-        Some(_rv(&TargetLinkers(linkers)))
+        Some(chunk.encode())
+    }
+
+    fn merge_op_add_counts(
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &MergeOperands,
+    ) -> Option<Vec<u8>> {
+        Self::add_counts(key, existing, &mut operands.iter())
+    }
+    /// counts and their deltas are the same thing, so this works as a partial merge too
+    fn add_counts(
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &mut dyn Iterator<Item = &[u8]>,
+    ) -> Option<Vec<u8>> {
+        let mut counts = LinkerCounts::default();
+        let existing = existing.map(_vr::<LinkerCounts>).into_iter();
+        for delta in existing.chain(operands.map(_vr::<LinkerCounts>)) {
+            match delta {
+                Ok(delta) => counts.add(&delta),
+                Err(e) => eprintln!("bug? could not deserialize target link counts: {e:?}. key={key:?}. skipping it, counts will be off!"),
+            }
+        }
+        Some(_rv(&counts))
     }
 
     fn prefix_iter_cf<K, V, CF, P>(
@@ -476,107 +1134,299 @@ impl RocksStorage {
             .map_while(|(k, v)| Some((_kr(&k).ok()?, _vr(&v).ok()?)))
     }
 
-    fn update_did_id_value<F>(&self, batch: &mut WriteBatch, did: &Did, update: F) -> Result<bool>
+    fn update_did_id_value<F>(&self, batch: &mut Batch, did: &Did, update: F) -> Result<bool>
     where
         F: FnOnce(DidIdValue) -> Option<DidIdValue>,
     {
-        let cf = self.db.cf_handle(DID_IDS_CF).unwrap();
-        let Some(did_id_value) = self.did_id_table.get_id_val(&self.db, did)? else {
+        let Some(did_id_value) = batch.get_value(&self.db, DID_IDS_CF, &_rk(did))? else {
             return Ok(false);
         };
         let Some(new_did_id_value) = update(did_id_value) else {
             return Ok(false);
         };
-        batch.put_cf(&cf, _rk(did), _rv(&new_did_id_value));
+        self.did_id_table
+            .put_id_val(&self.db, batch, did, &new_did_id_value);
         Ok(true)
     }
-    fn delete_did_id_value(&self, batch: &mut WriteBatch, did: &Did) {
-        let cf = self.db.cf_handle(DID_IDS_CF).unwrap();
-        batch.delete_cf(&cf, _rk(did));
+    fn delete_did_id_value(&self, batch: &mut Batch, did: &Did) {
+        batch.delete_cf(&self.db, DID_IDS_CF, _rk(did));
     }
 
-    fn get_target_linkers(&self, target_id: &TargetId) -> Result<TargetLinkers> {
-        let cf = self.db.cf_handle(TARGET_LINKERS_CF).unwrap();
-        let Some(linkers_bytes) = self.db.get_cf(&cf, _rk(target_id))? else {
-            return Ok(TargetLinkers::default());
+    fn get_linker_counts(&self, batch: &Batch, target_id: &TargetId) -> Result<LinkerCounts> {
+        Ok(batch
+            .get_value(&self.db, TARGET_LINK_COUNTS_CF, &_rk(target_id))?
+            .unwrap_or_default())
+    }
+    fn merge_linker_counts(&self, batch: &mut Batch, target_id: &TargetId, delta: &LinkerCounts) {
+        batch.merge_cf(
+            &self.db,
+            TARGET_LINK_COUNTS_CF,
+            _rk(target_id),
+            _rv(delta),
+            Self::add_counts,
+        );
+    }
+    /// which chunks this did's links to the target are in, also counting any already in the batch
+    fn get_did_links(&self, batch: &Batch, key: &TargetDidKey) -> Result<DidLinkChunks> {
+        Ok(batch
+            .get_value(&self.db, TARGET_DID_LINKS_CF, &_rk(key))?
+            .unwrap_or_default())
+    }
+    /// a target's chunks, newest first, from the one `until` points into back, each with its
+    /// number and how many of its slots come before `until`
+    ///
+    /// chunks that a collection emptied are gone, so they're skipped without a read.
+    fn linker_chunks_back(
+        &self,
+        target_id: &TargetId,
+        until: Option<u64>,
+    ) -> impl Iterator<Item = Result<(u64, LinkerChunk, usize)>> + use<'_> {
+        // (the chunk to start from, and the cursor's generation and slot if it's partway in)
+        let start = match until.map(split_linker_cursor) {
+            None => (u64::MAX, None),
+            Some((_, n, 0)) if n > 0 => (n - 1, None),
+            Some((generation, n, slot)) => (n, Some((generation, slot))),
         };
-        _vr(&linkers_bytes)
+        let cf = self.db.cf_handle(TARGET_LINKERS_CF).unwrap();
+        let mut read_opts = ReadOptions::default();
+        read_opts.set_iterate_range(PrefixRange(_rk(target_id)));
+        let from = _rk(&LinkerChunkKey(target_id.clone(), start.0));
+        let chunks = self.db.iterator_cf_opt(
+            &cf,
+            read_opts,
+            IteratorMode::From(&from, Direction::Reverse),
+        );
+        chunks.map(move |kv| {
+            let (k, v) = kv?;
+            let LinkerChunkKey(_, n) = _kr(&k)?;
+            let chunk = LinkerChunk::decode(&v)?;
+            #[cfg(test)]
+            CHUNK_READS.with(|reads| reads.set(reads.get() + 1));
+            let end = match start {
+                (from, Some((generation, slot))) if from == n => chunk.map_slot(generation, slot),
+                _ => chunk.linkers.0.len() as u64,
+            };
+            Ok((n, chunk, end as usize))
+        })
+    }
+    #[cfg(test)]
+    fn get_target_linkers(&self, target_id: &TargetId) -> Result<TargetLinkers> {
+        let mut chunks = self
+            .linker_chunks_back(target_id, None)
+            .collect::<Result<Vec<_>>>()?;
+        chunks.reverse();
+        Ok(TargetLinkers(
+            chunks
+                .into_iter()
+                .flat_map(|(_, chunk, _)| chunk.linkers.0)
+                .collect(),
+        ))
     }
-    /// zero out every duplicate did. bit of a hack, looks the same as deleted, but eh
-    fn get_distinct_target_linkers(&self, target_id: &TargetId) -> Result<TargetLinkers> {
+    /// the slots in a chunk's first linkers that have a did's first link to the target, in order
+    ///
+    /// each did's links are kept by chunk, so that's known without reading any other chunk: its
+    /// first link is the first one in the first chunk it has links in.
+    fn first_links(
+        &self,
+        target_id: &TargetId,
+        chunk: u64,
+        linkers: &[(DidId, RKey)],
+    ) -> Result<Vec<(usize, DidId)>> {
         let mut seen = HashSet::new();
-        let mut linkers = self.get_target_linkers(target_id)?;
-        for (did_id, _) in linkers.0.iter_mut() {
-            if seen.contains(did_id) {
-                did_id.0 = 0;
-            } else {
-                seen.insert(*did_id);
+        let firsts: Vec<_> = linkers
+            .iter()
+            .enumerate()
+            .filter(|(_, (did_id, _))| !did_id.is_empty() && seen.insert(*did_id))
+            .map(|(slot, (did_id, _))| (slot, *did_id))
+            .collect();
+        let cf = self.db.cf_handle(TARGET_DID_LINKS_CF).unwrap();
+        let keys: Vec<_> = firsts
+            .iter()
+            .map(|(_, did_id)| _rk(&TargetDidKey(target_id.clone(), *did_id)))
+            .collect();
+        let mut first_links = Vec::with_capacity(firsts.len());
+        for (first, did_links) in firsts
+            .into_iter()
+            .zip(self.db.batched_multi_get_cf(&cf, &keys, false))
+        {
+            let did_links: Option<DidLinkChunks> = did_links?.map(|b| _vr(&b)).transpose()?;
+            if did_links.and_then(|links| links.first()) == Some(chunk) {
+                first_links.push(first);
             }
         }
-        Ok(linkers)
+        Ok(first_links)
     }
-    fn merge_target_linker(
+    fn append_target_linker(
         &self,
-        batch: &mut WriteBatch,
+        batch: &mut Batch,
         target_id: &TargetId,
         linker_did_id: &DidId,
         linker_rkey: &RKey,
-    ) {
-        let cf = self.db.cf_handle(TARGET_LINKERS_CF).unwrap();
+    ) -> Result<()> {
+        let position = self.get_linker_counts(batch, target_id)?.total as u64;
+        let n = position / LINKERS_PER_CHUNK;
         batch.merge_cf(
-            &cf,
-            _rk(target_id),
-            _rv(&TargetLinkers(vec![(*linker_did_id, linker_rkey.clone())])),
+            &self.db,
+            TARGET_LINKERS_CF,
+            _rk(&LinkerChunkKey(target_id.clone(), n)),
+            TargetLinkers(vec![(*linker_did_id, linker_rkey.clone())]).encode(),
+            Self::extend_did_ids,
         );
+        let did_key = TargetDidKey(target_id.clone(), *linker_did_id);
+        let mut did_links = self.get_did_links(batch, &did_key)?;
+        let first_from_did = did_links.links() == 0;
+        did_links.add(n);
+        batch.put_cf(
+            &self.db,
+            TARGET_DID_LINKS_CF,
+            _rk(&did_key),
+            _rv(&did_links),
+        );
+        let delta = LinkerCounts {
+            total: 1,
+            distinct: first_from_did.into(),
+            ..Default::default()
+        };
+        self.merge_linker_counts(batch, target_id, &delta);
+        Ok(())
+    }
+    /// tombstone the most recent matching linker. false if there wasn't one.
+    ///
+    /// once the target has enough tombstones, a background task collects them out of its chunks.
+    fn remove_target_linker(
+        &mut self,
+        batch: &mut Batch,
+        target_id: &TargetId,
+        linker_did_id: &DidId,
+        linker_rkey: &RKey,
+    ) -> Result<bool> {
+        let counts = self.get_linker_counts(batch, target_id)?;
+        let total = counts.total as u64;
+        // removals are usually of recent links, so search from the newest chunk back
+        for n in (0..total.div_ceil(LINKERS_PER_CHUNK)).rev() {
+            let key = LinkerChunkKey(target_id.clone(), n);
+            let Some(bytes) = batch.get_cf(&self.db, TARGET_LINKERS_CF, &_rk(&key))? else {
+                continue;
+            };
+            let mut chunk = LinkerChunk::decode(&bytes)?;
+            if !chunk.linkers.remove_linker(linker_did_id, linker_rkey) {
+                continue;
+            }
+            batch.put_cf(&self.db, TARGET_LINKERS_CF, _rk(&key), chunk.encode());
+            let was_over = self.over_tombstone_ratio(&counts);
+            let over = self.over_tombstone_ratio(&LinkerCounts {
+                gone: counts.gone + 1,
+                ..counts
+            });
+            if over && !was_over {
+                self.queue_task(batch, &Task::CollectTombstones(target_id.clone(), 0));
+            }
+
+            let did_key = TargetDidKey(target_id.clone(), *linker_did_id);
+            let mut did_links = self.get_did_links(batch, &did_key)?;
+            did_links.remove(n);
+            let last_from_did = did_links.links() == 0;
+            if last_from_did {
+                batch.delete_cf(&self.db, TARGET_DID_LINKS_CF, _rk(&did_key));
+            } else {
+                batch.put_cf(
+                    &self.db,
+                    TARGET_DID_LINKS_CF,
+                    _rk(&did_key),
+                    _rv(&did_links),
+                );
+            }
+            let delta = LinkerCounts {
+                gone: 1,
+                distinct: -i64::from(last_from_did),
+                ..Default::default()
+            };
+            self.merge_linker_counts(batch, target_id, &delta);
+            return Ok(true);
+        }
+        Ok(false)
     }
-    fn update_target_linkers<F>(
+
+    /// whether enough of what a target's chunks hold is tombstones to collect them
+    fn over_tombstone_ratio(&self, counts: &LinkerCounts) -> bool {
+        let (total, gone) = counts.stored();
+        gone as i64 >= MIN_COLLECTED_TOMBSTONES
+            && gone as f64 >= total as f64 * self.tombstone_ratio
+    }
+    /// collect the tombstones out of up to `limit` of a target's chunks from `from` on,
+    /// returning the chunk to carry on from (if there are more) and how many it looked at
+    ///
+    /// emptied chunks are deleted, except the one appends still go to.
+    fn collect_some_tombstones(
         &self,
-        batch: &mut WriteBatch,
         target_id: &TargetId,
-        update: F,
-    ) -> Result<bool>
-    where
-        F: FnOnce(TargetLinkers) -> Option<TargetLinkers>,
-    {
+        from: u64,
+        limit: usize,
+        batch: &mut Batch,
+    ) -> Result<(Option<u64>, usize)> {
+        let appending = self.get_linker_counts(batch, target_id)?.total as u64 / LINKERS_PER_CHUNK;
         let cf = self.db.cf_handle(TARGET_LINKERS_CF).unwrap();
-        let existing_linkers = self.get_target_linkers(target_id)?;
-        let Some(new_linkers) = update(existing_linkers) else {
-            return Ok(false);
-        };
-        batch.put_cf(&cf, _rk(target_id), _rv(&new_linkers));
-        Ok(true)
+        let mut read_opts = ReadOptions::default();
+        read_opts.set_iterate_range(PrefixRange(_rk(target_id)));
+        let from_key = _rk(&LinkerChunkKey(target_id.clone(), from));
+        let chunks = self.db.iterator_cf_opt(
+            &cf,
+            read_opts,
+            IteratorMode::From(&from_key, Direction::Forward),
+        );
+        let (mut next, mut looked_at, mut collected) = (from, 0, 0);
+        for kv in chunks.take(limit) {
+            let (k, v) = kv?;
+            let LinkerChunkKey(_, n) = _kr(&k)?;
+            (next, looked_at) = (n + 1, looked_at + 1);
+            let mut chunk = LinkerChunk::decode(&v)?;
+            let removed = chunk.collect_tombstones();
+            if removed == 0 {
+                continue;
+            }
+            collected += removed as i64;
+            if chunk.linkers.0.is_empty() && n < appending {
+                batch.delete_cf(&self.db, TARGET_LINKERS_CF, k.to_vec());
+            } else {
+                batch.put_cf(&self.db, TARGET_LINKERS_CF, k.to_vec(), chunk.encode());
+            }
+        }
+        if collected > 0 {
+            let delta = LinkerCounts {
+                collected,
+                ..Default::default()
+            };
+            self.merge_linker_counts(batch, target_id, &delta);
+        }
+        Ok(((looked_at == limit).then_some(next), looked_at))
     }
 
     fn put_link_targets(
         &self,
-        batch: &mut WriteBatch,
+        batch: &mut Batch,
         record_link_key: &RecordLinkKey,
         targets: &RecordLinkTargets,
     ) {
-        // todo: we are almost idempotent to link creates with this blind write, but we'll still
-        // merge in the reverse index. we could read+modify+write here but it'll be SLOWWWWW on
-        // the path that we need to be fast. we could go back to a merge op and probably be
-        // consistent. or we can accept just a littttttle inconsistency and be idempotent on
-        // forward links but not reverse, slightly messing up deletes :/
-        // _maybe_ we could run in slow idempotent r-m-w mode during firehose catch-up at the start,
-        // then switch to the fast version?
-        let cf = self.db.cf_handle(LINK_TARGETS_CF).unwrap();
-        batch.put_cf(&cf, _rk(record_link_key), _rv(targets));
+        // we are almost idempotent to link creates with this blind write, but we'll still merge
+        // in the reverse index. checking first is a read on the path that we need to be fast, so
+        // add_links only does it in idempotent mode, which the consumer turns on while it might
+        // be seeing events again (catching up after a restart or reconnect).
+        batch.put_cf(
+            &self.db,
+            LINK_TARGETS_CF,
+            _rk(record_link_key),
+            _rv(targets),
+        );
     }
     fn get_record_link_targets(
         &self,
+        batch: &Batch,
         record_link_key: &RecordLinkKey,
     ) -> Result<Option<RecordLinkTargets>> {
-        let cf = self.db.cf_handle(LINK_TARGETS_CF).unwrap();
-        if let Some(bytes) = self.db.get_cf(&cf, _rk(record_link_key))? {
-            Ok(Some(_vr(&bytes)?))
-        } else {
-            Ok(None)
-        }
+        batch.get_value(&self.db, LINK_TARGETS_CF, &_rk(record_link_key))
     }
-    fn delete_record_link(&self, batch: &mut WriteBatch, record_link_key: &RecordLinkKey) {
-        let cf = self.db.cf_handle(LINK_TARGETS_CF).unwrap();
-        batch.delete_cf(&cf, _rk(record_link_key));
+    fn delete_record_link(&self, batch: &mut Batch, record_link_key: &RecordLinkKey) {
+        batch.delete_cf(&self.db, LINK_TARGETS_CF, _rk(record_link_key));
     }
     fn iter_links_for_did_id(
         &self,
@@ -593,123 +1443,536 @@ impl RocksStorage {
         self.prefix_iter_cf(&cf, TargetIdTargetPrefix(target.clone()))
     }
 
-    //
-    // higher-level event action handlers
-    //
-
-    fn add_links(
+    fn get_or_create_collection_id(
+        &mut self,
+        batch: &mut Batch,
+        collection: &str,
+    ) -> Result<CollectionId> {
+        self.collection_id_table.get_or_create_id_val(
+            &self.db,
+            batch,
+            &Collection(collection.to_string()),
+        )
+    }
+    fn get_or_create_source_id(
+        &mut self,
+        batch: &mut Batch,
+        collection_id: CollectionId,
+        path: &str,
+    ) -> Result<SourceId> {
+        self.source_id_table.get_or_create_id_val(
+            &self.db,
+            batch,
+            &SourceKey(collection_id, RPath(path.to_string())),
+        )
+    }
+    fn get_target_id(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+    ) -> Result<Option<TargetId>> {
+        let Some(collection_id) = self
+            .collection_id_table
+            .get_id_val(&self.db, &Collection(collection.to_string()))?
+        else {
+            return Ok(None);
+        };
+        let Some(source_id) = self
+            .source_id_table
+            .get_id_val(&self.db, &SourceKey(collection_id, RPath(path.to_string())))?
+        else {
+            return Ok(None);
+        };
+        self.target_id_table
+            .get_id_val(&self.db, &TargetKey(Target(target.to_string()), source_id))
+    }
+    /// the collection and path a source id stands for
+    pub fn get_source(&self, source_id: &SourceId) -> Result<Option<(Collection, RPath)>> {
+        let Some(SourceKey(collection_id, path)) = self
+            .source_id_table
+            .get_val_from_id(&self.db, source_id.0)?
+        else {
+            return Ok(None);
+        };
+        let Some(collection) = self
+            .collection_id_table
+            .get_val_from_id(&self.db, collection_id.0)?
+        else {
+            return Ok(None);
+        };
+        Ok(Some((collection, path)))
+    }
+
+    fn get_handle_history_value(&self, batch: &Batch, did: &Did) -> Result<HandleHistory> {
+        Ok(batch
+            .get_value(&self.db, DID_HANDLES_CF, &_rk(did))?
+            .unwrap_or_default())
+    }
+    fn get_handle_did(&self, batch: &Batch, handle: &Handle) -> Result<Option<Did>> {
+        batch.get_value(&self.db, HANDLE_DIDS_CF, &_rk(handle))
+    }
+
+    //
+    // higher-level event action handlers
+    //
+
+    /// the did id and record key for a record, with ids for everything it links to
+    fn record_link_targets(
         &mut self,
         record_id: &RecordId,
         links: &[CollectedLink],
-        batch: &mut WriteBatch,
-    ) -> Result<()> {
+        batch: &mut Batch,
+    ) -> Result<(DidId, RecordLinkKey, RecordLinkTargets)> {
         let DidIdValue(did_id, _) =
             self.did_id_table
                 .get_or_create_id_val(&self.db, batch, &record_id.did)?;
 
-        let record_link_key = RecordLinkKey(
-            did_id,
-            Collection(record_id.collection()),
-            RKey(record_id.rkey()),
-        );
+        let collection_id = self.get_or_create_collection_id(batch, &record_id.collection)?;
+        let record_link_key = RecordLinkKey(did_id, collection_id, RKey(record_id.rkey()));
         let mut record_link_targets = RecordLinkTargets::with_capacity(links.len());
 
         for CollectedLink { target, path } in links {
-            let target_key = TargetKey(
-                Target(target.clone().into_string()),
-                Collection(record_id.collection()),
-                RPath(path.clone()),
-            );
+            let source_id = self.get_or_create_source_id(batch, collection_id, path)?;
+            let target_key = TargetKey(Target(target.clone().into_string()), source_id);
             let target_id =
                 self.target_id_table
                     .get_or_create_id_val(&self.db, batch, &target_key)?;
-            self.merge_target_linker(batch, &target_id, &did_id, &RKey(record_id.rkey()));
+            record_link_targets.add(RecordLinkTarget(source_id, target_id))
+        }
+        Ok((did_id, record_link_key, record_link_targets))
+    }
+
+    fn add_links(
+        &mut self,
+        record_id: &RecordId,
+        links: &[CollectedLink],
+        batch: &mut Batch,
+    ) -> Result<()> {
+        if self.idempotent {
+            // a record we've seen already is unchanged, and a different version replaces it
+            return self.update_links(record_id, links, batch);
+        }
+        let (did_id, record_link_key, record_link_targets) =
+            self.record_link_targets(record_id, links, batch)?;
+        for RecordLinkTarget(_, target_id) in &record_link_targets.0 {
+            self.append_target_linker(batch, target_id, &did_id, &RKey(record_id.rkey()))?;
+        }
+        self.put_link_targets(batch, &record_link_key, &record_link_targets);
+        Ok(())
+    }
 
-            record_link_targets.add(RecordLinkTarget(RPath(path.clone()), target_id))
+    /// replace a record's links, only touching the ones that changed
+    ///
+    /// links that are still there keep their place in their targets' linkers, instead of moving
+    /// to the end and leaving a tombstone behind.
+    fn update_links(
+        &mut self,
+        record_id: &RecordId,
+        links: &[CollectedLink],
+        batch: &mut Batch,
+    ) -> Result<()> {
+        if links.is_empty() {
+            return self.remove_links(record_id, batch);
+        }
+        let (did_id, record_link_key, record_link_targets) =
+            self.record_link_targets(record_id, links, batch)?;
+        let existing = self
+            .get_record_link_targets(batch, &record_link_key)?
+            .unwrap_or_default();
+        if existing == record_link_targets {
+            return Ok(()); // nothing changed
         }
 
+        let rkey = RKey(record_id.rkey());
+        let mut added: Vec<_> = record_link_targets.0.iter().collect();
+        for old in &existing.0 {
+            if let Some(i) = added.iter().position(|new| *new == old) {
+                added.remove(i); // still there
+                continue;
+            }
+            if !self.remove_target_linker(batch, &old.1, &did_id, &rkey)? {
+                eprintln!("bug? linked target was missing a link when updating links");
+            }
+        }
+        for RecordLinkTarget(_, target_id) in added {
+            self.append_target_linker(batch, target_id, &did_id, &rkey)?;
+        }
         self.put_link_targets(batch, &record_link_key, &record_link_targets);
         Ok(())
     }
 
-    fn remove_links(&mut self, record_id: &RecordId, batch: &mut WriteBatch) -> Result<()> {
+    fn remove_links(&mut self, record_id: &RecordId, batch: &mut Batch) -> Result<()> {
         let Some(DidIdValue(linking_did_id, _)) =
-            self.did_id_table.get_id_val(&self.db, &record_id.did)?
+            batch.get_value(&self.db, DID_IDS_CF, &_rk(&record_id.did))?
         else {
             return Ok(()); // we don't know her: nothing to do
         };
 
-        let record_link_key = RecordLinkKey(
-            linking_did_id,
-            Collection(record_id.collection()),
-            RKey(record_id.rkey()),
-        );
-        let Some(record_link_targets) = self.get_record_link_targets(&record_link_key)? else {
+        let Some(collection_id) = batch.get_value(
+            &self.db,
+            COLLECTION_IDS_CF,
+            &_rk(&Collection(record_id.collection())),
+        )?
+        else {
+            return Ok(()); // nothing has ever linked from this collection
+        };
+        let record_link_key = RecordLinkKey(linking_did_id, collection_id, RKey(record_id.rkey()));
+        let Some(record_link_targets) = self.get_record_link_targets(batch, &record_link_key)?
+        else {
             return Ok(()); // we don't have these links
         };
 
         // we do read -> modify -> write here: could merge-op in the deletes instead?
         // otherwise it's another single-thread-constraining thing.
         for RecordLinkTarget(_, target_id) in record_link_targets.0 {
-            self.update_target_linkers(batch, &target_id, |mut linkers| {
-                if linkers.0.is_empty() {
-                    eprintln!("bug? linked target was missing when removing links");
-                }
-                if !linkers.remove_linker(&linking_did_id, &RKey(record_id.rkey.clone())) {
-                    eprintln!("bug? linked target was missing a link when removing links");
-                }
-                Some(linkers)
-            })?;
+            let rkey = RKey(record_id.rkey.clone());
+            if !self.remove_target_linker(batch, &target_id, &linking_did_id, &rkey)? {
+                eprintln!("bug? linked target was missing a link when removing links");
+            }
         }
 
         self.delete_record_link(batch, &record_link_key);
         Ok(())
     }
 
-    fn set_account(&mut self, did: &Did, active: bool, batch: &mut WriteBatch) -> Result<()> {
+    fn set_account(&mut self, did: &Did, active: bool, batch: &mut Batch) -> Result<()> {
         // this needs to be read-modify-write since the did_id needs to stay the same,
         // which has a benefit of allowing to avoid adding entries for dids we don't
         // need. reading on dids needs to be cheap anyway for the current design, and
         // did active/inactive updates are low-freq in the firehose so, eh, it's fine.
-        self.update_did_id_value(batch, did, |current_value| {
-            Some(DidIdValue(current_value.did_id(), active))
+        self.update_did_id_value(batch, did, |DidIdValue(did_id, flags)| {
+            Some(DidIdValue(did_id, flags.with(AccountFlags::ACTIVE, active)))
+        })?;
+        Ok(())
+    }
+
+    fn set_private(&mut self, did: &Did, private: bool, batch: &mut Batch) -> Result<()> {
+        if private {
+            // they might not have any links yet, but they should stay hidden once they do
+            self.did_id_table
+                .get_or_create_id_val(&self.db, batch, did)?;
+        }
+        self.update_did_id_value(batch, did, |DidIdValue(did_id, flags)| {
+            (flags.private() != private)
+                .then(|| DidIdValue(did_id, flags.with(AccountFlags::PRIVATE, private)))
         })?;
         Ok(())
     }
 
-    fn delete_account(&mut self, did: &Did, batch: &mut WriteBatch) -> Result<usize> {
-        let mut total_batched_ops = 0;
-        let Some(DidIdValue(did_id, _)) = self.did_id_table.get_id_val(&self.db, did)? else {
-            return Ok(total_batched_ops); // ignore updates for dids we don't know about
+    /// whether to list this account's links
+    fn shown(&self, DidIdValue(_, flags): &DidIdValue) -> bool {
+        flags.active() && (self.show_private || !flags.private())
+    }
+
+    /// look up a page of linking dids together, leaving out any that shouldn't be listed
+    fn get_shown_dids(&self, did_ids: &[DidId]) -> Result<Vec<Option<Did>>> {
+        let entries = self
+            .did_id_table
+            .multi_get_from_ids(&self.db, did_ids.iter().map(|did_id| did_id.0))?;
+        // reverse entries from before they held the flags need the forward entry too
+        let unflagged = entries.iter().filter_map(|entry| match entry {
+            Some((did, None)) => Some(did),
+            _ => None,
+        });
+        let mut unflagged_values = self
+            .did_id_table
+            .multi_get_id_vals(&self.db, unflagged)?
+            .into_iter();
+        let mut dids = Vec::with_capacity(did_ids.len());
+        for (entry, did_id) in entries.into_iter().zip(did_ids) {
+            let (did, did_value) = match entry {
+                Some((did, Some(did_value))) => (did, did_value),
+                Some((did, None)) => {
+                    let Some(did_value) = unflagged_values.next().flatten() else {
+                        eprintln!("failed to look up did_value from did_id {did_id:?}: {did:?}: data consistency bug?");
+                        dids.push(None);
+                        continue;
+                    };
+                    (did, did_value)
+                }
+                None => {
+                    eprintln!("failed to look up did from did_id {did_id:?}");
+                    dids.push(None);
+                    continue;
+                }
+            };
+            dids.push(self.shown(&did_value).then_some(did));
+        }
+        Ok(dids)
+    }
+    /// the did for an id, if it's still around
+    pub fn get_did(&self, did_id: &DidId) -> Result<Option<Did>> {
+        self.did_id_table.get_val_from_id(&self.db, did_id.0)
+    }
+
+    fn set_handle(
+        &mut self,
+        did: &Did,
+        handle: &Option<String>,
+        cursor: u64,
+        batch: &mut Batch,
+    ) -> Result<()> {
+        let mut history = self.get_handle_history_value(batch, did)?;
+        let previous = history.current();
+        if !history.0.is_empty() && previous == *handle {
+            return Ok(()); // identity events also fire for other changes, like pds migrations
+        }
+        if let Some(previous) = previous.map(Handle) {
+            // someone else may have claimed it since
+            if self.get_handle_did(batch, &previous)?.as_ref() == Some(did) {
+                batch.delete_cf(&self.db, HANDLE_DIDS_CF, _rk(&previous));
+            }
+        }
+        if let Some(handle) = handle {
+            batch.put_cf(
+                &self.db,
+                HANDLE_DIDS_CF,
+                _rk(&Handle(handle.clone())),
+                _rv(did),
+            );
+        }
+        history.0.push(HandleEntry {
+            handle: handle.clone(),
+            since: cursor,
+        });
+        batch.put_cf(&self.db, DID_HANDLES_CF, _rk(did), _rv(&history));
+        Ok(())
+    }
+
+    /// forget a did's handle history, and its current handle if it still has the claim
+    fn delete_handles(&self, did: &Did, batch: &mut Batch) -> Result<()> {
+        let history = self.get_handle_history_value(batch, did)?;
+        if let Some(current) = history.current().map(Handle) {
+            if self.get_handle_did(batch, &current)?.as_ref() == Some(did) {
+                batch.delete_cf(&self.db, HANDLE_DIDS_CF, _rk(&current));
+            }
+        }
+        batch.delete_cf(&self.db, DID_HANDLES_CF, _rk(did));
+        Ok(())
+    }
+
+    /// add one event's changes to the batch, returning the action name for metrics
+    fn apply(
+        &mut self,
+        event: &ActionableEvent,
+        cursor: u64,
+        batch: &mut Batch,
+    ) -> Result<Option<&'static str>> {
+        Ok(match event {
+            ActionableEvent::CreateLinks { record_id, links } => {
+                self.add_links(record_id, links, batch)?;
+                Some("create_links")
+            }
+            ActionableEvent::UpdateLinks {
+                record_id,
+                new_links,
+            } => {
+                self.update_links(record_id, new_links, batch)?;
+                Some("update_links")
+            }
+            ActionableEvent::UpdateProfile {
+                record_id,
+                new_links,
+                private,
+            } => {
+                self.update_links(record_id, new_links, batch)?;
+                self.set_private(&record_id.did, *private, batch)?;
+                Some("update_profile")
+            }
+            ActionableEvent::DeleteRecord(record_id) => {
+                self.remove_links(record_id, batch)?;
+                Some("delete_record")
+            }
+            ActionableEvent::ActivateAccount(did) => {
+                self.set_account(did, true, batch)?;
+                Some("set_account_status")
+            }
+            ActionableEvent::DeactivateAccount(did) => {
+                self.set_account(did, false, batch)?;
+                Some("set_account_status")
+            }
+            ActionableEvent::Identity { did, handle } => {
+                self.set_handle(did, handle, cursor, batch)?;
+                Some("identity")
+            }
+            ActionableEvent::DeleteAccount(did) => {
+                self.delete_account(did, batch)?;
+                Some("delete_account")
+            }
+        })
+    }
+
+    /// write out a coalesced batch of events, advancing the cursor to the last one
+    fn write_batch(&mut self, mut batch: Batch, cursor: Option<u64>, t0: Instant) -> Result<()> {
+        let Some(cursor) = cursor else {
+            return Ok(()); // nothing in it
+        };
+        let t_read = t0.elapsed();
+        batch.put(JETSTREAM_CURSOR_KEY.as_bytes(), _rv(cursor));
+        let events = batch.events;
+        self.write(batch)?;
+
+        histogram!("storage_rocksdb_read_seconds", "action" => "batch")
+            .record(t_read.as_secs_f64());
+        histogram!("storage_rocksdb_action_seconds", "action" => "batch")
+            .record(t0.elapsed().as_secs_f64());
+        histogram!("storage_rocksdb_batch_events").record(events as f64);
+        Ok(())
+    }
+
+    /// forget the did and hide its links right away, leaving their removal to a background task
+    ///
+    /// the did gets a new id if it comes back, so the task can't touch anything newer. the
+    /// reverse entry goes when the task is done, since reads still need it to hide the links.
+    fn delete_account(&mut self, did: &Did, batch: &mut Batch) -> Result<()> {
+        self.delete_handles(did, batch)?; // we can know a did's handles without any links
+        let Some(DidIdValue(did_id, flags)) = batch.get_value(&self.db, DID_IDS_CF, &_rk(did))?
+        else {
+            return Ok(()); // ignore updates for dids we don't know about
         };
         self.delete_did_id_value(batch, did);
-        // TODO: also delete the reverse!!
-
-        // use a separate batch for all their links, since it can be a lot and make us crash at around 1GiB batch size.
-        // this should still hopefully be crash-safe: as long as we don't actually delete the DidId entry until after all links are cleared.
-        // the above .delete_did_id_value is batched, so it shouldn't be written until we've returned from this fn successfully
-        // TODO: queue a background delete task or whatever
-        // TODO: test delete account with more links than chunk size
-        let stuff: Vec<_> = self.iter_links_for_did_id(&did_id).collect();
-        for chunk in stuff.chunks(1024) {
-            let mut mini_batch = WriteBatch::default();
-
-            for (record_link_key, links) in chunk {
-                self.delete_record_link(&mut mini_batch, record_link_key); // _could_ use delete range here instead of individual deletes, but since we have to scan anyway it's not obvious if it's better
-
-                for RecordLinkTarget(_, target_link_id) in links.0.iter() {
-                    self.update_target_linkers(&mut mini_batch, target_link_id, |mut linkers| {
-                        if !linkers.remove_linker(&did_id, &record_link_key.2) {
-                            eprintln!("bug? could not find linker when removing links while deleting an account");
+        // reads go through the reverse entry, so inactive there is hidden
+        let hidden = DidIdValue(did_id, flags.with(AccountFlags::ACTIVE, false));
+        self.did_id_table.put_reverse(&self.db, batch, did, &hidden);
+        self.queue_task(batch, &Task::DeleteAccountLinks(did_id));
+        Ok(())
+    }
+
+    /// unlink up to `limit` of a deleted account's records, returning how many, and whether that
+    /// was all of them
+    fn delete_some_account_links(
+        &mut self,
+        did_id: &DidId,
+        limit: usize,
+        batch: &mut Batch,
+    ) -> Result<(usize, bool)> {
+        let records: Vec<_> = self.iter_links_for_did_id(did_id).take(limit).collect();
+        for (record_link_key, links) in &records {
+            self.delete_record_link(batch, record_link_key); // _could_ use delete range here instead of individual deletes, but since we have to scan anyway it's not obvious if it's better
+
+            for RecordLinkTarget(_, target_link_id) in links.0.iter() {
+                if !self.remove_target_linker(batch, target_link_id, did_id, &record_link_key.2)? {
+                    eprintln!(
+                        "bug? could not find linker when removing links while deleting an account"
+                    );
+                }
+            }
+        }
+        Ok((records.len(), records.len() < limit))
+    }
+
+    //
+    // background tasks
+    //
+
+    /// the id for the next task, and how many are queued
+    fn count_tasks(db: &DBWithThreadMode<MultiThreaded>) -> Result<(u64, u64)> {
+        let cf = db.cf_handle(TASKS_CF).unwrap();
+        let next_task_id = match db.iterator_cf(&cf, IteratorMode::End).next() {
+            Some(kv) => _kr::<TaskKey>(&kv?.0)?.0 + 1,
+            None => 0,
+        };
+        let queued = db.iterator_cf(&cf, IteratorMode::Start).count() as u64;
+        Ok((next_task_id, queued))
+    }
+    /// tasks only count as queued once their batch is written (see `write`)
+    fn queue_task(&self, batch: &mut Batch, task: &Task) {
+        let key = TaskKey(self.next_task_id + batch.tasks);
+        batch.put_cf(&self.db, TASKS_CF, _rk(&key), _rv(task));
+        batch.tasks += 1;
+    }
+    /// write a batch, and only then take the ids and tasks it allocated
+    ///
+    /// if the write fails, they're allocated again the next time.
+    fn write(&mut self, batch: Batch) -> Result<()> {
+        let Batch {
+            batch, ids, tasks, ..
+        } = batch;
+        self.db.write(batch)?;
+        self.did_id_table.commit(&ids);
+        self.collection_id_table.commit(&ids);
+        self.source_id_table.commit(&ids);
+        self.target_id_table.commit(&ids);
+        self.next_task_id += tasks;
+        self.queued_tasks += tasks;
+        Ok(())
+    }
+    /// a round of steps on the queued tasks, oldest first, if there are any
+    ///
+    /// each step is written on its own, so tasks pick up where they left off after a restart.
+    fn work_on_tasks(&mut self) -> Result<()> {
+        let mut budget = TASK_WORK_PER_ROUND;
+        while budget > 0 && self.queued_tasks > 0 {
+            let next = {
+                let cf = self.db.cf_handle(TASKS_CF).unwrap();
+                self.db.iterator_cf(&cf, IteratorMode::Start).next()
+            };
+            let Some(kv) = next else {
+                self.queued_tasks = 0;
+                break;
+            };
+            let (k, v) = kv?;
+            let t0 = Instant::now();
+            let mut batch = Batch::default();
+            let (action, done) = match _vr(&v)? {
+                Task::DeleteAccountLinks(did_id) => {
+                    let (records, done) =
+                        self.delete_some_account_links(&did_id, budget, &mut batch)?;
+                    budget = budget.saturating_sub(records.max(1));
+                    if done {
+                        self.did_id_table
+                            .delete_reverse(&self.db, &mut batch, did_id.0);
+                    }
+                    ("delete_account", done)
+                }
+                Task::CollectTombstones(target_id, from) => {
+                    let (next, looked_at) =
+                        self.collect_some_tombstones(&target_id, from, budget, &mut batch)?;
+                    budget = budget.saturating_sub(looked_at.max(1));
+                    if let Some(next) = next {
+                        let task = Task::CollectTombstones(target_id, next);
+                        batch.put_cf(&self.db, TASKS_CF, k.to_vec(), _rv(&task));
+                    }
+                    ("collect_tombstones", next.is_none())
+                }
+                Task::Migrate(step, from, seen) => {
+                    let (next, looked_at) = self.migrate_some(step, &from, budget, &mut batch)?;
+                    budget = budget.saturating_sub(looked_at.max(1));
+                    let seen = seen + looked_at as u64;
+                    let name = MIGRATIONS[step as usize].name;
+                    if let Some(next) = &next {
+                        if seen / 1_000_000 > (seen - looked_at as u64) / 1_000_000 {
+                            println!("rocks: migrating ({name}): {seen} keys so far...");
                         }
-                        Some(linkers)
-                    })?;
+                        let task = Task::Migrate(step, next.clone(), seen);
+                        batch.put_cf(&self.db, TASKS_CF, k.to_vec(), _rv(&task));
+                    } else {
+                        println!("rocks: migrated ({name}) over {seen} keys");
+                    }
+                    ("migrate", next.is_none())
                 }
+            };
+            if done {
+                batch.delete_cf(&self.db, TASKS_CF, k.to_vec());
+            }
+            let batch_ops = batch.len();
+            self.write(batch)?;
+            if done {
+                self.queued_tasks -= 1;
+                counter!("storage_rocksdb_tasks_done").increment(1);
+            }
+
+            histogram!("storage_rocksdb_action_seconds", "action" => action)
+                .record(t0.elapsed().as_secs_f64());
+            counter!("storage_rocksdb_batch_ops_total", "action" => action)
+                .increment(batch_ops as u64);
+            if action == "delete_account" {
+                histogram!("storage_rocksdb_delete_account_ops").record(batch_ops as f64);
             }
-            total_batched_ops += mini_batch.len();
-            self.db.write(mini_batch)?; // todo
         }
-        Ok(total_batched_ops)
+        gauge!("storage_rocksdb_tasks_queued").set(self.queued_tasks as f64);
+        Ok(())
     }
 }
 
@@ -748,47 +2011,54 @@ impl ValueFromRocks for u64 {}
 
 impl LinkStorage for RocksStorage {
     fn get_cursor(&mut self) -> Result<Option<u64>> {
+        self.get_last_cursor()
+    }
+
+    fn get_stream(&mut self) -> Result<Option<String>> {
+        Ok(self
+            .db
+            .get(JETSTREAM_URL_KEY)?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    fn set_stream(&mut self, url: &str, cursor: Option<u64>) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.put(JETSTREAM_URL_KEY.as_bytes(), url.as_bytes());
+        if let Some(cursor) = cursor {
+            batch.put(JETSTREAM_CURSOR_KEY.as_bytes(), _rv(cursor));
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn rewind_stream(&mut self, url: &str, cursor: u64) -> Result<()> {
+        let seen_until = self.get_seen_until()?.max(self.get_cursor()?);
+        let mut batch = WriteBatch::default();
+        batch.put(JETSTREAM_URL_KEY.as_bytes(), url.as_bytes());
+        batch.put(JETSTREAM_CURSOR_KEY.as_bytes(), _rv(cursor));
+        if let Some(seen_until) = seen_until {
+            batch.put(JETSTREAM_SEEN_UNTIL_KEY.as_bytes(), _rv(seen_until));
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn get_seen_until(&mut self) -> Result<Option<u64>> {
         self.db
-            .get(JETSTREAM_CURSOR_KEY)?
+            .get(JETSTREAM_SEEN_UNTIL_KEY)?
             .map(|b| _vr(&b))
             .transpose()
     }
 
     fn push(&mut self, event: &ActionableEvent, cursor: u64) -> Result<()> {
-        // normal ops
-        let mut batch = WriteBatch::default();
+        let mut batch = Batch::default();
         let t0 = Instant::now();
-        if let Some(action) = match event {
-            ActionableEvent::CreateLinks { record_id, links } => {
-                self.add_links(record_id, links, &mut batch)?;
-                Some("create_links")
-            }
-            ActionableEvent::UpdateLinks {
-                record_id,
-                new_links,
-            } => {
-                self.remove_links(record_id, &mut batch)?;
-                self.add_links(record_id, new_links, &mut batch)?;
-                Some("update_links")
-            }
-            ActionableEvent::DeleteRecord(record_id) => {
-                self.remove_links(record_id, &mut batch)?;
-                Some("delete_record")
-            }
-            ActionableEvent::ActivateAccount(did) => {
-                self.set_account(did, true, &mut batch)?;
-                Some("set_account_status")
-            }
-            ActionableEvent::DeactivateAccount(did) => {
-                self.set_account(did, false, &mut batch)?;
-                Some("set_account_status")
-            }
-            ActionableEvent::DeleteAccount(_) => None, // delete account is handled specially
-        } {
+        if let Some(action) = self.apply(event, cursor, &mut batch)? {
             let t_read = t0.elapsed();
             batch.put(JETSTREAM_CURSOR_KEY.as_bytes(), _rv(cursor));
             let batch_ops = batch.len();
-            self.db.write(batch)?;
+            self.write(batch)?;
             let t_total = t0.elapsed();
 
             histogram!("storage_rocksdb_read_seconds", "action" => action)
@@ -798,27 +2068,35 @@ impl LinkStorage for RocksStorage {
             counter!("storage_rocksdb_batch_ops_total", "action" => action)
                 .increment(batch_ops as u64);
         }
+        self.work_on_tasks()
+    }
 
-        // special metrics for account deletion which can be arbitrarily expensive
-        let mut outer_batch = WriteBatch::default();
-        let t0 = Instant::now();
-        if let ActionableEvent::DeleteAccount(did) = event {
-            let inner_batch_ops = self.delete_account(did, &mut outer_batch)?;
-            let total_batch_ops = inner_batch_ops + outer_batch.len();
-            self.db.write(outer_batch)?;
-            let t_total = t0.elapsed();
+    fn set_idempotent(&mut self, idempotent: bool) {
+        self.idempotent = idempotent;
+    }
 
-            histogram!("storage_rocksdb_action_seconds", "action" => "delete_account")
-                .record(t_total.as_secs_f64());
-            counter!("storage_rocksdb_batch_ops_total", "action" => "delete_account")
-                .increment(total_batch_ops as u64);
-            histogram!("storage_rocksdb_delete_account_ops").record(total_batch_ops as f64);
-        }
+    fn set_show_private(&mut self, show: bool) {
+        self.show_private = show;
+    }
 
-        Ok(())
+    fn push_batch(&mut self, events: &[(ActionableEvent, u64)]) -> Result<()> {
+        let mut batch = Batch::default();
+        let mut last_cursor = None;
+        let t0 = Instant::now();
+        for (event, cursor) in events {
+            batch.events += 1;
+            let ops_before = batch.len();
+            if let Some(action) = self.apply(event, *cursor, &mut batch)? {
+                counter!("storage_rocksdb_batch_ops_total", "action" => action)
+                    .increment((batch.len() - ops_before) as u64);
+            }
+            last_cursor = Some(*cursor);
+        }
+        self.write_batch(batch, last_cursor, t0)?;
+        self.work_on_tasks()
     }
 
-    fn to_readable(&mut self) -> impl LinkReader {
+    fn to_readable(&mut self) -> impl LinkReader + use<> {
         let mut readable = self.clone();
         readable.is_writer = false;
         readable
@@ -827,27 +2105,20 @@ impl LinkStorage for RocksStorage {
 
 impl LinkReader for RocksStorage {
     fn get_count(&self, target: &str, collection: &str, path: &str) -> Result<u64> {
-        let target_key = TargetKey(
-            Target(target.to_string()),
-            Collection(collection.to_string()),
-            RPath(path.to_string()),
-        );
-        if let Some(target_id) = self.target_id_table.get_id_val(&self.db, &target_key)? {
-            let (alive, _) = self.get_target_linkers(&target_id)?.count();
-            Ok(alive)
+        if let Some(target_id) = self.get_target_id(target, collection, path)? {
+            Ok(self
+                .get_linker_counts(&Batch::default(), &target_id)?
+                .alive())
         } else {
             Ok(0)
         }
     }
 
     fn get_distinct_did_count(&self, target: &str, collection: &str, path: &str) -> Result<u64> {
-        let target_key = TargetKey(
-            Target(target.to_string()),
-            Collection(collection.to_string()),
-            RPath(path.to_string()),
-        );
-        if let Some(target_id) = self.target_id_table.get_id_val(&self.db, &target_key)? {
-            Ok(self.get_target_linkers(&target_id)?.count_distinct_dids())
+        if let Some(target_id) = self.get_target_id(target, collection, path)? {
+            Ok(self
+                .get_linker_counts(&Batch::default(), &target_id)?
+                .distinct())
         } else {
             Ok(0)
         }
@@ -861,13 +2132,7 @@ impl LinkReader for RocksStorage {
         limit: u64,
         until: Option<u64>,
     ) -> Result<PagedAppendingCollection<RecordId>> {
-        let target_key = TargetKey(
-            Target(target.to_string()),
-            Collection(collection.to_string()),
-            RPath(path.to_string()),
-        );
-
-        let Some(target_id) = self.target_id_table.get_id_val(&self.db, &target_key)? else {
+        let Some(target_id) = self.get_target_id(target, collection, path)? else {
             return Ok(PagedAppendingCollection {
                 version: (0, 0),
                 items: Vec::new(),
@@ -875,40 +2140,39 @@ impl LinkReader for RocksStorage {
             });
         };
 
-        let linkers = self.get_target_linkers(&target_id)?;
-
-        let (alive, gone) = linkers.count();
-        let total = alive + gone;
-        let end = until.map(|u| std::cmp::min(u, total)).unwrap_or(total) as usize;
-        let begin = end.saturating_sub(limit as usize);
-        let next = if begin == 0 { None } else { Some(begin as u64) };
-
-        let did_id_rkeys = linkers.0[begin..end].iter().rev().collect::<Vec<_>>();
-
-        let mut items = Vec::with_capacity(did_id_rkeys.len());
-        // TODO: use get-many (or multi-get or whatever it's called)
-        for (did_id, rkey) in did_id_rkeys {
-            if did_id.is_empty() {
-                continue;
-            }
-            if let Some(did) = self.did_id_table.get_val_from_id(&self.db, did_id.0)? {
-                let Some(DidIdValue(_, active)) = self.did_id_table.get_id_val(&self.db, &did)?
-                else {
-                    eprintln!("failed to look up did_value from did_id {did_id:?}: {did:?}: data consistency bug?");
-                    continue;
-                };
-                if !active {
+        let (total, gone) = self
+            .get_linker_counts(&Batch::default(), &target_id)?
+            .stored();
+        let mut linkers = Vec::new();
+        let mut next = None;
+        'chunks: for chunk in self.linker_chunks_back(&target_id, until) {
+            let (n, chunk, end) = chunk?;
+            for slot in (0..end).rev() {
+                let (did_id, rkey) = &chunk.linkers.0[slot];
+                if did_id.is_empty() {
                     continue;
                 }
-                items.push(RecordId {
-                    did,
-                    collection: collection.to_string(),
-                    rkey: rkey.0.clone(),
-                });
-            } else {
-                eprintln!("failed to look up did from did_id {did_id:?}");
+                if linkers.len() as u64 == limit {
+                    next = Some(linker_cursor(chunk.generation, n, slot as u64 + 1));
+                    break 'chunks;
+                }
+                linkers.push((*did_id, rkey.clone()));
             }
         }
+        let (did_ids, rkeys): (Vec<_>, Vec<_>) = linkers.into_iter().unzip();
+
+        let items = self
+            .get_shown_dids(&did_ids)?
+            .into_iter()
+            .zip(rkeys)
+            .filter_map(|(did, RKey(rkey))| {
+                Some(RecordId {
+                    did: did?,
+                    collection: collection.to_string(),
+                    rkey,
+                })
+            })
+            .collect();
 
         Ok(PagedAppendingCollection {
             version: (total, gone),
@@ -925,13 +2189,7 @@ impl LinkReader for RocksStorage {
         limit: u64,
         until: Option<u64>,
     ) -> Result<PagedAppendingCollection<Did>> {
-        let target_key = TargetKey(
-            Target(target.to_string()),
-            Collection(collection.to_string()),
-            RPath(path.to_string()),
-        );
-
-        let Some(target_id) = self.target_id_table.get_id_val(&self.db, &target_key)? else {
+        let Some(target_id) = self.get_target_id(target, collection, path)? else {
             return Ok(PagedAppendingCollection {
                 version: (0, 0),
                 items: Vec::new(),
@@ -939,37 +2197,30 @@ impl LinkReader for RocksStorage {
             });
         };
 
-        let linkers = self.get_distinct_target_linkers(&target_id)?;
-
-        let (alive, gone) = linkers.count();
-        let total = alive + gone;
-        let end = until.map(|u| std::cmp::min(u, total)).unwrap_or(total) as usize;
-        let begin = end.saturating_sub(limit as usize);
-        let next = if begin == 0 { None } else { Some(begin as u64) };
-
-        let did_id_rkeys = linkers.0[begin..end].iter().rev().collect::<Vec<_>>();
-
-        let mut items = Vec::with_capacity(did_id_rkeys.len());
-        // TODO: use get-many (or multi-get or whatever it's called)
-        for (did_id, _) in did_id_rkeys {
-            if did_id.is_empty() {
-                continue;
-            }
-            if let Some(did) = self.did_id_table.get_val_from_id(&self.db, did_id.0)? {
-                let Some(DidIdValue(_, active)) = self.did_id_table.get_id_val(&self.db, &did)?
-                else {
-                    eprintln!("failed to look up did_value from did_id {did_id:?}: {did:?}: data consistency bug?");
-                    continue;
-                };
-                if !active {
-                    continue;
+        // a did only counts at its first link, so the rest are gone as far as paging goes
+        let counts = self.get_linker_counts(&Batch::default(), &target_id)?;
+        let (total, _) = counts.stored();
+        let gone = total - counts.distinct().min(total);
+        let mut did_ids = Vec::new();
+        let mut next = None;
+        'chunks: for chunk in self.linker_chunks_back(&target_id, until) {
+            let (n, chunk, end) = chunk?;
+            let first_links = self.first_links(&target_id, n, &chunk.linkers.0[..end])?;
+            for (slot, did_id) in first_links.into_iter().rev() {
+                if did_ids.len() as u64 == limit {
+                    next = Some(linker_cursor(chunk.generation, n, slot as u64 + 1));
+                    break 'chunks;
                 }
-                items.push(did);
-            } else {
-                eprintln!("failed to look up did from did_id {did_id:?}");
+                did_ids.push(did_id);
             }
         }
 
+        let items = self
+            .get_shown_dids(&did_ids)?
+            .into_iter()
+            .flatten()
+            .collect();
+
         Ok(PagedAppendingCollection {
             version: (total, gone),
             items,
@@ -979,12 +2230,17 @@ impl LinkReader for RocksStorage {
 
     fn get_all_record_counts(&self, target: &str) -> Result<HashMap<String, HashMap<String, u64>>> {
         let mut out: HashMap<String, HashMap<String, u64>> = HashMap::new();
-        for (target_key, target_id) in self.iter_targets_for_target(&Target(target.into())) {
-            let TargetKey(_, Collection(ref collection), RPath(ref path)) = target_key;
-            let (count, _) = self.get_target_linkers(&target_id)?.count();
-            out.entry(collection.into())
-                .or_default()
-                .insert(path.clone(), count);
+        for (TargetKey(_, source_id), target_id) in
+            self.iter_targets_for_target(&Target(target.into()))
+        {
+            let Some((Collection(collection), RPath(path))) = self.get_source(&source_id)? else {
+                eprintln!("failed to look up source from source_id {source_id:?}");
+                continue;
+            };
+            let count = self
+                .get_linker_counts(&Batch::default(), &target_id)?
+                .alive();
+            out.entry(collection).or_default().insert(path, count);
         }
         Ok(out)
     }
@@ -994,13 +2250,17 @@ impl LinkReader for RocksStorage {
         target: &str,
     ) -> Result<HashMap<String, HashMap<String, CountsByCount>>> {
         let mut out: HashMap<String, HashMap<String, CountsByCount>> = HashMap::new();
-        for (target_key, target_id) in self.iter_targets_for_target(&Target(target.into())) {
-            let TargetKey(_, Collection(ref collection), RPath(ref path)) = target_key;
-            let target_linkers = self.get_target_linkers(&target_id)?;
-            let (records, _) = target_linkers.count();
-            let distinct_dids = target_linkers.count_distinct_dids();
-            out.entry(collection.into()).or_default().insert(
-                path.clone(),
+        for (TargetKey(_, source_id), target_id) in
+            self.iter_targets_for_target(&Target(target.into()))
+        {
+            let Some((Collection(collection), RPath(path))) = self.get_source(&source_id)? else {
+                eprintln!("failed to look up source from source_id {source_id:?}");
+                continue;
+            };
+            let counts = self.get_linker_counts(&Batch::default(), &target_id)?;
+            let (records, distinct_dids) = (counts.alive(), counts.distinct());
+            out.entry(collection).or_default().insert(
+                path,
                 CountsByCount {
                     records,
                     distinct_dids,
@@ -1026,24 +2286,117 @@ impl LinkReader for RocksStorage {
             linking_records,
         })
     }
-}
-
-trait AsRocksKey: Serialize {}
-trait AsRocksKeyPrefix<K: KeyFromRocks>: Serialize {}
-trait AsRocksValue: Serialize {}
-trait KeyFromRocks: for<'de> Deserialize<'de> {}
-trait ValueFromRocks: for<'de> Deserialize<'de> {}
 
-// did_id table
-impl AsRocksKey for &Did {}
-impl AsRocksValue for &DidIdValue {}
-impl ValueFromRocks for DidIdValue {}
+    fn get_last_cursor(&self) -> Result<Option<u64>> {
+        self.db
+            .get(JETSTREAM_CURSOR_KEY)?
+            .map(|b| _vr(&b))
+            .transpose()
+    }
 
-// temp
-impl KeyFromRocks for Did {}
-impl AsRocksKey for &DidId {}
+    fn get_source_counts(&self) -> Result<HashMap<String, HashMap<String, u64>>> {
+        let mut by_source: HashMap<SourceId, u64> = HashMap::new();
+        let cf = self.db.cf_handle(TARGET_IDS_CF).unwrap();
+        for kv in self.db.iterator_cf(&cf, IteratorMode::Start) {
+            let (k, v) = kv?;
+            let TargetKey(_, source_id) = _kr(&k)?;
+            let count = self
+                .get_linker_counts(&Batch::default(), &_vr(&v)?)?
+                .alive();
+            *by_source.entry(source_id).or_default() += count;
+        }
+        let mut out: HashMap<String, HashMap<String, u64>> = HashMap::new();
+        for (source_id, count) in by_source {
+            let Some((Collection(collection), RPath(path))) = self.get_source(&source_id)? else {
+                eprintln!("failed to look up source from source_id {source_id:?}");
+                continue;
+            };
+            *out.entry(collection).or_default().entry(path).or_default() += count;
+        }
+        Ok(out)
+    }
+
+    fn get_handle(&self, did: &Did) -> Result<Option<String>> {
+        let batch = Batch::default();
+        let Some(handle) = self.get_handle_history_value(&batch, did)?.current() else {
+            return Ok(None);
+        };
+        // someone else may have claimed it since
+        let claimed_by = self.get_handle_did(&batch, &Handle(handle.clone()))?;
+        Ok((claimed_by.as_ref() == Some(did)).then_some(handle))
+    }
+
+    fn get_handles(&self, dids: &[Did]) -> Result<Vec<Option<String>>> {
+        let histories_cf = self.db.cf_handle(DID_HANDLES_CF).unwrap();
+        let keys: Vec<_> = dids.iter().map(_rk).collect();
+        let handles = self
+            .db
+            .batched_multi_get_cf(&histories_cf, &keys, false)
+            .into_iter()
+            .map(|history_bytes| {
+                let history: Option<HandleHistory> = history_bytes?.map(|b| _vr(&b)).transpose()?;
+                Ok(history.and_then(|h| h.current()).map(Handle))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // only show handles that still point back at the same did
+        let claims_cf = self.db.cf_handle(HANDLE_DIDS_CF).unwrap();
+        let keys: Vec<_> = handles.iter().flatten().map(_rk).collect();
+        let mut claims = self
+            .db
+            .batched_multi_get_cf(&claims_cf, &keys, false)
+            .into_iter();
+        let mut out = Vec::with_capacity(dids.len());
+        for (did, handle) in dids.iter().zip(handles) {
+            let Some(handle) = handle else {
+                out.push(None);
+                continue;
+            };
+            let claim_bytes = claims.next().expect("one claim per handle")?;
+            let claimed_by: Option<Did> = claim_bytes.map(|b| _vr(&b)).transpose()?;
+            out.push((claimed_by.as_ref() == Some(did)).then_some(handle.0));
+        }
+        Ok(out)
+    }
+
+    fn get_handle_history(&self, did: &Did) -> Result<Vec<HandleEntry>> {
+        Ok(self.get_handle_history_value(&Batch::default(), did)?.0)
+    }
+
+    fn resolve_handle(&self, handle: &str) -> Result<Option<Did>> {
+        self.get_handle_did(&Batch::default(), &Handle(handle.to_string()))
+    }
+}
+
+trait AsRocksKey: Serialize {}
+trait AsRocksKeyPrefix<K: KeyFromRocks>: Serialize {}
+trait AsRocksValue: Serialize {}
+trait KeyFromRocks: for<'de> Deserialize<'de> {}
+trait ValueFromRocks: for<'de> Deserialize<'de> {}
+
+// did_id table
+impl AsRocksKey for &Did {}
+impl AsRocksValue for &DidIdValue {}
+impl ValueFromRocks for DidIdValue {}
+
+// temp
+impl KeyFromRocks for Did {}
+impl AsRocksKey for &DidId {}
+
+// collection_ids table
+impl AsRocksKey for &Collection {}
+impl KeyFromRocks for Collection {}
+impl AsRocksValue for &CollectionId {}
+impl ValueFromRocks for CollectionId {}
+
+// source_ids table
+impl AsRocksKey for &SourceKey {}
+impl KeyFromRocks for SourceKey {}
+impl AsRocksValue for &SourceId {}
+impl ValueFromRocks for SourceId {}
 
 // target_ids table
+impl KeyFromRocks for LegacyTargetKey {}
 impl AsRocksKey for &TargetKey {}
 impl AsRocksKeyPrefix<TargetKey> for &TargetIdTargetPrefix {}
 impl AsRocksValue for &TargetId {}
@@ -1052,8 +2405,32 @@ impl ValueFromRocks for TargetId {}
 
 // target_links table
 impl AsRocksKey for &TargetId {}
-impl AsRocksValue for &TargetLinkers {}
-impl ValueFromRocks for TargetLinkers {}
+impl KeyFromRocks for TargetId {} // keys from before chunking
+impl AsRocksKey for &LinkerChunkKey {}
+impl KeyFromRocks for LinkerChunkKey {}
+// (values have their own encoding: see `LinkerChunk::encode`)
+
+// target_link_counts table
+impl AsRocksValue for &LinkerCounts {}
+impl ValueFromRocks for LinkerCounts {}
+
+// target_did_links table
+impl AsRocksKey for &TargetDidKey {}
+impl AsRocksValue for &DidLinkChunks {}
+impl ValueFromRocks for DidLinkChunks {}
+
+// did_handles and handle_dids tables
+impl AsRocksValue for &HandleHistory {}
+impl ValueFromRocks for HandleHistory {}
+impl AsRocksKey for &Handle {}
+impl AsRocksValue for &Did {}
+impl ValueFromRocks for Did {}
+
+// tasks table
+impl AsRocksKey for &TaskKey {}
+impl KeyFromRocks for TaskKey {}
+impl AsRocksValue for &Task {}
+impl ValueFromRocks for Task {}
 
 // record_link_targets table
 impl AsRocksKey for &RecordLinkKey {}
@@ -1061,6 +2438,111 @@ impl AsRocksKeyPrefix<RecordLinkKey> for &RecordLinkKeyDidIdPrefix {}
 impl AsRocksValue for &RecordLinkTargets {}
 impl KeyFromRocks for RecordLinkKey {}
 impl ValueFromRocks for RecordLinkTargets {}
+impl KeyFromRocks for LegacyRecordLinkKey {}
+impl ValueFromRocks for LegacyRecordLinkTargets {}
+
+type MergeFn = fn(&[u8], Option<&[u8]>, &mut dyn Iterator<Item = &[u8]>) -> Option<Vec<u8>>;
+
+/// A WriteBatch that can read back its own writes
+///
+/// The rust bindings don't expose rocksdb's WriteBatchWithIndex, so this keeps its own overlay of
+/// everything written: later events in a batch need to see the ids allocated and the links
+/// written by earlier ones.
+#[derive(Default)]
+struct Batch {
+    batch: WriteBatch,
+    overlay: HashMap<(&'static str, Vec<u8>), Pending>,
+    events: usize,
+    /// the last id allocated from each id table
+    ids: HashMap<&'static str, u64>,
+    /// tasks queued
+    tasks: u64,
+}
+
+enum Pending {
+    /// put (Some) or deleted (None)
+    Value(Option<Vec<u8>>),
+    /// merge operands still to be applied on top of whatever is in the db
+    Merges(MergeFn, Vec<Vec<u8>>),
+}
+
+impl Batch {
+    fn len(&self) -> usize {
+        self.batch.len()
+    }
+    /// default-cf writes aren't read back
+    fn put(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        self.batch.put(key, value);
+    }
+    fn put_cf(
+        &mut self,
+        db: &DBWithThreadMode<MultiThreaded>,
+        cf: &'static str,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) {
+        self.batch.put_cf(&db.cf_handle(cf).unwrap(), &key, &value);
+        self.overlay.insert((cf, key), Pending::Value(Some(value)));
+    }
+    fn delete_cf(&mut self, db: &DBWithThreadMode<MultiThreaded>, cf: &'static str, key: Vec<u8>) {
+        self.batch.delete_cf(&db.cf_handle(cf).unwrap(), &key);
+        self.overlay.insert((cf, key), Pending::Value(None));
+    }
+    fn merge_cf(
+        &mut self,
+        db: &DBWithThreadMode<MultiThreaded>,
+        cf: &'static str,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+        merge: MergeFn,
+    ) {
+        self.batch
+            .merge_cf(&db.cf_handle(cf).unwrap(), &key, &operand);
+        match self.overlay.entry((cf, key)) {
+            Entry::Occupied(mut entry) => {
+                if let Pending::Value(existing) = entry.get() {
+                    // already has a known value: merge eagerly
+                    let merged = merge(
+                        &entry.key().1,
+                        existing.as_deref(),
+                        &mut [operand.as_slice()].into_iter(),
+                    );
+                    entry.insert(Pending::Value(merged));
+                } else if let Pending::Merges(_, operands) = entry.get_mut() {
+                    operands.push(operand);
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Pending::Merges(merge, vec![operand]));
+            }
+        }
+    }
+    fn get_cf(
+        &self,
+        db: &DBWithThreadMode<MultiThreaded>,
+        cf: &'static str,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        let from_db = || db.get_cf(&db.cf_handle(cf).unwrap(), key);
+        Ok(match self.overlay.get(&(cf, key.to_vec())) {
+            Some(Pending::Value(value)) => value.clone(),
+            Some(Pending::Merges(merge, operands)) => merge(
+                key,
+                from_db()?.as_deref(),
+                &mut operands.iter().map(Vec::as_slice),
+            ),
+            None => from_db()?,
+        })
+    }
+    fn get_value<V: ValueFromRocks>(
+        &self,
+        db: &DBWithThreadMode<MultiThreaded>,
+        cf: &'static str,
+        key: &[u8],
+    ) -> Result<Option<V>> {
+        self.get_cf(db, cf, key)?.map(|b| _vr(&b)).transpose()
+    }
+}
 
 pub fn _bincode_opts() -> impl BincodeOptions {
     bincode::DefaultOptions::new().with_big_endian() // happier db -- numeric prefixes in lsm
@@ -1110,29 +2592,151 @@ impl DidId {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct DidIdValue(DidId, bool); // active or not
+struct DidIdValue(DidId, AccountFlags);
+
+/// one byte, so values from when this was just an `active` bool still read the same
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct AccountFlags(u8);
 
-impl DidIdValue {
-    fn did_id(&self) -> DidId {
-        let Self(id, _) = self;
-        *id
+impl AccountFlags {
+    const ACTIVE: Self = Self(1);
+    /// self-labeled `!no-unauthenticated`
+    const PRIVATE: Self = Self(1 << 1);
+
+    fn active(&self) -> bool {
+        self.0 & Self::ACTIVE.0 != 0
+    }
+    fn private(&self) -> bool {
+        self.0 & Self::PRIVATE.0 != 0
+    }
+    fn with(self, flag: Self, on: bool) -> Self {
+        if on {
+            Self(self.0 | flag.0)
+        } else {
+            Self(self.0 & !flag.0)
+        }
     }
 }
 
 // target ids
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct TargetId(u64); // key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TargetId(pub u64); // key
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Target(pub String); // the actual target/uri
 
 // targets (uris, dids, etc.): the reverse index
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TargetKey(pub Target, pub Collection, pub RPath);
+pub struct TargetKey(pub Target, pub SourceId);
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+// from before sources had ids
+#[derive(Debug, Deserialize)]
+struct LegacyTargetKey(Target, Collection, RPath);
+
+// collection ids
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CollectionId(pub u64);
+
+// source ids: a path within a collection that links are found at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SourceId(pub u64);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceKey(pub CollectionId, pub RPath);
+
+// target id + chunk number: chunk n gets the linkers appended n * LINKERS_PER_CHUNK onwards
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LinkerChunkKey(pub TargetId, pub u64);
+
+/// serde is only for the old plain-bincode encoding: values are written with `encode`
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct TargetLinkers(pub Vec<(DidId, RKey)>);
 
+/// every linker ever appended to a target, how many of those were since deleted, how many
+/// distinct dids the rest are from, and how many of the deleted ones have been collected out of
+/// the chunks
+///
+/// signed, since merge operands are deltas of the same type.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LinkerCounts {
+    pub total: i64,
+    pub gone: i64,
+    pub distinct: i64,
+    pub collected: i64,
+}
+
+impl LinkerCounts {
+    pub fn alive(&self) -> u64 {
+        (self.total - self.gone).max(0) as u64
+    }
+    pub fn distinct(&self) -> u64 {
+        self.distinct.max(0) as u64
+    }
+    /// (linkers still in the chunks, tombstones among them)
+    pub fn stored(&self) -> (u64, u64) {
+        let total = (self.total - self.collected).max(0) as u64;
+        let gone = (self.gone - self.collected).max(0) as u64;
+        (total, gone)
+    }
+    fn add(&mut self, delta: &Self) {
+        self.total += delta.total;
+        self.gone += delta.gone;
+        self.distinct += delta.distinct;
+        self.collected += delta.collected;
+    }
+}
+
+// target id + linking did id: which chunks the did's links to the target are in
+#[derive(Debug, Serialize, Deserialize)]
+struct TargetDidKey(TargetId, DidId);
+
+/// (chunk number, links in it) for each of a target's chunks that a did links from, in order
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DidLinkChunks(Vec<(u64, u64)>);
+
+impl DidLinkChunks {
+    fn links(&self) -> u64 {
+        self.0.iter().map(|(_, links)| links).sum()
+    }
+    /// the chunk with the did's first link to the target
+    fn first(&self) -> Option<u64> {
+        self.0.first().map(|(n, _)| *n)
+    }
+    fn add(&mut self, chunk: u64) {
+        match self.0.binary_search_by_key(&chunk, |(n, _)| *n) {
+            Ok(i) => self.0[i].1 += 1,
+            Err(i) => self.0.insert(i, (chunk, 1)),
+        }
+    }
+    fn remove(&mut self, chunk: u64) {
+        if let Ok(i) = self.0.binary_search_by_key(&chunk, |(n, _)| *n) {
+            self.0[i].1 -= 1;
+            if self.0[i].1 == 0 {
+                self.0.remove(i);
+            }
+        }
+    }
+}
+
+/// a cursor is a linker's position (its chunk's number * LINKERS_PER_CHUNK + its slot) in the
+/// low bits, with the generation of its chunk above them
+const CURSOR_POSITION_BITS: u32 = 40;
+const CURSOR_GENERATION_MASK: u64 = (1 << (64 - CURSOR_POSITION_BITS)) - 1;
+
+fn linker_cursor(generation: u64, chunk: u64, slot: u64) -> u64 {
+    (generation & CURSOR_GENERATION_MASK) << CURSOR_POSITION_BITS
+        | (chunk * LINKERS_PER_CHUNK + slot)
+}
+/// (generation, chunk, slot)
+fn split_linker_cursor(cursor: u64) -> (u64, u64, u64) {
+    let position = cursor & ((1 << CURSOR_POSITION_BITS) - 1);
+    (
+        cursor >> CURSOR_POSITION_BITS,
+        position / LINKERS_PER_CHUNK,
+        position % LINKERS_PER_CHUNK,
+    )
+}
+
 impl TargetLinkers {
     fn remove_linker(&mut self, did: &DidId, rkey: &RKey) -> bool {
         if let Some(entry) = self.0.iter_mut().rfind(|d| **d == (*did, rkey.clone())) {
@@ -1149,6 +2753,7 @@ impl TargetLinkers {
         let gone = total - alive;
         (alive, gone)
     }
+    #[cfg(test)]
     fn count_distinct_dids(&self) -> u64 {
         self.0
             .iter()
@@ -1156,11 +2761,275 @@ impl TargetLinkers {
             .collect::<HashSet<_>>()
             .len() as u64
     }
+
+    /// compact encoding, tagged so it can't be confused with the old plain bincode
+    ///
+    /// after the two-byte header, each linker is a varint of its did id's (zigzagged) delta from
+    /// the previous linker's, shifted left to fit a two-bit rkey tag, then the rkey itself:
+    /// nothing for deleted linkers, 8 bytes for TIDs, or a length-prefixed string for anything
+    /// else. big chunks get zstd-compressed.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(self.0.len() * 11);
+        self.encode_into(&mut body);
+        tag_linkers(LINKERS_V1, LINKERS_V1_ZSTD, body)
+    }
+    fn encode_into(&self, body: &mut Vec<u8>) {
+        let mut prev = 0;
+        for (DidId(id), RKey(rkey)) in &self.0 {
+            let delta = id.wrapping_sub(prev) as i64;
+            prev = *id;
+            let zigzag = ((delta << 1) ^ (delta >> 63)) as u64;
+            if rkey.is_empty() {
+                put_varint(body, zigzag << 2 | RKEY_EMPTY);
+            } else if let Some(tid) = tid_to_u64(rkey) {
+                put_varint(body, zigzag << 2 | RKEY_TID);
+                body.extend_from_slice(&tid.to_be_bytes());
+            } else {
+                put_varint(body, zigzag << 2 | RKEY_STRING);
+                put_varint(body, rkey.len() as u64);
+                body.extend_from_slice(rkey.as_bytes());
+            }
+        }
+    }
+    fn decode_from(mut body: &[u8]) -> Result<Self> {
+        let mut prev = 0_u64;
+        let mut linkers = Vec::new();
+        while !body.is_empty() {
+            linkers.push(take_linker(&mut body, &mut prev)?);
+        }
+        Ok(Self(linkers))
+    }
+    /// read any of the encodings, chunks with tombstones collected out of them too
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        Ok(LinkerChunk::decode(bytes)?.linkers)
+    }
+    fn is_compact(bytes: &[u8]) -> bool {
+        bytes.first() == Some(&LINKERS_MARK)
+    }
+}
+
+/// a chunk of a target's linkers, and what the last collection of its tombstones took out
+///
+/// a linker's slot is its place in the chunk, so a collection moves every linker after a
+/// tombstone down. `generation` counts the collections, and `removed` is for cursors from
+/// before the last one to find their place again.
+#[derive(Debug, Default, PartialEq)]
+pub struct LinkerChunk {
+    pub linkers: TargetLinkers,
+    pub generation: u64,
+    /// the slots that held tombstones before the last collection, in order
+    pub removed: Vec<u64>,
+}
+
+impl LinkerChunk {
+    /// drop the tombstones, returning how many there were
+    fn collect_tombstones(&mut self) -> usize {
+        let removed: Vec<u64> = (0..self.linkers.0.len() as u64)
+            .filter(|slot| self.linkers.0[*slot as usize].0.is_empty())
+            .collect();
+        if removed.is_empty() {
+            return 0;
+        }
+        self.linkers.0.retain(|(did_id, _)| !did_id.is_empty());
+        self.generation += 1;
+        self.removed = removed;
+        self.removed.len()
+    }
+    /// where a slot from a cursor handed out at `generation` is now
+    ///
+    /// cursors from before the last two collections are only mapped through the last one, so
+    /// they can be off by the tombstones the ones before took out.
+    fn map_slot(&self, generation: u64, slot: u64) -> u64 {
+        let slot = if generation == self.generation & CURSOR_GENERATION_MASK {
+            slot
+        } else {
+            slot - self.removed.iter().take_while(|r| **r < slot).count() as u64
+        };
+        slot.min(self.linkers.0.len() as u64)
+    }
+
+    /// the compact encoding, after a header with the generation and the removed slots if
+    /// there's been a collection
+    pub fn encode(&self) -> Vec<u8> {
+        if self.generation == 0 {
+            return self.linkers.encode();
+        }
+        let mut body = Vec::with_capacity(self.linkers.0.len() * 11);
+        put_varint(&mut body, self.generation);
+        put_varint(&mut body, self.removed.len() as u64);
+        let mut prev = 0;
+        for slot in &self.removed {
+            put_varint(&mut body, slot - prev);
+            prev = *slot;
+        }
+        self.linkers.encode_into(&mut body);
+        tag_linkers(LINKERS_V1_COLLECTED, LINKERS_V1_COLLECTED_ZSTD, body)
+    }
+    /// read any of the encodings
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let Some((&LINKERS_MARK, rest)) = bytes.split_first() else {
+            let linkers = _bincode_opts().deserialize(bytes)?;
+            return Ok(Self {
+                linkers,
+                ..Default::default()
+            });
+        };
+        let (body, collected) = match rest.split_first() {
+            Some((&LINKERS_V1, body)) => (body.to_vec(), false),
+            Some((&LINKERS_V1_ZSTD, compressed)) => (zstd::stream::decode_all(compressed)?, false),
+            Some((&LINKERS_V1_COLLECTED, body)) => (body.to_vec(), true),
+            Some((&LINKERS_V1_COLLECTED_ZSTD, compressed)) => {
+                (zstd::stream::decode_all(compressed)?, true)
+            }
+            Some((v, _)) => bail!("unknown target linkers encoding version {v}"),
+            None => bail!("target linkers encoding is missing its version"),
+        };
+        let mut body = body.as_slice();
+        let mut chunk = Self::default();
+        if collected {
+            chunk.generation = take_varint(&mut body)?;
+            let removed = take_varint(&mut body)?;
+            let mut prev = 0;
+            for _ in 0..removed {
+                prev += take_varint(&mut body)?;
+                chunk.removed.push(prev);
+            }
+        }
+        chunk.linkers = TargetLinkers::decode_from(body)?;
+        Ok(chunk)
+    }
+}
+
+/// the two-byte header, compressing the body if that's worth it
+fn tag_linkers(version: u8, version_zstd: u8, body: Vec<u8>) -> Vec<u8> {
+    if body.len() >= LINKERS_ZSTD_MIN_BYTES {
+        if let Ok(compressed) = zstd::bulk::compress(&body, LINKERS_ZSTD_LEVEL) {
+            if compressed.len() < body.len() {
+                return [&[LINKERS_MARK, version_zstd][..], &compressed].concat();
+            }
+        }
+    }
+    [&[LINKERS_MARK, version][..], &body].concat()
+}
+
+/// one linker in the compact encoding, after the one before it
+fn take_linker(body: &mut &[u8], prev: &mut u64) -> Result<(DidId, RKey)> {
+    let head = take_varint(body)?;
+    let zigzag = head >> 2;
+    let delta = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+    *prev = prev.wrapping_add(delta as u64);
+    let rkey = match head & 0b11 {
+        RKEY_EMPTY => String::new(),
+        RKEY_TID => {
+            let Some((tid, rest)) = body.split_first_chunk::<8>() else {
+                bail!("target linkers encoding ends inside a tid");
+            };
+            *body = rest;
+            u64_to_tid(u64::from_be_bytes(*tid))
+        }
+        RKEY_STRING => {
+            let len = take_varint(body)? as usize;
+            if body.len() < len {
+                bail!("target linkers encoding ends inside an rkey");
+            }
+            let (rkey, rest) = body.split_at(len);
+            *body = rest;
+            String::from_utf8(rkey.to_vec())?
+        }
+        tag => bail!("unknown rkey tag {tag} in target linkers encoding"),
+    };
+    Ok((DidId(*prev), RKey(rkey)))
+}
+
+/// first byte of the compact encoding. bincode's varint length prefix never starts with it.
+const LINKERS_MARK: u8 = 0xFF;
+const LINKERS_V1: u8 = 1;
+const LINKERS_V1_ZSTD: u8 = 2;
+const LINKERS_V1_COLLECTED: u8 = 3;
+const LINKERS_V1_COLLECTED_ZSTD: u8 = 4;
+/// about half of a full chunk of TIDs
+const LINKERS_ZSTD_MIN_BYTES: usize = 1024;
+const LINKERS_ZSTD_LEVEL: i32 = 3;
+
+const RKEY_EMPTY: u64 = 0;
+const RKEY_TID: u64 = 1;
+const RKEY_STRING: u64 = 2;
+
+const TID_ALPHABET: &[u8; 32] = b"234567abcdefghijklmnopqrstuvwxyz";
+
+/// TIDs are 13 base32-sortable chars holding a 64-bit int with the top bit unset
+fn tid_to_u64(s: &str) -> Option<u64> {
+    if s.len() != 13 {
+        return None;
+    }
+    let mut n = 0_u64;
+    for (i, c) in s.bytes().enumerate() {
+        let digit = TID_ALPHABET.iter().position(|&a| a == c)? as u64;
+        if i == 0 && digit >= 16 {
+            return None;
+        }
+        n = n << 5 | digit;
+    }
+    Some(n)
+}
+fn u64_to_tid(mut n: u64) -> String {
+    let mut chars = [0; 13];
+    for c in chars.iter_mut().rev() {
+        *c = TID_ALPHABET[(n & 0b11111) as usize];
+        n >>= 5;
+    }
+    String::from_utf8(chars.to_vec()).unwrap()
+}
+
+fn put_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+fn take_varint(bytes: &mut &[u8]) -> Result<u64> {
+    let mut n = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let Some((&b, rest)) = bytes.split_first() else {
+            bail!("target linkers encoding ends inside a varint");
+        };
+        *bytes = rest;
+        n |= ((b & 0x7F) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    bail!("varint in target linkers encoding is too long")
+}
+
+#[cfg(test)]
+thread_local! {
+    /// linker chunks read for pages on this thread
+    static CHUNK_READS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// background tasks, in the order they were queued
+#[derive(Debug, Serialize, Deserialize)]
+struct TaskKey(u64);
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Task {
+    /// remove every link from an account that's already been forgotten
+    DeleteAccountLinks(DidId),
+    /// collect the tombstones out of a target's linker chunks, from this chunk on
+    CollectTombstones(TargetId, u64),
+    /// run an online migration (by its step) from this key on, with how many keys it's seen
+    Migrate(u64, Vec<u8>, u64),
 }
 
 // forward links to targets so we can delete links
 #[derive(Debug, Serialize, Deserialize)]
-struct RecordLinkKey(DidId, Collection, RKey);
+struct RecordLinkKey(DidId, CollectionId, RKey);
+
+// from before collections had ids
+#[derive(Debug, Deserialize)]
+struct LegacyRecordLinkKey(DidId, Collection, RKey);
 
 // does this even work????
 #[derive(Debug, Serialize, Deserialize)]
@@ -1169,10 +3038,13 @@ struct RecordLinkKeyDidIdPrefix(DidId);
 #[derive(Debug, Serialize, Deserialize)]
 struct TargetIdTargetPrefix(Target);
 
-#[derive(Debug, Serialize, Deserialize)]
-struct RecordLinkTarget(RPath, TargetId);
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct RecordLinkTarget(SourceId, TargetId);
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Deserialize)]
+struct LegacyRecordLinkTargets(Vec<(RPath, TargetId)>);
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 struct RecordLinkTargets(Vec<RecordLinkTarget>);
 
 impl RecordLinkTargets {
@@ -1184,9 +3056,22 @@ impl RecordLinkTargets {
     }
 }
 
+// identities
+#[derive(Debug, Serialize, Deserialize)]
+struct Handle(String);
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HandleHistory(Vec<HandleEntry>);
+
+impl HandleHistory {
+    fn current(&self) -> Option<String> {
+        self.0.last().and_then(|entry| entry.handle.clone())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::super::ActionableEvent;
+    use super::super::{ActionableEvent, MemStorage};
     use super::*;
     use links::Link;
     use tempfile::tempdir;
@@ -1285,5 +3170,1012 @@ mod tests {
         Ok(())
     }
 
+    /// the schema version from just before a migration, so that it runs again
+    fn version_before(name: &str) -> u64 {
+        MIGRATIONS.iter().position(|m| m.name == name).unwrap() as u64
+    }
+
+    fn like(i: u64) -> ActionableEvent {
+        ActionableEvent::CreateLinks {
+            record_id: RecordId {
+                did: format!("did:plc:{i}").into(),
+                collection: "a.b.c".into(),
+                rkey: "asdf".into(),
+            },
+            links: vec![CollectedLink {
+                target: Link::Uri("example.com".into()),
+                path: ".uri".into(),
+            }],
+        }
+    }
+
+    #[test]
+    fn rocks_linkers_page_across_chunks() -> Result<()> {
+        let mut store = RocksStorage::new(tempdir()?)?;
+        let mut mem = MemStorage::new();
+        let n = LINKERS_PER_CHUNK * 2 + 10;
+        let mut events: Vec<_> = (0..n).map(|i| (like(i), i)).collect();
+        for i in [3, LINKERS_PER_CHUNK, n - 2] {
+            let ActionableEvent::CreateLinks { record_id, .. } = like(i) else {
+                unreachable!()
+            };
+            events.push((ActionableEvent::DeleteRecord(record_id), n + i));
+        }
+        store.push_batch(&events)?;
+        mem.push_batch(&events)?;
+
+        assert_eq!(store.get_count("example.com", "a.b.c", ".uri")?, n - 3);
+        let mut until = None;
+        loop {
+            let page = store.get_links("example.com", "a.b.c", ".uri", 100, until)?;
+            assert_eq!(
+                page,
+                mem.get_links("example.com", "a.b.c", ".uri", 100, until)?
+            );
+            assert_eq!(page.version, (n, 3));
+            until = page.next;
+            if until.is_none() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_distinct_dids_page_across_chunks() -> Result<()> {
+        let mut store = RocksStorage::new(tempdir()?)?;
+        let mut mem = MemStorage::new();
+        // dids linking again and again, in chunks well after their first links
+        let record_id = |i: u64| RecordId {
+            did: format!("did:plc:{}", i % 37).into(),
+            collection: "a.b.c".into(),
+            rkey: format!("r{i}"),
+        };
+        let n = LINKERS_PER_CHUNK * 2 + 10;
+        let mut events: Vec<_> = (0..n)
+            .map(|i| {
+                let event = ActionableEvent::CreateLinks {
+                    record_id: record_id(i),
+                    links: vec![CollectedLink {
+                        target: Link::Uri("example.com".into()),
+                        path: ".uri".into(),
+                    }],
+                };
+                (event, i)
+            })
+            .collect();
+        // some first links go, so later ones take over
+        for i in [3, 5, 5 + 37, LINKERS_PER_CHUNK, n - 2] {
+            events.push((ActionableEvent::DeleteRecord(record_id(i)), n + i));
+        }
+        store.push_batch(&events)?;
+        mem.push_batch(&events)?;
+
+        for limit in [16, 100] {
+            let mut until = None;
+            loop {
+                let page = store.get_distinct_dids("example.com", "a.b.c", ".uri", limit, until)?;
+                assert_eq!(
+                    page,
+                    mem.get_distinct_dids("example.com", "a.b.c", ".uri", limit, until)?
+                );
+                until = page.next;
+                if until.is_none() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_deep_distinct_dids_pages_read_only_their_chunks() -> Result<()> {
+        let mut store = RocksStorage::new(tempdir()?)?;
+        let mut mem = MemStorage::new();
+        // every did links again in later chunks
+        let events: Vec<_> = (0..LINKERS_PER_CHUNK * 10)
+            .map(|i| {
+                let event = ActionableEvent::CreateLinks {
+                    record_id: RecordId {
+                        did: format!("did:plc:{}", i % 1000).into(),
+                        collection: "a.b.c".into(),
+                        rkey: format!("r{i}"),
+                    },
+                    links: vec![CollectedLink {
+                        target: Link::Uri("example.com".into()),
+                        path: ".uri".into(),
+                    }],
+                };
+                (event, i)
+            })
+            .collect();
+        store.push_batch(&events)?;
+        mem.push_batch(&events)?;
+
+        let until = Some(LINKERS_PER_CHUNK + 100);
+        CHUNK_READS.with(|reads| reads.set(0));
+        let page = store.get_distinct_dids("example.com", "a.b.c", ".uri", 16, until)?;
+        assert_eq!(CHUNK_READS.with(|reads| reads.get()), 1);
+        assert_eq!(
+            page,
+            mem.get_distinct_dids("example.com", "a.b.c", ".uri", 16, until)?
+        );
+        assert_eq!(page.items.len(), 16);
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_counts_match_a_scan() -> Result<()> {
+        let dir = tempdir()?;
+        let mut store = RocksStorage::new(dir.path())?;
+        let mut mem = MemStorage::new();
+        let targets = ["a.com", "b.com", "c.com"];
+        let record_id = |did: u64, rkey: u64| RecordId {
+            did: format!("did:plc:{did}").into(),
+            collection: "a.b.c".into(),
+            rkey: format!("r{rkey}"),
+        };
+        let links = |n: u64| {
+            // one or two targets, sometimes the same one twice
+            (0..1 + n % 2)
+                .map(|i| CollectedLink {
+                    target: Link::Uri(targets[((n + i) % 3) as usize].into()),
+                    path: ".uri".into(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut rng = 7_u64;
+        let mut next = |n: u64| {
+            rng = rng
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (rng >> 33) % n
+        };
+        // only events that could really happen: records are created once before being changed
+        let mut records = HashSet::new();
+        let mut events = vec![];
+        for cursor in 0..600 {
+            let (did, rkey) = (next(8), next(6));
+            let event = match (next(10), records.contains(&(did, rkey))) {
+                (9, _) => {
+                    records.retain(|(d, _)| *d != did);
+                    ActionableEvent::DeleteAccount(format!("did:plc:{did}").into())
+                }
+                (_, false) => {
+                    records.insert((did, rkey));
+                    ActionableEvent::CreateLinks {
+                        record_id: record_id(did, rkey),
+                        links: links(next(6)),
+                    }
+                }
+                (0..=4, true) => {
+                    records.remove(&(did, rkey));
+                    ActionableEvent::DeleteRecord(record_id(did, rkey))
+                }
+                (_, true) => ActionableEvent::UpdateLinks {
+                    record_id: record_id(did, rkey),
+                    new_links: links(next(6)),
+                },
+            };
+            events.push((event, cursor));
+        }
+        for chunk in events.chunks(50) {
+            store.push_batch(chunk)?;
+            mem.push_batch(chunk)?;
+        }
+
+        let check = |store: &RocksStorage| -> Result<()> {
+            for target in targets {
+                let target_id = store.get_target_id(target, "a.b.c", ".uri")?.unwrap();
+                let scanned = store.get_target_linkers(&target_id)?;
+                let count = store.get_count(target, "a.b.c", ".uri")?;
+                let distinct = store.get_distinct_did_count(target, "a.b.c", ".uri")?;
+                assert_eq!(count, scanned.count().0);
+                assert_eq!(distinct, scanned.count_distinct_dids());
+                assert_eq!(count, mem.get_count(target, "a.b.c", ".uri")?);
+                assert_eq!(
+                    distinct,
+                    mem.get_distinct_did_count(target, "a.b.c", ".uri")?
+                );
+            }
+            Ok(())
+        };
+        check(&store)?;
+
+        // counts are rebuilt from the linkers if they're missing
+        let counts_cf = store.db.cf_handle(TARGET_LINK_COUNTS_CF).unwrap();
+        let did_links_cf = store.db.cf_handle(TARGET_DID_LINKS_CF).unwrap();
+        let mut batch = WriteBatch::default();
+        for cf in [&counts_cf, &did_links_cf] {
+            for kv in store.db.iterator_cf(cf, IteratorMode::Start) {
+                batch.delete_cf(cf, kv?.0);
+            }
+        }
+        batch.put(
+            SCHEMA_VERSION_KEY,
+            _rv(version_before("count target linkers")),
+        );
+        store.db.write(batch)?;
+        drop((counts_cf, did_links_cf));
+        drop(store);
+        assert!(RocksStorage::open_readonly(dir.path()).is_err());
+        check(&RocksStorage::new(dir.path())?)?;
+        Ok(())
+    }
+
+    fn tid(i: u64) -> String {
+        // roughly a microsecond timestamp with a clock id, like real ones
+        u64_to_tid((1_700_000_000_000_000 + i * 1_234) << 10 | 7)
+    }
+
+    #[test]
+    fn tids_round_trip() {
+        for rkey in ["2222222222222", "3kfo6ffvwzc2e", "7zzzzzzzzzzzz", &tid(99)] {
+            assert_eq!(u64_to_tid(tid_to_u64(rkey).unwrap()), rkey);
+        }
+        for rkey in [
+            "self",
+            "",
+            "3kfo6ffvwzc2",
+            "3kfo6ffvwzc2ee",
+            "8zzzzzzzzzzzz",
+            "3KFO6FFVWZC2E",
+        ] {
+            assert_eq!(tid_to_u64(rkey), None, "{rkey}");
+        }
+    }
+
+    #[test]
+    fn linkers_encoding_round_trips() -> Result<()> {
+        let small = TargetLinkers(vec![
+            (DidId(5), RKey(tid(0))),
+            (DidId::empty(), RKey::empty()),
+            (DidId(3), RKey("self".into())),
+            (DidId(u64::MAX), RKey(tid(1))),
+            (DidId(1), RKey("3kfo6ffvwzc2ee".into())),
+        ]);
+        let big = TargetLinkers(
+            (0..LINKERS_PER_CHUNK)
+                .map(|i| (DidId(1_000_000 + i * 37 % 1000), RKey(tid(i))))
+                .collect(),
+        );
+        for linkers in [TargetLinkers::default(), small, big] {
+            let compact = linkers.encode();
+            assert!(TargetLinkers::is_compact(&compact));
+            assert_eq!(TargetLinkers::decode(&compact)?, linkers);
+
+            let legacy = _bincode_opts().serialize(&linkers)?;
+            assert!(!TargetLinkers::is_compact(&legacy));
+            assert_eq!(TargetLinkers::decode(&legacy)?, linkers);
+        }
+
+        let mut chunk = LinkerChunk {
+            linkers: TargetLinkers(
+                (0..LINKERS_PER_CHUNK)
+                    .map(|i| match i % 3 {
+                        0 => (DidId::empty(), RKey::empty()),
+                        _ => (DidId(1_000_000 + i * 37 % 1000), RKey(tid(i))),
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        assert_eq!(LinkerChunk::decode(&chunk.encode())?, chunk);
+        let before = chunk.encode().len();
+        assert_eq!(chunk.collect_tombstones(), 86);
+        assert!(chunk.encode().len() < before);
+        assert_eq!(LinkerChunk::decode(&chunk.encode())?, chunk);
+        assert_eq!(TargetLinkers::decode(&chunk.encode())?, chunk.linkers);
+        // slots from before move down past the tombstones that were under them
+        assert_eq!(chunk.map_slot(0, 0), 0);
+        assert_eq!(chunk.map_slot(0, 1), 0);
+        assert_eq!(chunk.map_slot(0, 5), 3);
+        assert_eq!(chunk.map_slot(1, 5), 5);
+        assert_eq!(chunk.map_slot(0, LINKERS_PER_CHUNK), 170);
+        Ok(())
+    }
+
+    #[test]
+    fn linkers_encoding_is_compact() -> Result<()> {
+        let linkers = TargetLinkers(
+            (0..LINKERS_PER_CHUNK)
+                .map(|i| (DidId(1_000_000 + i * 1_000), RKey(tid(i))))
+                .collect(),
+        );
+        let compact = linkers.encode();
+        let legacy = _bincode_opts().serialize(&linkers)?;
+        assert_eq!(compact[1], LINKERS_V1_ZSTD);
+        assert!(
+            compact.len() * 2 < legacy.len(),
+            "{} vs {}",
+            compact.len(),
+            legacy.len()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_collects_tombstones_and_maps_old_cursors() -> Result<()> {
+        let n = LINKERS_PER_CHUNK * 3 + 10;
+        let unlike = |i: u64| {
+            ActionableEvent::DeleteRecord(RecordId {
+                did: format!("did:plc:{i}").into(),
+                collection: "a.b.c".into(),
+                rkey: "asdf".into(),
+            })
+        };
+        let (target, collection, path) = ("example.com", "a.b.c", ".uri");
+        let (dir, kept_dir) = (tempdir()?, tempdir()?);
+        let mut store = RocksStorage::new(dir.path())?;
+        let mut kept = RocksStorage::new(kept_dir.path())?;
+        kept.set_tombstone_ratio(2.0);
+        // in batches, so the tombstones are collected once, after each
+        let mut cursor = 0;
+        let mut push_each = |mut stores: [&mut RocksStorage; 2], events: Vec<_>| -> Result<()> {
+            let events: Vec<_> = events
+                .into_iter()
+                .map(|event| {
+                    cursor += 1;
+                    (event, cursor)
+                })
+                .collect();
+            for store in stores.iter_mut() {
+                store.push_batch(&events)?;
+            }
+            Ok(())
+        };
+        push_each([&mut store, &mut kept], (0..n).map(like).collect())?;
+        // every cursor a client could be holding
+        let (mut links_untils, mut dids_untils) = (vec![None, Some(3)], vec![None, Some(3)]);
+        while let Some(until) = links_untils.last().copied().flatten() {
+            let page = store.get_links(target, collection, path, 100, Some(until))?;
+            links_untils.push(page.next);
+        }
+        while let Some(until) = dids_untils.last().copied().flatten() {
+            let page = store.get_distinct_dids(target, collection, path, 100, Some(until))?;
+            dids_untils.push(page.next);
+        }
+        for until in [None, Some(3)] {
+            links_untils.push(store.get_links(target, collection, path, 100, until)?.next);
+            dids_untils.push(
+                store
+                    .get_distinct_dids(target, collection, path, 100, until)?
+                    .next,
+            );
+        }
+
+        // a whole chunk goes, and a lot of the rest
+        let gone = |i: &u64| i % 5 < 2 || i / LINKERS_PER_CHUNK == 1;
+        let unlikes: Vec<_> = (0..n).filter(gone).map(unlike).collect();
+        let unliked = unlikes.len() as u64;
+        push_each([&mut store, &mut kept], unlikes)?;
+        // some after the collection, landing in the chunk it left appends going to
+        let mut more: Vec<_> = (n..n + 20).map(like).collect();
+        more.push(unlike(n + 3));
+        push_each([&mut store, &mut kept], more)?;
+        assert_eq!(store.queued_tasks, 0);
+
+        let chunks = |store: &RocksStorage| -> Result<Vec<(LinkerChunkKey, LinkerChunk)>> {
+            let cf = store.db.cf_handle(TARGET_LINKERS_CF).unwrap();
+            let chunks = store.db.iterator_cf(&cf, IteratorMode::Start);
+            chunks
+                .map(|kv| {
+                    let (k, v) = kv?;
+                    Ok((_kr(&k)?, LinkerChunk::decode(&v)?))
+                })
+                .collect()
+        };
+        let collected = chunks(&store)?;
+        let numbers: Vec<_> = collected
+            .iter()
+            .map(|(LinkerChunkKey(_, n), _)| *n)
+            .collect();
+        assert_eq!(numbers, [0, 2, 3]);
+        let tombstones = |chunks: &[(LinkerChunkKey, LinkerChunk)]| {
+            chunks.iter().map(|(_, c)| c.linkers.count().1).sum::<u64>()
+        };
+        assert_eq!(tombstones(&collected), 1);
+        assert!(collected.iter().all(|(_, c)| c.generation == 1));
+        assert_eq!(tombstones(&chunks(&kept)?), unliked + 1);
+
+        // totals leave the collected tombstones out
+        let alive = n + 20 - unliked - 1;
+        let page = store.get_links(target, collection, path, 100, None)?;
+        assert_eq!(page.version, (alive + 1, 1));
+        let kept_page = kept.get_links(target, collection, path, 100, None)?;
+        assert_eq!(kept_page.version, (n + 20, unliked + 1));
+        assert_eq!(store.get_count(target, collection, path)?, alive);
+
+        // pages from cursors handed out before line up with a store that kept its tombstones
+        for until in links_untils {
+            let (mut until, mut kept_until) = (until, until);
+            loop {
+                let page = store.get_links(target, collection, path, 100, until)?;
+                let kept_page = kept.get_links(target, collection, path, 100, kept_until)?;
+                assert_eq!(page.items, kept_page.items);
+                (until, kept_until) = (page.next, kept_page.next);
+                assert_eq!(until.is_some(), kept_until.is_some());
+                if until.is_none() {
+                    break;
+                }
+            }
+        }
+        for until in dids_untils {
+            let (mut until, mut kept_until) = (until, until);
+            loop {
+                let page = store.get_distinct_dids(target, collection, path, 100, until)?;
+                let kept_page =
+                    kept.get_distinct_dids(target, collection, path, 100, kept_until)?;
+                assert_eq!(page.items, kept_page.items);
+                (until, kept_until) = (page.next, kept_page.next);
+                assert_eq!(until.is_some(), kept_until.is_some());
+                if until.is_none() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_re_encodes_old_linker_chunks() -> Result<()> {
+        let dir = tempdir()?;
+        let events: Vec<_> = (0..LINKERS_PER_CHUNK + 1).map(|i| (like(i), i)).collect();
+        let expected = {
+            let mut store = RocksStorage::new(dir.path())?;
+            store.push_batch(&events)?;
+            let before = store.get_links("example.com", "a.b.c", ".uri", 1000, None)?;
+
+            // put every chunk back in the old encoding
+            let linkers_cf = store.db.cf_handle(TARGET_LINKERS_CF).unwrap();
+            let mut batch = WriteBatch::default();
+            for kv in store.db.iterator_cf(&linkers_cf, IteratorMode::Start) {
+                let (k, v) = kv?;
+                let legacy = _bincode_opts().serialize(&TargetLinkers::decode(&v)?)?;
+                batch.put_cf(&linkers_cf, k, legacy);
+            }
+            store.db.write(batch)?;
+            drop(linkers_cf);
+            // and leave the writer with them still to re-encode
+            let mut batch = Batch::default();
+            let step = version_before("re-encode linker chunks compactly");
+            store.queue_task(&mut batch, &Task::Migrate(step, vec![], 0));
+            store.write(batch)?;
+            before
+        };
+        // old chunks can still be read before they're re-encoded
+        let readonly = RocksStorage::open_readonly(dir.path())?;
+        assert_eq!(
+            readonly.get_links("example.com", "a.b.c", ".uri", 1000, None)?,
+            expected
+        );
+        drop(readonly);
+
+        // the writer re-encodes them in the background
+        let mut store = RocksStorage::new(dir.path())?;
+        store.work_on_tasks()?;
+        let linkers_cf = store.db.cf_handle(TARGET_LINKERS_CF).unwrap();
+        for kv in store.db.iterator_cf(&linkers_cf, IteratorMode::Start) {
+            assert!(TargetLinkers::is_compact(&kv?.1));
+        }
+        drop(linkers_cf);
+        assert_eq!(
+            store.get_links("example.com", "a.b.c", ".uri", 1000, None)?,
+            expected
+        );
+        store.push(&like(LINKERS_PER_CHUNK + 1), LINKERS_PER_CHUNK + 1)?;
+        assert_eq!(
+            store.get_count("example.com", "a.b.c", ".uri")?,
+            LINKERS_PER_CHUNK + 2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_gives_old_keys_source_ids() -> Result<()> {
+        let dir = tempdir()?;
+        let events: Vec<_> = (0..5).map(|i| (like(i), i)).collect();
+        let (links_before, counts_before, sources_before) = {
+            let mut store = RocksStorage::new(dir.path())?;
+            store.push_batch(&events)?;
+            let before = (
+                store.get_links("example.com", "a.b.c", ".uri", 100, None)?,
+                store.get_all_counts("example.com")?,
+                store.get_source_counts()?,
+            );
+
+            // put target keys and record links back the way they were stored before source ids
+            let db = store.db.clone();
+            db.create_cf(LEGACY_LINK_TARGETS_CF, &rocks_opts_base())?;
+            let target_ids_cf = db.cf_handle(TARGET_IDS_CF).unwrap();
+            let links_cf = db.cf_handle(LINK_TARGETS_CF).unwrap();
+            let legacy_links_cf = db.cf_handle(LEGACY_LINK_TARGETS_CF).unwrap();
+            let mut batch = WriteBatch::default();
+            for kv in db.iterator_cf(&target_ids_cf, IteratorMode::Start) {
+                let (k, v) = kv?;
+                let TargetKey(target, source_id) = _kr(&k)?;
+                let (collection, path) = store.get_source(&source_id)?.unwrap();
+                let legacy_key = _bincode_opts().serialize(&(target, collection, path))?;
+                batch.delete_cf(&target_ids_cf, k);
+                batch.put_cf(&target_ids_cf, legacy_key, v);
+            }
+            for kv in db.iterator_cf(&links_cf, IteratorMode::Start) {
+                let (k, v) = kv?;
+                let RecordLinkKey(did_id, collection_id, rkey) = _kr(&k)?;
+                let collection = store
+                    .collection_id_table
+                    .get_val_from_id(&db, collection_id.0)?
+                    .unwrap();
+                let mut legacy_targets = vec![];
+                for RecordLinkTarget(source_id, target_id) in _vr::<RecordLinkTargets>(&v)?.0 {
+                    let (_, path) = store.get_source(&source_id)?.unwrap();
+                    legacy_targets.push((path, target_id));
+                }
+                let legacy_key = _bincode_opts().serialize(&(did_id, collection, rkey))?;
+                batch.delete_cf(&links_cf, k);
+                batch.put_cf(
+                    &legacy_links_cf,
+                    legacy_key,
+                    _bincode_opts().serialize(&legacy_targets)?,
+                );
+            }
+            batch.put(
+                SCHEMA_VERSION_KEY,
+                _rv(version_before("give collections and paths ids")),
+            );
+            db.write(batch)?;
+            before
+        };
+        assert!(RocksStorage::open_readonly(dir.path()).is_err());
+
+        let mut store = RocksStorage::new(dir.path())?;
+        assert!(store.db.cf_handle(LEGACY_LINK_TARGETS_CF).is_none());
+        assert_eq!(
+            store.get_links("example.com", "a.b.c", ".uri", 100, None)?,
+            links_before
+        );
+        assert_eq!(store.get_all_counts("example.com")?, counts_before);
+        assert_eq!(store.get_source_counts()?, sources_before);
+
+        // record links can still find their targets to remove them
+        let ActionableEvent::CreateLinks { record_id, .. } = like(2) else {
+            unreachable!()
+        };
+        store.push(&ActionableEvent::DeleteRecord(record_id), 5)?;
+        assert_eq!(store.get_count("example.com", "a.b.c", ".uri")?, 4);
+        drop(store);
+        assert!(RocksStorage::open_readonly(dir.path()).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_reads_reverse_ids_without_values() -> Result<()> {
+        let dir = tempdir()?;
+        let mut events: Vec<_> = (0..5).map(|i| (like(i), i)).collect();
+        events.push((ActionableEvent::DeactivateAccount("did:plc:3".into()), 5));
+        let (expected, ids) = {
+            let mut store = RocksStorage::new(dir.path())?;
+            store.push_batch(&events)?;
+            let expected = store.get_links("example.com", "a.b.c", ".uri", 100, None)?;
+            assert_eq!(expected.items.len(), 4);
+            let mut ids = vec![];
+
+            // put reverse did entries back the way they were before they held values
+            let cf = store.db.cf_handle(DID_IDS_CF).unwrap();
+            let mut batch = WriteBatch::default();
+            for kv in store.db.iterator_cf(&cf, IteratorMode::Start) {
+                let (k, v) = kv?;
+                if k.len() == 8 {
+                    let (did, _) = _bincode_opts().deserialize::<(Did, DidIdValue)>(&v)?;
+                    ids.push(u64::from_be_bytes(k[..].try_into()?));
+                    batch.put_cf(&cf, k, _rk(&did));
+                }
+            }
+            batch.put(
+                SCHEMA_VERSION_KEY,
+                _rv(version_before("fill values into reverse id entries")),
+            );
+            store.db.write(batch)?;
+            (expected, ids)
+        };
+        assert_eq!(ids.len(), 5);
+
+        let readonly = RocksStorage::open_readonly(dir.path())?;
+        let entries = readonly
+            .did_id_table
+            .multi_get_from_ids(&readonly.db, ids.clone())?;
+        assert!(entries.iter().all(|e| matches!(e, Some((_, None)))));
+        assert_eq!(
+            readonly.get_links("example.com", "a.b.c", ".uri", 100, None)?,
+            expected
+        );
+        drop(readonly);
+
+        let store = RocksStorage::new(dir.path())?;
+        let entries = store.did_id_table.multi_get_from_ids(&store.db, ids)?;
+        assert!(entries.iter().all(|e| matches!(e, Some((_, Some(_))))));
+        assert_eq!(
+            store.get_links("example.com", "a.b.c", ".uri", 100, None)?,
+            expected
+        );
+        Ok(())
+    }
+
+    /// page latency for 100-item pages, against looking up each did one at a time
+    ///
+    /// both arms read the current layout (flagged reverse entries), so this only compares the
+    /// batched lookup with per-did reads, not with the read path from before the flags.
+    ///
+    /// cargo test --release -p constellation rocks_bench_link_pages -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn rocks_bench_link_pages() -> Result<()> {
+        let dir = tempdir()?;
+        let mut store = RocksStorage::new(dir.path())?;
+        let n = 10_000;
+        let events: Vec<_> = (0..n).map(|i| (like(i), i)).collect();
+        for chunk in events.chunks(1_000) {
+            store.push_batch(chunk)?;
+        }
+        store
+            .db
+            .flush_cf(&store.db.cf_handle(DID_IDS_CF).unwrap())?;
+        let target_id = store
+            .get_target_id("example.com", "a.b.c", ".uri")?
+            .unwrap();
+        let TargetLinkers(linkers) = store.get_target_linkers(&target_id)?;
+        let page_did_ids: Vec<Vec<_>> = linkers
+            .rchunks(100)
+            .map(|page| page.iter().rev().map(|(did_id, _)| *did_id).collect())
+            .collect();
+
+        let t0 = Instant::now();
+        for did_ids in &page_did_ids {
+            for did_id in did_ids {
+                let did = store
+                    .did_id_table
+                    .get_val_from_id(&store.db, did_id.0)?
+                    .unwrap();
+                let did_value = store.did_id_table.get_id_val(&store.db, &did)?.unwrap();
+                assert!(store.shown(&did_value));
+            }
+        }
+        let one_by_one = t0.elapsed() / page_did_ids.len() as u32;
+
+        let t0 = Instant::now();
+        for did_ids in &page_did_ids {
+            let dids = store.get_shown_dids(did_ids)?;
+            assert!(dids.iter().all(Option::is_some));
+        }
+        let batched = t0.elapsed() / page_did_ids.len() as u32;
+
+        println!("100-item pages: {one_by_one:?} one at a time, {batched:?} batched");
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_deletes_big_accounts_in_the_background() -> Result<()> {
+        let dir = tempdir()?;
+        let n = TASK_WORK_PER_ROUND as u64 * 2 + 5;
+        let big = |i: u64| ActionableEvent::CreateLinks {
+            record_id: RecordId {
+                did: "did:plc:big".into(),
+                collection: "a.b.c".into(),
+                rkey: format!("r{i}"),
+            },
+            links: vec![CollectedLink {
+                target: Link::Uri("example.com".into()),
+                path: ".uri".into(),
+            }],
+        };
+        {
+            let mut store = RocksStorage::new(dir.path())?;
+            store.set_tombstone_ratio(2.0); // just the one task
+            let events: Vec<_> = (0..n).map(|i| (big(i), i)).collect();
+            store.push_batch(&events)?;
+            store.push(&like(0), n)?;
+            store.push(&ActionableEvent::DeleteAccount("did:plc:big".into()), n + 1)?;
+
+            // hidden right away, though only the first round of links is gone
+            let page = store.get_links("example.com", "a.b.c", ".uri", 100, None)?;
+            assert_eq!(
+                page.items.into_iter().map(|r| r.did.0).collect::<Vec<_>>(),
+                vec!["did:plc:0".to_string()]
+            );
+            assert_eq!(store.queued_tasks, 1);
+            assert_eq!(
+                store.get_count("example.com", "a.b.c", ".uri")?,
+                n + 1 - TASK_WORK_PER_ROUND as u64
+            );
+        }
+
+        // the rest gets done after a restart
+        let mut store = RocksStorage::new(dir.path())?;
+        assert_eq!(store.queued_tasks, 1);
+        store.push(&like(1), n + 2)?;
+        store.push(&like(2), n + 3)?;
+        assert_eq!(store.queued_tasks, 0);
+        assert_eq!(store.get_count("example.com", "a.b.c", ".uri")?, 3);
+
+        // and if the did comes back, it starts fresh
+        store.push(&big(0), n + 4)?;
+        assert_eq!(store.get_count("example.com", "a.b.c", ".uri")?, 4);
+        assert_eq!(
+            store.get_distinct_did_count("example.com", "a.b.c", ".uri")?,
+            4
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_forgets_every_trace_of_deleted_accounts() -> Result<()> {
+        let dir = tempdir()?;
+        let gone: Did = "did:plc:gone".into();
+        let record = |rkey: &str| RecordId {
+            did: gone.clone(),
+            collection: "a.b.c".into(),
+            rkey: rkey.into(),
+        };
+        let links = || {
+            vec![CollectedLink {
+                target: Link::Uri("example.com".into()),
+                path: ".uri".into(),
+            }]
+        };
+        let traces = |store: &RocksStorage| -> Result<Vec<String>> {
+            let mut found = vec![];
+            for name in DBWithThreadMode::<MultiThreaded>::list_cf(&rocks_opts_base(), dir.path())?
+            {
+                let cf = store.db.cf_handle(&name).unwrap();
+                for kv in store.db.iterator_cf(&cf, IteratorMode::Start) {
+                    let (k, v) = kv?;
+                    let needle = gone.0.as_bytes();
+                    if [k, v]
+                        .iter()
+                        .any(|b| b.windows(needle.len()).any(|w| w == needle))
+                    {
+                        found.push(name.clone());
+                    }
+                }
+            }
+            Ok(found)
+        };
+
+        let mut store = RocksStorage::new(dir.path())?;
+        store.push(&like(0), 0)?;
+        // enough links for their tombstones to get collected out of the target's chunks
+        let records = MIN_COLLECTED_TOMBSTONES as u64;
+        for i in 0..records {
+            let create = ActionableEvent::CreateLinks {
+                record_id: record(&format!("r{i}")),
+                links: links(),
+            };
+            store.push(&create, 1 + i)?;
+        }
+        let profile = ActionableEvent::UpdateProfile {
+            record_id: RecordId {
+                did: gone.clone(),
+                collection: "app.bsky.actor.profile".into(),
+                rkey: "self".into(),
+            },
+            new_links: vec![],
+            private: true,
+        };
+        store.push(&profile, records + 1)?;
+        let identity = ActionableEvent::Identity {
+            did: gone.clone(),
+            handle: Some("gone.example.com".into()),
+        };
+        store.push(&identity, records + 2)?;
+        assert!(!traces(&store)?.is_empty());
+        let DidIdValue(did_id, _) = store.did_id_table.get_id_val(&store.db, &gone)?.unwrap();
+
+        store.push(&ActionableEvent::DeleteAccount(gone.clone()), records + 3)?;
+        assert_eq!(store.queued_tasks, 0);
+        assert_eq!(traces(&store)?, Vec::<String>::new());
+        assert_eq!(store.get_did(&did_id)?, None);
+        assert_eq!(store.get_count("example.com", "a.b.c", ".uri")?, 1);
+        // and nothing is left where their links were
+        let page = store.get_links("example.com", "a.b.c", ".uri", 100, None)?;
+        assert_eq!(page.version, (1, 0));
+
+        // a reverse entry left behind by a deletion from before goes on the next open
+        let leftover = DidIdValue(did_id, AccountFlags::ACTIVE);
+        let mut batch = Batch::default();
+        store
+            .did_id_table
+            .put_reverse(&store.db, &mut batch, &gone, &leftover);
+        batch.put(
+            SCHEMA_VERSION_KEY,
+            _rv(version_before("drop reverse entries for deleted dids")),
+        );
+        store.write(batch)?;
+        assert!(!traces(&store)?.is_empty());
+        drop(store);
+
+        let mut store = RocksStorage::new(dir.path())?;
+        assert_eq!(store.queued_tasks, 1);
+        store.work_on_tasks()?;
+        assert_eq!(traces(&store)?, Vec::<String>::new());
+        assert_eq!(store.get_did(&DidId(2))?, Some("did:plc:0".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_splits_old_linkers_into_chunks() -> Result<()> {
+        let dir = tempdir()?;
+        {
+            let mut store = RocksStorage::new(dir.path())?;
+            let events: Vec<_> = (0..LINKERS_PER_CHUNK + 1).map(|i| (like(i), i)).collect();
+            store.push_batch(&events)?;
+
+            // put it back the way it was stored before chunking
+            let linkers_cf = store.db.cf_handle(TARGET_LINKERS_CF).unwrap();
+            let counts_cf = store.db.cf_handle(TARGET_LINK_COUNTS_CF).unwrap();
+            let target_id = store
+                .get_target_id("example.com", "a.b.c", ".uri")?
+                .unwrap();
+            let mut linkers = store.get_target_linkers(&target_id)?;
+            let (first_did_id, first_rkey) = linkers.0[0].clone();
+            assert!(linkers.remove_linker(&first_did_id, &first_rkey));
+            let mut batch = WriteBatch::default();
+            batch.delete_cf(&linkers_cf, _rk(&LinkerChunkKey(target_id.clone(), 0)));
+            batch.delete_cf(&linkers_cf, _rk(&LinkerChunkKey(target_id.clone(), 1)));
+            batch.delete_cf(&counts_cf, _rk(&target_id));
+            batch.put_cf(
+                &linkers_cf,
+                _rk(&target_id),
+                _bincode_opts().serialize(&linkers)?,
+            );
+            batch.put(
+                SCHEMA_VERSION_KEY,
+                _rv(version_before("split target linkers into chunks")),
+            );
+            store.db.write(batch)?;
+        }
+        assert!(RocksStorage::open_readonly(dir.path()).is_err());
+
+        let store = RocksStorage::new(dir.path())?;
+        assert_eq!(
+            store.get_count("example.com", "a.b.c", ".uri")?,
+            LINKERS_PER_CHUNK
+        );
+        let page = store.get_links("example.com", "a.b.c", ".uri", 2, None)?;
+        assert_eq!(page.version, (LINKERS_PER_CHUNK + 1, 1));
+        assert_eq!(
+            page.items.into_iter().map(|r| r.did.0).collect::<Vec<_>>(),
+            vec![
+                format!("did:plc:{LINKERS_PER_CHUNK}"),
+                format!("did:plc:{}", LINKERS_PER_CHUNK - 1)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_allocates_nothing_for_unwritten_batches() -> Result<()> {
+        let mut store = RocksStorage::new(tempdir()?)?;
+        store.push(&like(0), 1)?;
+        let events = [
+            (ActionableEvent::DeleteAccount("did:plc:0".into()), 2),
+            (like(1), 3),
+        ];
+        let before = (
+            store.did_id_table.priv_id_seq,
+            store.next_task_id,
+            store.queued_tasks,
+        );
+
+        // like a batch whose write failed
+        let mut batch = Batch::default();
+        for (event, cursor) in &events {
+            store.apply(event, *cursor, &mut batch)?;
+        }
+        assert_eq!(batch.tasks, 1);
+        drop(batch);
+        assert_eq!(
+            (
+                store.did_id_table.priv_id_seq,
+                store.next_task_id,
+                store.queued_tasks
+            ),
+            before
+        );
+
+        // so trying again uses the same ones
+        store.push_batch(&events)?;
+        assert_eq!(store.next_task_id, before.1 + 1);
+        assert_eq!(store.queued_tasks, 0);
+        let DidIdValue(did_id, _) = store
+            .did_id_table
+            .get_id_val(&store.db, &"did:plc:1".into())?
+            .unwrap();
+        assert_eq!(did_id, DidId(before.0 + 1));
+        assert_eq!(store.did_id_table.estimate_count(), before.0);
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_readonly_sends_older_dbs_to_the_writer() -> Result<()> {
+        let dir = tempdir()?;
+        {
+            // only some of the column families, like a db from before links were counted
+            let mut opts = Options::default();
+            opts.create_if_missing(true);
+            opts.create_missing_column_families(true);
+            let cfs = [
+                DID_IDS_CF,
+                COLLECTION_IDS_CF,
+                SOURCE_IDS_CF,
+                TARGET_IDS_CF,
+                TARGET_LINKERS_CF,
+                LINK_TARGETS_CF,
+            ];
+            DBWithThreadMode::<MultiThreaded>::open_cf(&opts, dir.path(), cfs)?;
+        }
+        let Err(e) = RocksStorage::open_readonly(dir.path()) else {
+            panic!("readonly mode opened a db with missing column families");
+        };
+        assert!(
+            e.to_string().contains("open it once with the writer"),
+            "{e}"
+        );
+
+        RocksStorage::new(dir.path())?;
+        assert!(RocksStorage::open_readonly(dir.path()).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_refuses_newer_schema_versions() -> Result<()> {
+        let dir = tempdir()?;
+        {
+            let store = RocksStorage::new(dir.path())?;
+            assert_eq!(store.schema_version()?, MIGRATIONS.len() as u64);
+            let newer = MIGRATIONS.len() as u64 + 1;
+            store.db.put(SCHEMA_VERSION_KEY, _rv(newer))?;
+        }
+        assert!(RocksStorage::open_readonly(dir.path()).is_err());
+        assert!(RocksStorage::new(dir.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_upgrades_dbs_from_before_schema_versions() -> Result<()> {
+        let dir = tempdir()?;
+        {
+            let mut store = RocksStorage::new(dir.path())?;
+            let events: Vec<_> = (0..3).map(|i| (like(i), i)).collect();
+            store.push_batch(&events)?;
+
+            // only the first few migrations' keys, like a db that stopped updating a while ago
+            let mut batch = WriteBatch::default();
+            batch.delete(SCHEMA_VERSION_KEY);
+            for legacy_key in MIGRATIONS.iter().filter_map(|m| m.legacy_key).take(3) {
+                batch.put(legacy_key, [1]);
+            }
+            store.db.write(batch)?;
+            assert_eq!(store.schema_version()?, 3);
+        }
+        // the next step is offline
+        assert!(RocksStorage::open_readonly(dir.path()).is_err());
+
+        let mut store = RocksStorage::new(dir.path())?;
+        assert_eq!(store.schema_version()?, MIGRATIONS.len() as u64);
+        for legacy_key in MIGRATIONS.iter().filter_map(|m| m.legacy_key) {
+            assert_eq!(store.db.get(legacy_key)?, None);
+        }
+        assert_eq!(store.queued_tasks, 1);
+        store.work_on_tasks()?;
+        assert_eq!(store.queued_tasks, 0);
+        assert_eq!(store.get_count("example.com", "a.b.c", ".uri")?, 3);
+        drop(store);
+
+        let readonly = RocksStorage::open_readonly(dir.path())?;
+        assert_eq!(readonly.get_count("example.com", "a.b.c", ".uri")?, 3);
+        Ok(())
+    }
+
     // TODO: add tests for key prefixes actually prefixing (bincode encoding _should_...)
 }
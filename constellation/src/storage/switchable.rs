@@ -0,0 +1,101 @@
+use super::{HandleEntry, LinkReader, PagedAppendingCollection, StorageStats};
+use crate::{CountsByCount, Did, RecordId};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// A reader that can be pointed at a different store while it's being served
+///
+/// Each call grabs a (cheap) clone of the current reader, so requests in flight during a switch
+/// finish against the store they started on.
+#[derive(Debug, Clone)]
+pub struct SwitchableReader<R: LinkReader>(Arc<RwLock<R>>);
+
+impl<R: LinkReader> SwitchableReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self(Arc::new(RwLock::new(reader)))
+    }
+
+    /// start serving from `reader`, handing back the one it replaces
+    pub fn switch(&self, reader: R) -> R {
+        std::mem::replace(&mut *self.0.write().unwrap(), reader)
+    }
+
+    fn current(&self) -> R {
+        self.0.read().unwrap().clone()
+    }
+}
+
+impl<R: LinkReader> LinkReader for SwitchableReader<R> {
+    fn get_count(&self, target: &str, collection: &str, path: &str) -> Result<u64> {
+        self.current().get_count(target, collection, path)
+    }
+
+    fn get_distinct_did_count(&self, target: &str, collection: &str, path: &str) -> Result<u64> {
+        self.current()
+            .get_distinct_did_count(target, collection, path)
+    }
+
+    fn get_links(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        limit: u64,
+        until: Option<u64>,
+    ) -> Result<PagedAppendingCollection<RecordId>> {
+        self.current()
+            .get_links(target, collection, path, limit, until)
+    }
+
+    fn get_distinct_dids(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        limit: u64,
+        until: Option<u64>,
+    ) -> Result<PagedAppendingCollection<Did>> {
+        self.current()
+            .get_distinct_dids(target, collection, path, limit, until)
+    }
+
+    fn get_all_record_counts(&self, target: &str) -> Result<HashMap<String, HashMap<String, u64>>> {
+        self.current().get_all_record_counts(target)
+    }
+
+    fn get_all_counts(
+        &self,
+        target: &str,
+    ) -> Result<HashMap<String, HashMap<String, CountsByCount>>> {
+        self.current().get_all_counts(target)
+    }
+
+    fn get_stats(&self) -> Result<StorageStats> {
+        self.current().get_stats()
+    }
+
+    fn get_last_cursor(&self) -> Result<Option<u64>> {
+        self.current().get_last_cursor()
+    }
+
+    fn get_source_counts(&self) -> Result<HashMap<String, HashMap<String, u64>>> {
+        self.current().get_source_counts()
+    }
+
+    fn get_handle(&self, did: &Did) -> Result<Option<String>> {
+        self.current().get_handle(did)
+    }
+
+    fn get_handles(&self, dids: &[Did]) -> Result<Vec<Option<String>>> {
+        self.current().get_handles(dids)
+    }
+
+    fn get_handle_history(&self, did: &Did) -> Result<Vec<HandleEntry>> {
+        self.current().get_handle_history(did)
+    }
+
+    fn resolve_handle(&self, handle: &str) -> Result<Option<Did>> {
+        self.current().resolve_handle(handle)
+    }
+}